@@ -1,5 +1,15 @@
+pub mod runtime;
+
 pub mod compressor {
+    use ::events::{Event, EventKind};
     use chrono::*;
+    use std::fmt;
+    use std::io;
+    use std::mem;
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration as StdDuration;
+    use super::runtime::Runtime;
 
     #[derive(PartialEq, PartialOrd, Debug, Clone, Copy)]
     pub enum CompressorMode {
@@ -8,33 +18,108 @@ pub mod compressor {
         Off,
     }
 
+    #[derive(PartialEq, Debug, Clone, Copy)]
+    pub enum Output {
+        Cool,
+        Heat,
+        Fan,
+    }
+
     pub trait Switches {
-        fn set_cool(&mut self, on: bool);
-        fn set_heat(&mut self, on: bool);
-        fn set_fan(&mut self, on: bool);
+        fn set_cool(&mut self, on: bool) -> io::Result<()>;
+        fn set_heat(&mut self, on: bool) -> io::Result<()>;
+        fn set_fan(&mut self, on: bool) -> io::Result<()>;
+
+        /// Reads back the actual level of an output, or None if the hardware can't tell
+        fn read_cool(&mut self) -> io::Result<Option<bool>> { Ok(None) }
+        fn read_heat(&mut self) -> io::Result<Option<bool>> { Ok(None) }
+        fn read_fan(&mut self) -> io::Result<Option<bool>> { Ok(None) }
+    }
+
+    /// An output that could not be switched, even after retrying
+    #[derive(PartialEq, Debug, Clone)]
+    pub struct Fault {
+        pub output: Output,
+        pub since: DateTime<UTC>,
+        pub reason: String,
+    }
+
+    impl fmt::Display for Fault {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{:?} output failed at {}: {}", self.output, self.since, self.reason)
+        }
+    }
+
+    const SWITCH_ATTEMPTS: u32 = 3;
+    /// How long a relay gets to settle before it is written and read back again
+    const SWITCH_SETTLE: StdDuration = StdDuration::from_millis(100);
+
+    fn fault_retry() -> Duration {
+        Duration::minutes(10)
+    }
+
+    /// The output a mode turns on
+    fn output_for(mode: CompressorMode) -> Option<Output> {
+        match mode {
+            CompressorMode::Cool => Some(Output::Cool),
+            CompressorMode::HeatPump => Some(Output::Heat),
+            CompressorMode::Off => None,
+        }
     }
 
     pub struct Compressor<'a> {
         mode: CompressorMode,
         fan_mode: bool,
+        /// What the user asked for, as opposed to `fan_mode` which also includes the timers
+        fan_requested: bool,
+        /// True while the fan is only on because of the pre-run or overrun timers
+        fan_timed: bool,
+        fan_pre_run: Duration,
+        fan_overrun: Duration,
+        /// Mode waiting for the fan pre-run to finish before the compressor starts
+        pending_mode: Option<(CompressorMode, DateTime<UTC>)>,
+        fan_overrun_end: Option<DateTime<UTC>>,
         min_duration: Duration,
         next_allowed_compressor_change: DateTime<UTC>,
         next_allowed_fan_change: DateTime<UTC>,
-        switches: &'a mut Switches,
+        runtime: Runtime,
+        runtime_file: Option<PathBuf>,
+        next_runtime_save: DateTime<UTC>,
+        /// Outputs that failed to switch and haven't worked since, at most one fault per output
+        faults: Vec<Fault>,
+        /// The mode last asked for, so that asking again isn't another event
+        requested: CompressorMode,
+        /// Why the requested mode was last held back, so each reason is an event only once
+        blocked: Option<String>,
+        events: Vec<Event>,
+        switches: &'a mut dyn Switches,
     }
 
     impl<'a> Compressor<'a> {
-        pub fn new(switches: &mut Switches) -> Compressor { 
+        pub fn new(switches: &'a mut dyn Switches) -> Compressor<'a> { 
             let now = UTC::now();
             // TODO: possibly use lazy_static crate here
             let min_duration = Duration::minutes(2);
             Compressor { 
                 mode: CompressorMode::Off,
                 fan_mode: false,
+                fan_requested: false,
+                fan_timed: false,
+                fan_pre_run: Duration::zero(),
+                fan_overrun: Duration::zero(),
+                pending_mode: None,
+                fan_overrun_end: None,
                 next_allowed_compressor_change: now - min_duration,
                 next_allowed_fan_change: now - min_duration,
-                min_duration: min_duration,
-                switches: switches,
+                min_duration,
+                runtime: Runtime::new(),
+                runtime_file: None,
+                next_runtime_save: now,
+                faults: vec![],
+                requested: CompressorMode::Off,
+                blocked: None,
+                events: vec![],
+                switches,
             }
         }
 
@@ -43,12 +128,59 @@ pub mod compressor {
             self.min_duration = min_duration;
         }
 
+        /// Sets how long the fan runs before the compressor starts, and how long it keeps running
+        /// after cooling or heating stops to push the remaining cold or warm air out of the coil.
+        pub fn set_fan_timing(&mut self, pre_run: Duration, overrun: Duration) {
+            self.fan_pre_run = pre_run;
+            self.fan_overrun = overrun;
+        }
+
         pub fn set_mode(&mut self, mode: CompressorMode) {
-            if mode == self.mode { return; }
+            self.set_mode_at(mode, UTC::now());
+        }
+
+        pub fn set_mode_at(&mut self, mode: CompressorMode, now: DateTime<UTC>) {
+            self.record_runtime(now);
+
+            if mode != self.requested {
+                self.requested = mode;
+                self.blocked = None;
+                self.events.push(Event::new(now, EventKind::CompressorRequested { mode }));
+            }
+
+            let fault = output_for(mode).and_then(|output| self.active_fault(output, now))
+                .map(|fault| format!("{:?} output failed, retrying at {}", fault.output, fault.since + fault_retry()));
+            if let Some(reason) = fault {
+                self.block(mode, reason, now);
+                return;
+            }
+
+            if mode == self.mode {
+                if self.pending_mode.take().is_some() {
+                    self.update_fan(now);
+                }
+                return;
+            }
+
+            if self.mode == CompressorMode::Off && mode != CompressorMode::Off && !self.fan_pre_run.is_zero() {
+                let pre_run_end = match self.pending_mode {
+                    Some((_, end)) => end,
+                    // nothing to pre-run if the fan is already moving air
+                    None if self.fan_mode => now,
+                    None => now + self.fan_pre_run,
+                };
+                self.pending_mode = Some((mode, pre_run_end));
+                self.update_fan(now);
+
+                if now < pre_run_end {
+                    self.block(mode, format!("fan pre-run until {}", pre_run_end), now);
+                    return;
+                }
+            }
 
-            let now = UTC::now();
             if self.next_allowed_compressor_change < now {
-                self.mode = mode;
+                let previous = self.mode;
+                self.pending_mode = None;
                 self.next_allowed_compressor_change = now + self.min_duration;
                 
                 let modes = match mode {
@@ -57,43 +189,364 @@ pub mod compressor {
                      CompressorMode::Off => (false, false)
                 };
                      
-                self.switches.set_cool(modes.0);
-                self.switches.set_heat(modes.1);
+                // switch off before switching on, so cooling and heating never overlap
+                let result = if modes.0 {
+                    self.switch(Output::Heat, modes.1, now).and_then(|_| self.switch(Output::Cool, modes.0, now))
+                } else {
+                    self.switch(Output::Cool, modes.0, now).and_then(|_| self.switch(Output::Heat, modes.1, now))
+                };
+
+                match result {
+                    Ok(()) => {
+                        self.mode = mode;
+                        self.blocked = None;
+                        self.events.push(Event::new(now, EventKind::CompressorApplied { from: previous, to: mode }));
+                    },
+                    Err(fault) => {
+                        self.block(mode, fault.to_string(), now);
+                        self.enter_fault(fault);
+                    },
+                }
+
+                self.fan_overrun_end = if self.mode == CompressorMode::Off && previous != CompressorMode::Off
+                        && !self.fan_overrun.is_zero() {
+                    Some(now + self.fan_overrun)
+                } else {
+                    None
+                };
+                self.update_fan(now);
             } else {
                 warn!("Compressor toggled too fast. {} {}", now, self.next_allowed_compressor_change);
+                let reason = format!("minimum run time until {}", self.next_allowed_compressor_change);
+                self.block(mode, reason, now);
             }
         }
 
+        fn block(&mut self, mode: CompressorMode, reason: String, now: DateTime<UTC>) {
+            if self.blocked.as_ref() != Some(&reason) {
+                self.blocked = Some(reason.clone());
+                self.events.push(Event::new(now, EventKind::CompressorBlocked { mode, reason }));
+            }
+        }
+
+        /// What happened since the last call, oldest first
+        pub fn take_events(&mut self) -> Vec<Event> {
+            mem::take(&mut self.events)
+        }
+
         pub fn get_mode(&self) -> CompressorMode {
             self.mode
         }
 
+        /// Writes every output off, which is what the compressor starts out believing. An earlier
+        /// run that crashed may have left any of them on.
+        pub fn reset_outputs_at(&mut self, now: DateTime<UTC>) {
+            let result = self.switch(Output::Cool, false, now)
+                .and_then(|_| self.switch(Output::Heat, false, now))
+                .and_then(|_| self.switch(Output::Fan, false, now));
+            if let Err(fault) = result {
+                self.enter_fault(fault);
+            }
+        }
+
+        /// Turns cooling and heating off right away, even inside the minimum run time, which is
+        /// there to protect against starting too soon rather than stopping. The fan keeps going
+        /// for its overrun; returns when `finish_shutdown_at` may turn it off.
+        pub fn shut_down_at(&mut self, now: DateTime<UTC>) -> DateTime<UTC> {
+            self.pending_mode = None;
+            self.fan_requested = false;
+            self.next_allowed_compressor_change = now - Duration::seconds(1);
+            self.set_mode_at(CompressorMode::Off, now);
+            self.fan_overrun_end.unwrap_or(now)
+        }
+
+        /// Turns the fan off, whatever its timers say, and saves the runtime counters
+        pub fn finish_shutdown_at(&mut self, now: DateTime<UTC>) {
+            self.record_runtime(now);
+            if self.switch(Output::Heat, false, now).and_then(|_| self.switch(Output::Cool, false, now)).is_err() {
+                error!("Could not make sure cooling and heating are off");
+            }
+            match self.switch(Output::Fan, false, now) {
+                Ok(()) => self.fan_mode = false,
+                Err(fault) => error!("{}", fault),
+            }
+            self.fan_overrun_end = None;
+            self.save_runtime();
+        }
+
+        /// The next time after `now` something here could change by itself: the fan pre-run or
+        /// overrun ending, a lockout running out, or a fault that can be retried
+        pub fn next_deadline(&self, now: DateTime<UTC>) -> Option<DateTime<UTC>> {
+            let deadlines = [
+                self.pending_mode.map(|(_, end)| end),
+                self.fan_overrun_end,
+                Some(self.next_allowed_compressor_change),
+                Some(self.next_allowed_fan_change),
+            ];
+            deadlines.iter().flatten().cloned()
+                .chain(self.faults.iter().map(|fault| fault.since + fault_retry()))
+                .filter(|deadline| *deadline > now).min()
+        }
+
+        /// The mode the compressor is heading to, which differs from `get_mode` during a fan pre-run
+        pub fn get_target_mode(&self) -> CompressorMode {
+            self.pending_mode.map_or(self.mode, |(mode, _)| mode)
+        }
+
         pub fn set_fan_mode(&mut self, mode: bool) {
-            if mode == self.fan_mode { return; }
+            self.set_fan_mode_at(mode, UTC::now());
+        }
+
+        pub fn set_fan_mode_at(&mut self, mode: bool, now: DateTime<UTC>) {
+            self.fan_requested = mode;
+            self.update_fan(now);
+        }
+
+        pub fn get_fan_mode(&self) -> bool {
+            self.fan_mode
+        }
+
+        /// Keeps the runtime counters in the given file, starting from what is already in it
+        pub fn set_runtime_file(&mut self, path: PathBuf) {
+            match Runtime::load(&path) {
+                Ok(mut runtime) => {
+                    runtime.set_filter_limit(self.runtime.get_filter_limit());
+                    self.runtime = runtime;
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => warn!("Could not read runtime counters from {}: {}", path.display(), e),
+            }
+            self.runtime_file = Some(path);
+        }
+
+        pub fn set_filter_limit(&mut self, limit: Option<Duration>) {
+            self.runtime.set_filter_limit(limit);
+        }
+
+        pub fn reset_filter_runtime(&mut self) {
+            self.runtime.reset_filter_runtime();
+            self.save_runtime();
+        }
+
+        pub fn get_runtime(&self) -> &Runtime {
+            &self.runtime
+        }
+
+        pub fn save_runtime(&mut self) {
+            if let Some(ref path) = self.runtime_file {
+                if let Err(e) = self.runtime.save(path) {
+                    warn!("Could not save runtime counters to {}: {}", path.display(), e);
+                }
+            }
+        }
+
+        /// Counts the time since the last change towards the outputs as they are now, so this
+        /// has to be called before changing any of them
+        fn record_runtime(&mut self, now: DateTime<UTC>) {
+            self.runtime.update(now, self.mode, self.fan_mode);
+
+            if now >= self.next_runtime_save {
+                // only every so often, to go easy on the flash
+                self.next_runtime_save = now + Duration::minutes(10);
+                self.save_runtime();
+            }
+        }
+
+        /// The output that failed to switch most recently, of those that haven't worked since
+        pub fn get_fault(&self) -> Option<&Fault> {
+            self.faults.iter().max_by_key(|fault| fault.since)
+        }
+
+        /// Writes an output, reading it back when the hardware allows, and retries a few times
+        /// before giving up on it. The output's fault is kept until it switches again.
+        fn switch(&mut self, output: Output, on: bool, now: DateTime<UTC>) -> Result<(), Fault> {
+            let mut reason = String::new();
+
+            for attempt in 0..SWITCH_ATTEMPTS {
+                if attempt > 0 {
+                    thread::sleep(SWITCH_SETTLE);
+                }
+                let result = match output {
+                    Output::Cool => self.switches.set_cool(on).and_then(|_| self.switches.read_cool()),
+                    Output::Heat => self.switches.set_heat(on).and_then(|_| self.switches.read_heat()),
+                    Output::Fan => self.switches.set_fan(on).and_then(|_| self.switches.read_fan()),
+                };
+
+                match result {
+                    Ok(Some(level)) if level != on => reason = format!("reads {} after writing {}", level, on),
+                    Ok(_) => {
+                        self.clear_fault(output);
+                        return Ok(());
+                    },
+                    Err(e) => reason = e.to_string(),
+                }
+                warn!("Switching {:?} output to {} failed: {}", output, on, reason);
+            }
+
+            let fault = Fault { output, since: now, reason };
+            self.faults.retain(|f| f.output != output);
+            self.faults.push(fault.clone());
+            Err(fault)
+        }
+
+        /// We can't tell what the compressor is doing anymore, so make an attempt at turning it
+        /// off and hold off on starting it again for a while
+        fn enter_fault(&mut self, fault: Fault) {
+            error!("{}", fault);
+            let _ = self.switches.set_cool(false);
+            let _ = self.switches.set_heat(false);
+            self.mode = CompressorMode::Off;
+        }
+
+        /// The output's fault, while it is too recent to try the output again
+        fn active_fault(&self, output: Output, now: DateTime<UTC>) -> Option<&Fault> {
+            self.faults.iter().find(|fault| fault.output == output && now < fault.since + fault_retry())
+        }
+
+        fn clear_fault(&mut self, output: Output) {
+            if let Some(index) = self.faults.iter().position(|fault| fault.output == output) {
+                info!("{:?} output is working again", output);
+                self.faults.remove(index);
+            }
+        }
+
+        fn fan_timer_active(&self, now: DateTime<UTC>) -> bool {
+            self.pending_mode.is_some() || self.fan_overrun_end.is_some_and(|end| now < end)
+        }
+
+        /// Combines the requested fan mode with the pre-run and overrun timers. Changes caused by
+        /// the timers skip the fan lockout, otherwise the blower could be held on or off by it.
+        fn update_fan(&mut self, now: DateTime<UTC>) {
+            self.record_runtime(now);
+
+            let timer_active = self.fan_timer_active(now);
+            if !timer_active {
+                self.fan_overrun_end = None;
+            }
+
+            let mode = self.fan_requested || timer_active;
+            if mode == self.fan_mode {
+                self.fan_timed = mode && !self.fan_requested;
+                return;
+            }
+
+            if self.active_fault(Output::Fan, now).is_some() { return; }
+
+            let timed_change = timer_active || self.fan_timed;
+            if timed_change || self.next_allowed_fan_change < now {
+                if let Err(fault) = self.switch(Output::Fan, mode, now) {
+                    error!("{}", fault);
+                    return;
+                }
 
-            let now = UTC::now();
-            if self.next_allowed_fan_change < now {
                 self.fan_mode = mode;
-                self.next_allowed_fan_change = now + self.min_duration;
+                self.fan_timed = mode && !self.fan_requested;
+                self.events.push(Event::new(now, EventKind::FanChanged { on: mode, timed: self.fan_timed }));
+                if !timed_change {
+                    self.next_allowed_fan_change = now + self.min_duration;
+                }
                 info!("Fan mode: {}", mode);
-                self.switches.set_fan(mode);
             } else {
                 warn!("Fan toggled too fast. {} {}", now, self.next_allowed_fan_change);
             }
         }
-
-        pub fn get_fan_mode(&mut self) -> bool {
-            self.fan_mode
-        }
     }
 
     #[cfg(test)]
+    #[allow(clippy::bool_assert_comparison)]
     mod test {
         use super::*;
+        use std::cell::Cell;
+        use std::io;
+        use std::rc::Rc;
+        use std::time::Instant;
+
+        struct MockSwitches;
+        impl Switches for MockSwitches {
+            fn set_cool(&mut self, _on: bool) -> io::Result<()> { Ok(()) }
+            fn set_heat(&mut self, _on: bool) -> io::Result<()> { Ok(()) }
+            fn set_fan(&mut self, _on: bool) -> io::Result<()> { Ok(()) }
+        }
+
+        /// Keeps what was last written to (cool, heat, fan)
+        #[derive(Default)]
+        struct RecordingSwitches {
+            outputs: (bool, bool, bool),
+        }
+
+        impl Switches for RecordingSwitches {
+            fn set_cool(&mut self, on: bool) -> io::Result<()> { self.outputs.0 = on; Ok(()) }
+            fn set_heat(&mut self, on: bool) -> io::Result<()> { self.outputs.1 = on; Ok(()) }
+            fn set_fan(&mut self, on: bool) -> io::Result<()> { self.outputs.2 = on; Ok(()) }
+        }
+
+        /// Cooling either can't be written, or its relay reads back as off whatever is written
+        struct BrokenCoolSwitches {
+            write_fails: bool,
+            cool_writes: u32,
+        }
+
+        impl Switches for BrokenCoolSwitches {
+            fn set_cool(&mut self, _on: bool) -> io::Result<()> {
+                self.cool_writes += 1;
+                if self.write_fails {
+                    Err(io::Error::other("gpio not exported"))
+                } else {
+                    Ok(())
+                }
+            }
+            fn set_heat(&mut self, _on: bool) -> io::Result<()> { Ok(()) }
+            fn set_fan(&mut self, _on: bool) -> io::Result<()> { Ok(()) }
+            fn read_cool(&mut self) -> io::Result<Option<bool>> { Ok(Some(false)) }
+        }
+
+        /// Cooling can't be turned on, and the fan can't be written until it is fixed
+        struct BrokenFanSwitches {
+            fan_fixed: Rc<Cell<bool>>,
+            cool_attempts: Rc<Cell<u32>>,
+        }
+
+        impl Switches for BrokenFanSwitches {
+            fn set_cool(&mut self, on: bool) -> io::Result<()> {
+                if on {
+                    self.cool_attempts.set(self.cool_attempts.get() + 1);
+                    Err(io::Error::other("gpio not exported"))
+                } else {
+                    Ok(())
+                }
+            }
+            fn set_heat(&mut self, _on: bool) -> io::Result<()> { Ok(()) }
+            fn set_fan(&mut self, _on: bool) -> io::Result<()> {
+                if self.fan_fixed.get() { Ok(()) } else { Err(io::Error::other("gpio not exported")) }
+            }
+        }
+
+        /// A fan relay that reads back its old level for a while after it is switched
+        struct SlowFanSwitches {
+            level: bool,
+            switched: Instant,
+        }
+
+        impl Switches for SlowFanSwitches {
+            fn set_cool(&mut self, _on: bool) -> io::Result<()> { Ok(()) }
+            fn set_heat(&mut self, _on: bool) -> io::Result<()> { Ok(()) }
+            fn set_fan(&mut self, on: bool) -> io::Result<()> {
+                if on != self.level {
+                    self.level = on;
+                    self.switched = Instant::now();
+                }
+                Ok(())
+            }
+            fn read_fan(&mut self) -> io::Result<Option<bool>> {
+                let settled = self.switched.elapsed() >= SWITCH_SETTLE / 2;
+                Ok(Some(if settled { self.level } else { !self.level }))
+            }
+        }
 
         #[test]
         fn limits_compressor_changes_to_2_minutes_minimum() {
-            let mut compressor = Compressor::new();
+            let mut switches = MockSwitches;
+            let mut compressor = Compressor::new(&mut switches);
 
             compressor.set_mode(CompressorMode::Cool);
             compressor.set_mode(CompressorMode::Off);
@@ -105,7 +558,8 @@ pub mod compressor {
 
         #[test]
         fn limits_fan_changes_to_2_minutes_minimum() {
-            let mut compressor = Compressor::new();
+            let mut switches = MockSwitches;
+            let mut compressor = Compressor::new(&mut switches);
 
             compressor.set_fan_mode(true);
             compressor.set_fan_mode(false);
@@ -115,6 +569,253 @@ pub mod compressor {
             assert_eq!(compressor.get_fan_mode(), true);
         }
 
+        #[test]
+        fn runs_the_fan_for_the_overrun_after_cooling_stops() {
+            let mut switches = MockSwitches;
+            let mut compressor = Compressor::new(&mut switches);
+            compressor.set_min_change_duration(Duration::zero());
+            compressor.set_fan_timing(Duration::zero(), Duration::seconds(90));
+            let now = UTC::now();
+
+            compressor.set_mode_at(CompressorMode::Cool, now);
+            compressor.set_mode_at(CompressorMode::Off, now + Duration::seconds(1));
+            assert_eq!(compressor.get_fan_mode(), true);
+
+            compressor.set_fan_mode_at(false, now + Duration::seconds(90));
+            assert_eq!(compressor.get_fan_mode(), true);
+
+            compressor.set_fan_mode_at(false, now + Duration::seconds(92));
+            assert_eq!(compressor.get_fan_mode(), false);
+        }
+
+        #[test]
+        fn fan_overrun_is_not_blocked_by_the_fan_lockout() {
+            let mut switches = MockSwitches;
+            let mut compressor = Compressor::new(&mut switches);
+            compressor.set_fan_timing(Duration::zero(), Duration::seconds(30));
+            let now = UTC::now();
+
+            compressor.set_mode_at(CompressorMode::Cool, now);
+            compressor.set_fan_mode_at(true, now + Duration::seconds(121));
+            compressor.set_mode_at(CompressorMode::Off, now + Duration::seconds(122));
+            compressor.set_fan_mode_at(false, now + Duration::seconds(123));
+            assert_eq!(compressor.get_fan_mode(), true);
+
+            compressor.set_fan_mode_at(false, now + Duration::seconds(153));
+            assert_eq!(compressor.get_fan_mode(), false);
+        }
+
+        #[test]
+        fn requested_fan_stays_on_after_the_overrun() {
+            let mut switches = MockSwitches;
+            let mut compressor = Compressor::new(&mut switches);
+            compressor.set_min_change_duration(Duration::zero());
+            compressor.set_fan_timing(Duration::zero(), Duration::seconds(30));
+            let now = UTC::now();
+
+            compressor.set_mode_at(CompressorMode::Cool, now);
+            compressor.set_mode_at(CompressorMode::Off, now + Duration::seconds(1));
+            compressor.set_fan_mode_at(true, now + Duration::seconds(2));
+            compressor.set_fan_mode_at(true, now + Duration::seconds(60));
+
+            assert_eq!(compressor.get_fan_mode(), true);
+        }
+
+        #[test]
+        fn runs_the_fan_before_starting_the_compressor() {
+            let mut switches = MockSwitches;
+            let mut compressor = Compressor::new(&mut switches);
+            compressor.set_fan_timing(Duration::seconds(30), Duration::zero());
+            let now = UTC::now();
+
+            compressor.set_mode_at(CompressorMode::Cool, now);
+            assert_eq!(compressor.get_mode(), CompressorMode::Off);
+            assert_eq!(compressor.get_fan_mode(), true);
+
+            compressor.set_mode_at(CompressorMode::Cool, now + Duration::seconds(31));
+            assert_eq!(compressor.get_mode(), CompressorMode::Cool);
+            assert_eq!(compressor.get_fan_mode(), false);
+        }
+
+        #[test]
+        fn cancelling_the_pre_run_turns_the_fan_back_off() {
+            let mut switches = MockSwitches;
+            let mut compressor = Compressor::new(&mut switches);
+            compressor.set_fan_timing(Duration::seconds(30), Duration::zero());
+            let now = UTC::now();
+
+            compressor.set_mode_at(CompressorMode::HeatPump, now);
+            compressor.set_mode_at(CompressorMode::Off, now + Duration::seconds(10));
+
+            assert_eq!(compressor.get_mode(), CompressorMode::Off);
+            assert_eq!(compressor.get_fan_mode(), false);
+        }
+
+        #[test]
+        fn shuts_down_inside_the_minimum_run_time_and_overruns_the_fan() {
+            let mut switches = RecordingSwitches::default();
+            {
+                let mut compressor = Compressor::new(&mut switches);
+                compressor.set_fan_timing(Duration::zero(), Duration::seconds(90));
+                let now = UTC::now();
+
+                compressor.set_mode_at(CompressorMode::Cool, now);
+                let fan_off_at = compressor.shut_down_at(now + Duration::seconds(10));
+
+                assert_eq!(compressor.get_mode(), CompressorMode::Off);
+                assert!(compressor.get_fan_mode());
+                assert_eq!(fan_off_at, now + Duration::seconds(100));
+
+                compressor.finish_shutdown_at(fan_off_at);
+                assert!(!compressor.get_fan_mode());
+            }
+
+            assert_eq!(switches.outputs, (false, false, false));
+        }
+
+        #[test]
+        fn reports_what_was_asked_for_and_why_it_waits() {
+            let mut switches = MockSwitches;
+            let mut compressor = Compressor::new(&mut switches);
+            let now = UTC::now();
+
+            compressor.set_mode_at(CompressorMode::Cool, now);
+            compressor.set_mode_at(CompressorMode::Off, now + Duration::seconds(10));
+            compressor.set_mode_at(CompressorMode::Off, now + Duration::seconds(20));
+
+            let events: Vec<EventKind> = compressor.take_events().into_iter().map(|event| event.kind).collect();
+            assert_eq!(events, vec![
+                EventKind::CompressorRequested { mode: CompressorMode::Cool },
+                EventKind::CompressorApplied { from: CompressorMode::Off, to: CompressorMode::Cool },
+                EventKind::CompressorRequested { mode: CompressorMode::Off },
+                EventKind::CompressorBlocked {
+                    mode: CompressorMode::Off,
+                    reason: format!("minimum run time until {}", now + Duration::minutes(2)),
+                },
+            ]);
+            assert!(compressor.take_events().is_empty());
+        }
+
+        #[test]
+        fn knows_when_its_timers_run_out() {
+            let mut switches = MockSwitches;
+            let mut compressor = Compressor::new(&mut switches);
+            compressor.set_fan_timing(Duration::seconds(60), Duration::seconds(90));
+            let now = UTC::now();
+            assert_eq!(compressor.next_deadline(now), None);
+
+            compressor.set_mode_at(CompressorMode::Cool, now);
+            assert_eq!(compressor.next_deadline(now), Some(now + Duration::seconds(60)));
+
+            // the pre-run is over, so the minimum run time is next
+            let started = now + Duration::seconds(61);
+            compressor.set_mode_at(CompressorMode::Cool, started);
+            assert_eq!(compressor.next_deadline(started), Some(started + Duration::minutes(2)));
+
+            let stopped = started + Duration::minutes(3);
+            compressor.set_mode_at(CompressorMode::Off, stopped);
+            assert_eq!(compressor.next_deadline(stopped), Some(stopped + Duration::seconds(90)));
+        }
+
+        #[test]
+        fn resets_outputs_left_on() {
+            let mut switches = RecordingSwitches { outputs: (true, false, true) };
+            {
+                let mut compressor = Compressor::new(&mut switches);
+                compressor.reset_outputs_at(UTC::now());
+                assert!(compressor.get_fault().is_none());
+            }
+
+            assert_eq!(switches.outputs, (false, false, false));
+        }
+
+        #[test]
+        fn retries_and_faults_when_an_output_doesnt_follow() {
+            let mut switches = BrokenCoolSwitches { write_fails: false, cool_writes: 0 };
+            {
+                let mut compressor = Compressor::new(&mut switches);
+
+                compressor.set_mode(CompressorMode::Cool);
+
+                assert_eq!(compressor.get_mode(), CompressorMode::Off);
+                assert_eq!(compressor.get_fault().map(|f| f.output), Some(Output::Cool));
+            }
+
+            // the retries, then turning it off again after giving up
+            assert_eq!(switches.cool_writes, SWITCH_ATTEMPTS + 1);
+        }
+
+        #[test]
+        fn waits_before_trying_a_faulted_output_again() {
+            let mut switches = BrokenCoolSwitches { write_fails: true, cool_writes: 0 };
+            {
+                let mut compressor = Compressor::new(&mut switches);
+                compressor.set_min_change_duration(Duration::zero());
+                let now = UTC::now();
+
+                compressor.set_mode_at(CompressorMode::Cool, now);
+                compressor.set_mode_at(CompressorMode::Cool, now + Duration::minutes(1));
+                assert_eq!(compressor.get_mode(), CompressorMode::Off);
+                assert_eq!(compressor.get_fault().map(|f| f.output), Some(Output::Cool));
+            }
+
+            assert_eq!(switches.cool_writes, SWITCH_ATTEMPTS + 1);
+        }
+
+        #[test]
+        fn clears_the_fault_once_switching_works_again() {
+            let mut switches = BrokenCoolSwitches { write_fails: false, cool_writes: 0 };
+            let mut compressor = Compressor::new(&mut switches);
+            compressor.set_min_change_duration(Duration::zero());
+            let now = UTC::now();
+
+            compressor.set_mode_at(CompressorMode::Cool, now);
+            compressor.set_mode_at(CompressorMode::Cool, now + Duration::minutes(1));
+            assert_eq!(compressor.get_mode(), CompressorMode::Off);
+
+            // turning cooling off reads back fine, and heating doesn't need it
+            compressor.set_mode_at(CompressorMode::HeatPump, now + Duration::minutes(2));
+            assert_eq!(compressor.get_mode(), CompressorMode::HeatPump);
+            assert!(compressor.get_fault().is_none());
+        }
+
+        #[test]
+        fn keeps_a_fault_for_each_output() {
+            let (fan_fixed, cool_attempts) = (Rc::new(Cell::new(false)), Rc::new(Cell::new(0)));
+            let mut switches = BrokenFanSwitches { fan_fixed: fan_fixed.clone(), cool_attempts: cool_attempts.clone() };
+            let mut compressor = Compressor::new(&mut switches);
+            compressor.set_min_change_duration(Duration::zero());
+            let now = UTC::now();
+
+            compressor.set_mode_at(CompressorMode::Cool, now);
+            compressor.set_fan_mode_at(true, now + Duration::minutes(1));
+            assert_eq!(compressor.get_fault().map(|f| f.output), Some(Output::Fan));
+
+            // the fan failing too doesn't make cooling worth trying again any sooner
+            compressor.set_mode_at(CompressorMode::Cool, now + Duration::minutes(2));
+            assert_eq!(cool_attempts.get(), SWITCH_ATTEMPTS);
+
+            // and neither does cooling failing again for the fan
+            fan_fixed.set(true);
+            compressor.set_mode_at(CompressorMode::Cool, now + Duration::minutes(10));
+            assert_eq!(cool_attempts.get(), 2 * SWITCH_ATTEMPTS);
+            compressor.set_fan_mode_at(true, now + Duration::minutes(10));
+            assert!(!compressor.get_fan_mode());
+
+            compressor.set_fan_mode_at(true, now + Duration::minutes(11));
+            assert!(compressor.get_fan_mode());
+            assert_eq!(compressor.get_fault().map(|f| (f.output, f.since)), Some((Output::Cool, now + Duration::minutes(10))));
+        }
+
+        #[test]
+        fn gives_a_slow_relay_time_to_settle() {
+            let mut switches = SlowFanSwitches { level: false, switched: Instant::now() };
+            let mut compressor = Compressor::new(&mut switches);
+
+            compressor.set_fan_mode_at(true, UTC::now());
+
+            assert!(compressor.get_fan_mode());
+            assert!(compressor.get_fault().is_none());
+        }
     }
 }
-
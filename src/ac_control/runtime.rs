@@ -0,0 +1,274 @@
+use ::persist;
+use ::ac_control::compressor::CompressorMode;
+use chrono::*;
+use rustc_serialize::json::{Json, ToJson};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+/// How long each output has been on. `fan` is the time the fan output itself was on, which
+/// doesn't include the blower running for heating or cooling.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RuntimeTotals {
+    pub cool: Duration,
+    pub heat: Duration,
+    pub fan: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reminder {
+    ChangeFilter,
+}
+
+impl fmt::Display for Reminder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Reminder::ChangeFilter => write!(f, "Change the air filter"),
+        }
+    }
+}
+
+/// Runtime counters for the current day, the current month and the lifetime of the system, plus
+/// the air handler runtime since the filter was last changed.
+#[derive(Clone, Debug)]
+pub struct Runtime {
+    pub daily: RuntimeTotals,
+    pub monthly: RuntimeTotals,
+    pub lifetime: RuntimeTotals,
+    day: Option<NaiveDate>,
+    filter: Duration,
+    filter_limit: Option<Duration>,
+    last_update: Option<DateTime<UTC>>,
+}
+
+impl RuntimeTotals {
+    pub fn zero() -> RuntimeTotals {
+        RuntimeTotals { cool: Duration::zero(), heat: Duration::zero(), fan: Duration::zero() }
+    }
+
+    fn add(&mut self, elapsed: Duration, mode: CompressorMode, fan: bool) {
+        match mode {
+            CompressorMode::Cool => self.cool = self.cool + elapsed,
+            CompressorMode::HeatPump => self.heat = self.heat + elapsed,
+            CompressorMode::Off => (),
+        }
+        if fan {
+            self.fan = self.fan + elapsed;
+        }
+    }
+
+    fn from_json(json: &Json) -> RuntimeTotals {
+        let seconds = |key| json.find(key).and_then(|s| s.as_i64()).and_then(persist::seconds).unwrap_or(Duration::zero());
+        RuntimeTotals { cool: seconds("cool"), heat: seconds("heat"), fan: seconds("fan") }
+    }
+}
+
+impl ToJson for RuntimeTotals {
+    fn to_json(&self) -> Json {
+        let mut json = BTreeMap::new();
+        json.insert("cool".to_string(), self.cool.num_seconds().to_json());
+        json.insert("heat".to_string(), self.heat.num_seconds().to_json());
+        json.insert("fan".to_string(), self.fan.num_seconds().to_json());
+        Json::Object(json)
+    }
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Runtime {
+    pub fn new() -> Runtime {
+        Runtime {
+            daily: RuntimeTotals::zero(),
+            monthly: RuntimeTotals::zero(),
+            lifetime: RuntimeTotals::zero(),
+            day: None,
+            filter: Duration::zero(),
+            filter_limit: None,
+            last_update: None,
+        }
+    }
+
+    /// Filter runtime after which a filter change is due, or None to never remind
+    pub fn set_filter_limit(&mut self, limit: Option<Duration>) {
+        self.filter_limit = limit;
+    }
+
+    pub fn get_filter_limit(&self) -> Option<Duration> {
+        self.filter_limit
+    }
+
+    /// Adds the time since the last update to the outputs that were on during it
+    pub fn update(&mut self, now: DateTime<UTC>, mode: CompressorMode, fan: bool) {
+        let today = now.with_timezone(&Local).date().naive_local();
+        if let Some(day) = self.day {
+            if day != today {
+                self.daily = RuntimeTotals::zero();
+                if day.month() != today.month() || day.year() != today.year() {
+                    self.monthly = RuntimeTotals::zero();
+                }
+            }
+        }
+        self.day = Some(today);
+
+        let elapsed = match self.last_update {
+            Some(last_update) if now > last_update => now - last_update,
+            _ => Duration::zero(),
+        };
+        self.last_update = Some(now);
+
+        self.daily.add(elapsed, mode, fan);
+        self.monthly.add(elapsed, mode, fan);
+        self.lifetime.add(elapsed, mode, fan);
+
+        if fan || mode != CompressorMode::Off {
+            let needed_change = self.needs_filter_change();
+            self.filter = self.filter + elapsed;
+            if !needed_change && self.needs_filter_change() {
+                warn!("Filter has run for {} hours, time to change it", self.filter.num_hours());
+            }
+        }
+    }
+
+    pub fn get_filter_runtime(&self) -> Duration {
+        self.filter
+    }
+
+    pub fn reset_filter_runtime(&mut self) {
+        self.filter = Duration::zero();
+    }
+
+    pub fn needs_filter_change(&self) -> bool {
+        self.filter_limit.is_some_and(|limit| self.filter >= limit)
+    }
+
+    pub fn get_reminders(&self) -> Vec<Reminder> {
+        if self.needs_filter_change() { vec![Reminder::ChangeFilter] } else { vec![] }
+    }
+
+    /// Restores the counters saved with `to_json`. The filter limit is a setting, so it isn't saved.
+    pub fn from_json(json: &Json) -> Runtime {
+        let totals = |key| json.find(key).map_or(RuntimeTotals::zero(), RuntimeTotals::from_json);
+        Runtime {
+            daily: totals("daily"),
+            monthly: totals("monthly"),
+            lifetime: totals("lifetime"),
+            day: json.find("day").and_then(|d| d.as_string())
+                .and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok()),
+            filter: json.find("filter").and_then(|s| s.as_i64()).and_then(persist::seconds).unwrap_or(Duration::zero()),
+            filter_limit: None,
+            last_update: None,
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Runtime> {
+        let mut file = File::open(path)?;
+        let mut s = String::new();
+        file.read_to_string(&mut s)?;
+
+        Json::from_str(&s)
+            .map(|json| Runtime::from_json(&json))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        persist::write_atomic(path, self.to_json().to_string().as_bytes())
+    }
+}
+
+impl ToJson for Runtime {
+    fn to_json(&self) -> Json {
+        let mut json = BTreeMap::new();
+        json.insert("daily".to_string(), self.daily.to_json());
+        json.insert("monthly".to_string(), self.monthly.to_json());
+        json.insert("lifetime".to_string(), self.lifetime.to_json());
+        json.insert("filter".to_string(), self.filter.num_seconds().to_json());
+        if let Some(day) = self.day {
+            json.insert("day".to_string(), day.format("%Y-%m-%d").to_string().to_json());
+        }
+        Json::Object(json)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::ac_control::compressor::CompressorMode;
+    use rustc_serialize::json::ToJson;
+
+    fn at(day: u32, hour: u32) -> DateTime<UTC> {
+        Local.ymd(2016, 7, day).and_hms(hour, 0, 0).with_timezone(&UTC)
+    }
+
+    #[test]
+    fn counts_runtime_of_the_outputs_that_were_on() {
+        let mut runtime = Runtime::new();
+
+        runtime.update(at(1, 10), CompressorMode::Off, false);
+        runtime.update(at(1, 11), CompressorMode::Cool, false);
+        runtime.update(at(1, 13), CompressorMode::HeatPump, true);
+
+        assert_eq!(runtime.daily.cool, Duration::hours(1));
+        assert_eq!(runtime.daily.heat, Duration::hours(2));
+        assert_eq!(runtime.daily.fan, Duration::hours(2));
+        assert_eq!(runtime.get_filter_runtime(), Duration::hours(3));
+    }
+
+    #[test]
+    fn starts_a_new_day_but_keeps_the_month() {
+        let mut runtime = Runtime::new();
+
+        runtime.update(at(1, 10), CompressorMode::Off, false);
+        runtime.update(at(1, 12), CompressorMode::Cool, false);
+        runtime.update(at(2, 10), CompressorMode::Off, false);
+
+        assert_eq!(runtime.daily.cool, Duration::zero());
+        assert_eq!(runtime.monthly.cool, Duration::hours(2));
+        assert_eq!(runtime.lifetime.cool, Duration::hours(2));
+    }
+
+    #[test]
+    fn reminds_to_change_the_filter() {
+        let mut runtime = Runtime::new();
+        runtime.set_filter_limit(Some(Duration::hours(2)));
+
+        runtime.update(at(1, 10), CompressorMode::Off, false);
+        runtime.update(at(1, 11), CompressorMode::Off, true);
+        assert!(runtime.get_reminders().is_empty());
+
+        runtime.update(at(1, 12), CompressorMode::Cool, false);
+        assert_eq!(runtime.get_reminders(), vec![Reminder::ChangeFilter]);
+
+        runtime.reset_filter_runtime();
+        assert!(runtime.get_reminders().is_empty());
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let mut runtime = Runtime::new();
+        runtime.update(at(1, 10), CompressorMode::Off, false);
+        runtime.update(at(1, 12), CompressorMode::HeatPump, true);
+
+        let restored = Runtime::from_json(&runtime.to_json());
+
+        assert_eq!(restored.daily, runtime.daily);
+        assert_eq!(restored.lifetime, runtime.lifetime);
+        assert_eq!(restored.get_filter_runtime(), Duration::hours(2));
+    }
+
+    #[test]
+    fn starts_damaged_counters_over() {
+        let json = Json::from_str(r#"{"lifetime":{"cool":9223372036854775807,"heat":-60,"fan":60},"filter":-1}"#).unwrap();
+
+        let restored = Runtime::from_json(&json);
+
+        assert_eq!(restored.lifetime, RuntimeTotals { cool: Duration::zero(), heat: Duration::zero(), fan: Duration::minutes(1) });
+        assert_eq!(restored.get_filter_runtime(), Duration::zero());
+    }
+}
@@ -0,0 +1,136 @@
+use chrono::*;
+
+/// Keeps track of how long air has been moving during the current hour, so the fan can be run for
+/// a number of minutes every hour without counting the time it already ran for heating or cooling.
+pub struct Circulation {
+    hour_start: Option<DateTime<UTC>>,
+    last_update: Option<DateTime<UTC>>,
+    run_time: Duration,
+}
+
+fn start_of_hour(time: DateTime<UTC>) -> DateTime<UTC> {
+    time.with_minute(0).and_then(|t| t.with_second(0)).and_then(|t| t.with_nanosecond(0)).unwrap()
+}
+
+impl Default for Circulation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Circulation {
+    pub fn new() -> Circulation {
+        Circulation {
+            hour_start: None,
+            last_update: None,
+            run_time: Duration::zero(),
+        }
+    }
+
+    /// `air_moving` is whether the fan ran since the last update. Returns true if the fan has to
+    /// run now to get `minutes` of circulation by the end of the hour. Running is put off until the
+    /// end of the hour so any heating or cooling in between counts towards it.
+    pub fn update(&mut self, time: DateTime<UTC>, air_moving: bool, minutes: u32) -> bool {
+        let hour_start = start_of_hour(time);
+
+        if self.hour_start != Some(hour_start) {
+            self.hour_start = Some(hour_start);
+            self.run_time = Duration::zero();
+            // only the part of the last interval that falls within this hour counts
+            self.last_update = self.last_update.map(|last| if last < hour_start { hour_start } else { last });
+        }
+
+        if let Some(last_update) = self.last_update {
+            if air_moving && time > last_update {
+                self.run_time = self.run_time + (time - last_update);
+            }
+        }
+        self.last_update = Some(time);
+
+        let needed = Duration::minutes(minutes as i64) - self.run_time;
+        let left = hour_start + Duration::hours(1) - time;
+
+        needed > Duration::zero() && left <= needed
+    }
+
+    /// When the fan has to start to get `minutes` of circulation this hour, as far as `update`
+    /// knows, or else the start of the next hour, when the count starts over
+    pub fn next_start(&self, time: DateTime<UTC>, minutes: u32) -> Option<DateTime<UTC>> {
+        if minutes == 0 {
+            return None;
+        }
+        let hour_start = start_of_hour(time);
+        let run_time = if self.hour_start == Some(hour_start) { self.run_time } else { Duration::zero() };
+        let next_hour = hour_start + Duration::hours(1);
+        let start = next_hour - (Duration::minutes(minutes as i64) - run_time);
+
+        Some(if start > time { start } else { next_hour })
+    }
+
+    pub fn get_run_time(&self) -> Duration {
+        self.run_time
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(minute: u32) -> DateTime<UTC> {
+        UTC.ymd(2016, 7, 1).and_hms(14, minute, 0)
+    }
+
+    #[test]
+    fn runs_the_fan_at_the_end_of_the_hour() {
+        let mut circulation = Circulation::new();
+
+        assert!(!circulation.update(at(0), false, 15));
+        assert!(!circulation.update(at(44), false, 15));
+        assert!(circulation.update(at(45), false, 15));
+        assert!(circulation.update(at(50), true, 15));
+    }
+
+    #[test]
+    fn counts_heating_and_cooling_towards_the_circulation() {
+        let mut circulation = Circulation::new();
+
+        circulation.update(at(0), false, 15);
+        circulation.update(at(10), true, 15);
+
+        assert!(!circulation.update(at(45), false, 15));
+        assert!(circulation.update(at(55), false, 15));
+    }
+
+    #[test]
+    fn stops_once_the_minutes_are_reached() {
+        let mut circulation = Circulation::new();
+
+        circulation.update(at(0), true, 15);
+
+        assert!(!circulation.update(at(15), true, 15));
+        assert_eq!(circulation.get_run_time(), Duration::minutes(15));
+    }
+
+    #[test]
+    fn knows_when_the_fan_has_to_start() {
+        let mut circulation = Circulation::new();
+
+        assert_eq!(circulation.next_start(at(0), 0), None);
+        circulation.update(at(0), false, 15);
+        circulation.update(at(10), true, 15);
+
+        assert_eq!(circulation.next_start(at(10), 15), Some(at(55)));
+        assert_eq!(circulation.next_start(at(56), 15), Some(UTC.ymd(2016, 7, 1).and_hms(15, 0, 0)));
+    }
+
+    #[test]
+    fn starts_over_every_hour() {
+        let mut circulation = Circulation::new();
+
+        circulation.update(at(30), true, 15);
+        circulation.update(at(59), true, 15);
+
+        assert!(!circulation.update(UTC.ymd(2016, 7, 1).and_hms(15, 1, 0), true, 15));
+        assert_eq!(circulation.get_run_time(), Duration::minutes(1));
+    }
+}
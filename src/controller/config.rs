@@ -2,7 +2,9 @@
 use ::uom::temp::Temperature as T;
 use ::uom::temp::*;
 use chrono::*;
+use std::fmt;
 use std::ops::Range;
+use std::str::FromStr;
 
 /// Intended to be copied into Controller, not moved or borrowed
 #[derive(Clone)]
@@ -10,8 +12,48 @@ pub struct Config {
     pub max_temp: Temperature<F>,
     pub min_temp: Temperature<F>,
     hold_end: Option<DateTime<UTC>>,
+    /// (max, min) in place of max_temp and min_temp until the hold ends
+    hold_setpoints: Option<(T<F>, T<F>)>,
     fan_end: Option<DateTime<UTC>>,
+    /// Minutes per hour to run the fan for, including the time it runs for heating or cooling
+    circulate_minutes: u32,
     schedule: Schedule,
+    mode: SystemMode,
+}
+
+/// What the user allows the thermostat to do
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SystemMode {
+    /// Heat or cool, whichever the temperature calls for
+    Auto,
+    Heat,
+    Cool,
+    Off,
+}
+
+impl fmt::Display for SystemMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            SystemMode::Auto => "auto",
+            SystemMode::Heat => "heat",
+            SystemMode::Cool => "cool",
+            SystemMode::Off => "off",
+        })
+    }
+}
+
+impl FromStr for SystemMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SystemMode, String> {
+        match s {
+            "auto" => Ok(SystemMode::Auto),
+            "heat" => Ok(SystemMode::Heat),
+            "cool" => Ok(SystemMode::Cool),
+            "off" => Ok(SystemMode::Off),
+            other => Err(format!("unknown mode {}, expected auto, heat, cool or off", other)),
+        }
+    }
 }
 
 // TODO: not sure if this belongs here or in the Controller. It protects the compressor, so it may even
@@ -24,6 +66,8 @@ pub struct ScheduleLeg {
     pub max_temp: Temperature<F>,
     pub weekdays: Vec<Weekday>,
     pub active_range: Range<NaiveTime>,
+    /// Overrides the circulate minutes of the `Config` while this leg is active
+    pub circulate_minutes: Option<u32>,
 }
 
 #[derive(Clone)]
@@ -33,27 +77,49 @@ pub struct Schedule {
 
 impl Schedule {
     pub fn new(legs: Vec<ScheduleLeg>) -> Schedule {
-        Schedule { legs: legs }
+        Schedule { legs }
     }
 
     pub fn get_active_leg(&self, current_datetime: DateTime<UTC>) -> Option<&ScheduleLeg> {
+        self.get_active_leg_index(current_datetime).map(|index| &self.legs[index])
+    }
+
+    /// The position of the active leg in the schedule
+    pub fn get_active_leg_index(&self, current_datetime: DateTime<UTC>) -> Option<usize> {
         let weekday = current_datetime.weekday();
         // shift the current time because the scheule legs use a NaiveTime
         let time = current_datetime.with_timezone(&Local).time();
-        self.legs.iter().find(|leg| {
-            leg.weekdays.iter().find(|w| **w == weekday) != None && leg.active_range.start <= time && leg.active_range.end >= time 
+        self.legs.iter().position(|leg| {
+            leg.weekdays.iter().find(|w| **w == weekday).is_some() && leg.active_range.start <= time && leg.active_range.end >= time 
         })
     }
+
+    /// The next time after `current_datetime` that a leg starts or ends. Every leg counts, today
+    /// and tomorrow, whatever its weekdays; that only means checking now and then for nothing.
+    pub fn next_boundary(&self, current_datetime: DateTime<UTC>) -> Option<DateTime<UTC>> {
+        let today = current_datetime.with_timezone(&Local).date();
+        self.legs.iter()
+            // the range includes its end, so the leg is over a moment later
+            .flat_map(|leg| vec![leg.active_range.start, leg.active_range.end + Duration::seconds(1)])
+            .flat_map(|time| vec![today.and_time(time), today.succ().and_time(time)])
+            .flatten()
+            .map(|boundary| boundary.with_timezone(&UTC))
+            .filter(|boundary| *boundary > current_datetime)
+            .min()
+    }
 }
 
 impl Config {
     pub fn new(max_temp: T<F>, min_temp: T<F>) -> Config  {
         Config {
-            max_temp: max_temp,
-            min_temp: min_temp,
+            max_temp,
+            min_temp,
             hold_end: None,
+            hold_setpoints: None,
             fan_end: None,
+            circulate_minutes: 0,
             schedule: Schedule::new(vec![]),
+            mode: SystemMode::Auto,
         }
     }
 
@@ -69,10 +135,41 @@ impl Config {
         self.hold_end = None
     }
 
+    /// Holds the current setpoints until the given time, or cancels the hold for None
+    pub fn set_hold_until(&mut self, end: Option<DateTime<UTC>>) {
+        self.hold_end = end;
+    }
+
+    /// What the hold keeps the temperature between, instead of max_temp and min_temp
+    pub fn set_hold_setpoints(&mut self, max_temp: T<F>, min_temp: T<F>) {
+        self.hold_setpoints = Some((max_temp, min_temp));
+    }
+
     pub fn cancel_fan_mode(&mut self) {
         self.fan_end = None
     }
 
+    /// When the hold ends, if it was ever started
+    pub fn get_hold_end(&self) -> Option<DateTime<UTC>> {
+        self.hold_end
+    }
+
+    pub fn is_hold_mode(&self, time: DateTime<UTC>) -> bool {
+        match self.hold_end {
+            Some(hold_end) => time < hold_end,
+            None => false
+        }
+    }
+
+    /// When the fan timer runs out, if it was ever started
+    pub fn get_fan_end(&self) -> Option<DateTime<UTC>> {
+        self.fan_end
+    }
+
+    pub fn set_fan_until(&mut self, end: Option<DateTime<UTC>>) {
+        self.fan_end = end;
+    }
+
     pub fn is_fan_on(&self, time: DateTime<UTC>) -> bool {
         match self.fan_end {
             Some(fan_end) => time < fan_end,
@@ -80,17 +177,56 @@ impl Config {
         }
     }
 
-    /// Returns a tuple of (minRange, maxRange) specifying the allowable ranges of temperatures
-    /// before turning on AC, Heat, ETC
-    pub fn get_temp_ranges(&self, time: DateTime<UTC>) -> (Range<T<F>>, Range<T<F>>) {
-        let temp_range = T::in_f(HOLD_RANGE.start)..T::in_f(HOLD_RANGE.end);
-        let (min_temp, max_temp) = match self.schedule.get_active_leg(time) {
+    pub fn set_mode(&mut self, mode: SystemMode) {
+        self.mode = mode;
+    }
+
+    pub fn get_mode(&self) -> SystemMode {
+        self.mode
+    }
+
+    pub fn set_circulate_minutes(&mut self, minutes: u32) {
+        self.circulate_minutes = minutes;
+    }
+
+    /// Minutes per hour the fan should be running at the given time
+    pub fn get_circulate_minutes(&self, time: DateTime<UTC>) -> u32 {
+        match self.schedule.get_active_leg(time) {
+            Some(&ScheduleLeg { circulate_minutes: Some(minutes), .. }) => minutes,
+            _ => self.circulate_minutes,
+        }
+    }
+
+    /// The next time after `time` the hold or the fan timer runs out, or the schedule changes legs
+    pub fn next_change(&self, time: DateTime<UTC>) -> Option<DateTime<UTC>> {
+        [self.hold_end, self.fan_end, self.schedule.next_boundary(time)].iter()
+            .flatten()
+            .filter(|change| **change > time)
+            .min()
+            .cloned()
+    }
+
+    /// The (min, max) temperatures in effect at the given time, from the schedule unless there is
+    /// a hold
+    pub fn get_setpoints(&self, time: DateTime<UTC>) -> (T<F>, T<F>) {
+        if self.is_hold_mode(time) {
+            let (max_temp, min_temp) = self.hold_setpoints.unwrap_or((self.max_temp, self.min_temp));
+            return (min_temp, max_temp);
+        }
+        match self.schedule.get_active_leg(time) {
             Some(active_leg) => {
                 info!("Schedule active!");
                 (active_leg.min_temp, active_leg.max_temp)
             },
             None => (self.min_temp, self.max_temp),
-        };
+        }
+    }
+
+    /// Returns a tuple of (minRange, maxRange) specifying the allowable ranges of temperatures
+    /// before turning on AC, Heat, ETC
+    pub fn get_temp_ranges(&self, time: DateTime<UTC>) -> (Range<T<F>>, Range<T<F>>) {
+        let temp_range = T::in_f(HOLD_RANGE.start)..T::in_f(HOLD_RANGE.end);
+        let (min_temp, max_temp) = self.get_setpoints(time);
 
         (
             (min_temp - temp_range.end)..(min_temp - temp_range.start),
@@ -98,6 +234,10 @@ impl Config {
         )
     }
 
+    pub fn get_schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
     pub fn set_schedule(&mut self, schedule: Schedule) {
         self.schedule = schedule;
     }
@@ -106,7 +246,6 @@ impl Config {
 #[cfg(test)]
 mod test {
     use super::*;
-    use chrono::*;
     use ::uom::temp::Temperature as T;
 
     #[test] 
@@ -129,7 +268,7 @@ mod test {
 
     #[test]
     fn fan_off_when_no_timeout_set() {
-        let mut config = Config::new(T::in_f(25.0), T::in_f(24.0)); 
+        let config = Config::new(T::in_f(25.0), T::in_f(24.0)); 
 
         assert!(!config.is_fan_on(UTC::now()));
     }
@@ -154,9 +293,73 @@ mod test {
 
     #[test]
     fn hold_off_when_no_timeout_set() {
-        let mut config = Config::new(T::in_f(25.0), T::in_f(24.0)); 
+        let config = Config::new(T::in_f(25.0), T::in_f(24.0)); 
 
         assert!(!config.is_hold_mode(UTC::now()));
     }
-}
 
+    #[test]
+    fn hold_ignores_the_schedule() {
+        let mut config = Config::new(T::in_f(78.0), T::in_f(72.0));
+        config.set_schedule(Schedule::new(vec![ScheduleLeg {
+            min_temp: T::in_f(60.0),
+            max_temp: T::in_f(85.0),
+            weekdays: vec![Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu,
+                           Weekday::Fri, Weekday::Sat, Weekday::Sun],
+            active_range: NaiveTime::from_hms(0, 0, 0)..NaiveTime::from_hms(23, 59, 59),
+            circulate_minutes: None,
+        }]));
+        let now = UTC::now();
+
+        config.set_hold_until(Some(now + Duration::hours(1)));
+        let (min_range, _) = config.get_temp_ranges(now);
+        assert!(min_range.end == T::in_f(72.5));
+
+        config.set_hold_until(None);
+        let (min_range, _) = config.get_temp_ranges(now);
+        assert!(min_range.end == T::in_f(60.5));
+    }
+
+    #[test]
+    fn knows_when_the_hold_fan_or_schedule_change() {
+        let mut config = Config::new(T::in_f(78.0), T::in_f(72.0));
+        let now = UTC.ymd(2016, 7, 1).and_hms(12, 0, 0);
+        let local = now.with_timezone(&Local);
+        assert_eq!(config.next_change(now), None);
+
+        config.set_hold_until(Some(now + Duration::hours(3)));
+        config.set_fan_until(Some(now + Duration::hours(2)));
+        assert_eq!(config.next_change(now), Some(now + Duration::hours(2)));
+
+        let start = (local + Duration::hours(1)).time();
+        config.set_schedule(Schedule::new(vec![ScheduleLeg {
+            min_temp: T::in_f(60.0),
+            max_temp: T::in_f(85.0),
+            weekdays: vec![local.weekday()],
+            active_range: start..(start + Duration::minutes(30)),
+            circulate_minutes: None,
+        }]));
+        assert_eq!(config.next_change(now), Some(now + Duration::hours(1)));
+        assert_eq!(config.next_change(now + Duration::hours(1)),
+                   Some(now + Duration::minutes(90) + Duration::seconds(1)));
+    }
+
+    #[test]
+    fn schedule_leg_overrides_circulate_minutes() {
+        let mut config = Config::new(T::in_f(25.0), T::in_f(24.0));
+        config.set_circulate_minutes(10);
+
+        assert_eq!(config.get_circulate_minutes(UTC::now()), 10);
+
+        config.set_schedule(Schedule::new(vec![ScheduleLeg {
+            min_temp: T::in_f(24.0),
+            max_temp: T::in_f(25.0),
+            weekdays: vec![Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu,
+                           Weekday::Fri, Weekday::Sat, Weekday::Sun],
+            active_range: NaiveTime::from_hms(0, 0, 0)..NaiveTime::from_hms(23, 59, 59),
+            circulate_minutes: Some(20),
+        }]));
+
+        assert_eq!(config.get_circulate_minutes(UTC::now()), 20);
+    }
+}
@@ -0,0 +1,462 @@
+use ::uom::temp::*;
+use super::config::{Config, Schedule, ScheduleLeg, SystemMode};
+use ::persist;
+use chrono::*;
+use num::traits::FromPrimitive;
+use rustc_serialize::json::{Json, ToJson};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+/// The format `save` writes. Version 1 is the original config.json from the web UI, which had no
+/// version field.
+pub const CONFIG_VERSION: u64 = 5;
+
+/// The longest the fan, a hold or a stretch of history can be asked to last
+pub const MAX_DURATION_DAYS: i64 = 366;
+
+/// The user's settings as the web UI edits them and config.json stores them
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigFile {
+    pub max_temp_f: i32,
+    pub min_temp_f: i32,
+    pub fan_duration_hours: i32,
+    pub schedule: Vec<ScheduleEntry>,
+    pub mode: SystemMode,
+    /// The setpoints are held, ignoring the schedule, until then
+    pub hold_until: Option<DateTime<UTC>>,
+    /// The setpoints while the hold lasts, or max_temp_f and min_temp_f for None. Those are back
+    /// once it is over.
+    pub hold_max_temp_f: Option<i32>,
+    pub hold_min_temp_f: Option<i32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScheduleEntry {
+    pub min_temp_f: i32,
+    pub max_temp_f: i32,
+    /// e.g. "7:30 AM"
+    pub start: String,
+    pub end: String,
+    /// 0 is Monday
+    pub days: Vec<i8>,
+    /// Overrides the circulate minutes from settings.json while the entry is active
+    pub circulate_minutes: Option<u32>,
+}
+
+fn field<T, F>(json: &Json, key: &str, convert: F) -> Result<T, String> where F: Fn(&Json) -> Option<T> {
+    json.find(key).and_then(convert).ok_or(format!("missing or invalid {}", key))
+}
+
+fn int(json: &Json) -> Option<i32> {
+    json.as_i64().and_then(|i| i32::try_from(i).ok())
+}
+
+fn optional_int(json: &Json, key: &str) -> Result<Option<i32>, String> {
+    match json.find(key) {
+        Some(&Json::Null) => Ok(None),
+        Some(value) => int(value).map(Some).ok_or(format!("invalid {}", key)),
+        None => Err(format!("missing {}", key)),
+    }
+}
+
+/// Brings json written by an older version up to the current format, one version at a time
+fn migrate(mut json: Json) -> Result<Json, String> {
+    let mut version = match json.find("version") {
+        Some(version) => version.as_u64().ok_or("invalid version".to_string())?,
+        None => 1,
+    };
+    if version > CONFIG_VERSION {
+        return Err(format!("version {} is newer than this thermostat understands", version));
+    }
+
+    while version < CONFIG_VERSION {
+        let object = json.as_object_mut().ok_or("expected an object".to_string())?;
+        match version {
+            // the system mode was added; before it the thermostat always heated and cooled
+            1 => { object.insert("mode".to_string(), SystemMode::Auto.to_string().to_json()); },
+            // holds were added
+            2 => { object.insert("holdUntil".to_string(), Json::Null); },
+            // schedule entries can circulate the air for their own number of minutes
+            3 => if let Some(&mut Json::Array(ref mut schedule)) = object.get_mut("schedule") {
+                for entry in schedule.iter_mut().filter_map(|entry| entry.as_object_mut()) {
+                    entry.insert("circulateMinutes".to_string(), Json::Null);
+                }
+            },
+            // holds got setpoints of their own; before they were written over maxTempF and minTempF
+            4 => {
+                object.insert("holdMaxTempF".to_string(), Json::Null);
+                object.insert("holdMinTempF".to_string(), Json::Null);
+            },
+            _ => unreachable!(),
+        }
+        version += 1;
+        object.insert("version".to_string(), version.to_json());
+    }
+
+    Ok(json)
+}
+
+impl ScheduleEntry {
+    fn from_json(json: &Json) -> Result<ScheduleEntry, String> {
+        Ok(ScheduleEntry {
+            min_temp_f: field(json, "minTempF", int)?,
+            max_temp_f: field(json, "maxTempF", int)?,
+            start: field(json, "start", |s| s.as_string().map(|s| s.to_string()))?,
+            end: field(json, "end", |s| s.as_string().map(|s| s.to_string()))?,
+            days: field(json, "days", |days| days.as_array().map(|days| {
+                days.iter().filter_map(|d| d.as_i64()).map(|d| d as i8).collect()
+            }))?,
+            circulate_minutes: match json.find("circulateMinutes") {
+                Some(&Json::Null) => None,
+                Some(minutes) => Some(minutes.as_u64().ok_or("invalid circulateMinutes".to_string())? as u32),
+                None => return Err("missing circulateMinutes".to_string()),
+            },
+        })
+    }
+
+    /// What is wrong with the entry, if anything. `to_leg` skips entries like that.
+    fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        for time in &[&self.start, &self.end] {
+            if NaiveTime::parse_from_str(time, "%I:%M %p").is_err() {
+                problems.push(format!("invalid time {}, expected e.g. 7:30 AM", time));
+            }
+        }
+        if let Some(day) = self.days.iter().find(|d| !(0..7).contains(*d)) {
+            problems.push(format!("invalid day {}, expected 0 (Monday) to 6", day));
+        }
+        if self.min_temp_f > self.max_temp_f {
+            problems.push(format!("minTempF {} is above maxTempF {}", self.min_temp_f, self.max_temp_f));
+        }
+        if let Some(minutes) = self.circulate_minutes.filter(|minutes| *minutes > 60) {
+            problems.push(format!("circulateMinutes {} is more than an hour", minutes));
+        }
+        problems
+    }
+
+    fn to_leg(&self) -> Option<ScheduleLeg> {
+        let start_result = NaiveTime::parse_from_str(&self.start, "%I:%M %p");
+        let end_result = NaiveTime::parse_from_str(&self.end, "%I:%M %p");
+
+        if let (Ok(start), Ok(end)) = (start_result, end_result) {
+            Some(ScheduleLeg {
+                min_temp: Temperature::in_f(self.min_temp_f as f32),
+                max_temp: Temperature::in_f(self.max_temp_f as f32),
+                active_range: start..end,
+                weekdays: self.days.iter().filter_map(|d| Weekday::from_i8(*d)).collect(),
+                circulate_minutes: self.circulate_minutes,
+            })
+        } else {
+            println!("error parsing {} {}", self.start, self.end);
+            None
+        }
+    }
+}
+
+impl ToJson for ScheduleEntry {
+    fn to_json(&self) -> Json {
+        let mut json = BTreeMap::new();
+        json.insert("minTempF".to_string(), self.min_temp_f.to_json());
+        json.insert("maxTempF".to_string(), self.max_temp_f.to_json());
+        json.insert("start".to_string(), self.start.to_json());
+        json.insert("end".to_string(), self.end.to_json());
+        json.insert("days".to_string(), self.days.to_json());
+        json.insert("circulateMinutes".to_string(), self.circulate_minutes.map_or(Json::Null, |minutes| minutes.to_json()));
+        Json::Object(json)
+    }
+}
+
+impl Default for ConfigFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConfigFile {
+    pub fn new() -> ConfigFile {
+        ConfigFile {
+            max_temp_f: 79,
+            min_temp_f: 70,
+            fan_duration_hours: 0,
+            schedule: vec![],
+            mode: SystemMode::Auto,
+            hold_until: None,
+            hold_max_temp_f: None,
+            hold_min_temp_f: None,
+        }
+    }
+
+    /// Reads any version of the format
+    pub fn from_json(json: &Json) -> Result<ConfigFile, String> {
+        let json = &migrate(json.clone())?;
+        Ok(ConfigFile {
+            max_temp_f: field(json, "maxTempF", int)?,
+            min_temp_f: field(json, "minTempF", int)?,
+            fan_duration_hours: field(json, "fanDurationHours", int)?,
+            schedule: json.find("schedule").and_then(|s| s.as_array())
+                .ok_or("missing or invalid schedule".to_string())?
+                .iter().map(ScheduleEntry::from_json).collect::<Result<_, _>>()?,
+            mode: field(json, "mode", |m| m.as_string().map(|m| m.to_string()))?.parse()?,
+            hold_until: match json.find("holdUntil") {
+                Some(&Json::Null) => None,
+                Some(Json::String(until)) => Some(DateTime::parse_from_rfc3339(until)
+                    .map_err(|e| format!("invalid holdUntil {}: {}", until, e))?
+                    .with_timezone(&UTC)),
+                _ => return Err("missing or invalid holdUntil".to_string()),
+            },
+            hold_max_temp_f: optional_int(json, "holdMaxTempF")?,
+            hold_min_temp_f: optional_int(json, "holdMinTempF")?,
+        })
+    }
+
+    /// Whether the setpoints are held at the given time
+    pub fn is_held(&self, time: DateTime<UTC>) -> bool {
+        self.hold_until.is_some_and(|until| time < until)
+    }
+
+    /// The (max, min) setpoints while the hold lasts
+    pub fn hold_setpoints(&self) -> (i32, i32) {
+        (self.hold_max_temp_f.unwrap_or(self.max_temp_f), self.hold_min_temp_f.unwrap_or(self.min_temp_f))
+    }
+
+    /// Everything that parses but makes no sense, e.g. schedule times `apply` would have to skip
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.min_temp_f > self.max_temp_f {
+            problems.push(format!("minTempF {} is above maxTempF {}", self.min_temp_f, self.max_temp_f));
+        }
+        let (hold_max_temp_f, hold_min_temp_f) = self.hold_setpoints();
+        if self.hold_until.is_some() && hold_min_temp_f > hold_max_temp_f {
+            problems.push(format!("the held minTempF {} is above the held maxTempF {}", hold_min_temp_f, hold_max_temp_f));
+        }
+        if self.fan_duration_hours < 0 {
+            problems.push(format!("fanDurationHours {} is negative", self.fan_duration_hours));
+        }
+        if self.fan_duration_hours as i64 > MAX_DURATION_DAYS * 24 {
+            problems.push(format!("fanDurationHours {} is more than {} days", self.fan_duration_hours, MAX_DURATION_DAYS));
+        }
+        for (index, entry) in self.schedule.iter().enumerate() {
+            problems.extend(entry.problems().into_iter().map(|p| format!("schedule[{}]: {}", index, p)));
+        }
+        problems
+    }
+
+    /// Copies these settings into the controller's config. The fan timer starts over from now.
+    pub fn apply(&self, config: &mut Config) {
+        config.max_temp = Temperature::in_f(self.max_temp_f as f32);
+        config.min_temp = Temperature::in_f(self.min_temp_f as f32);
+        config.set_fan_on(Duration::hours(self.fan_duration_hours as i64));
+        // TODO: all of this parsing should take place in the server, and return 401 Bad Request if it
+        // doesn't parse
+        config.set_schedule(Schedule::new(self.schedule.iter().filter_map(|s| s.to_leg()).collect()));
+        config.set_mode(self.mode);
+        config.set_hold_until(self.hold_until);
+        let (hold_max_temp_f, hold_min_temp_f) = self.hold_setpoints();
+        config.set_hold_setpoints(Temperature::in_f(hold_max_temp_f as f32), Temperature::in_f(hold_min_temp_f as f32));
+    }
+
+    fn parse(s: &str) -> Result<ConfigFile, String> {
+        Json::from_str(s)
+            .map_err(|e| e.to_string())
+            .and_then(|json| ConfigFile::from_json(&json))
+    }
+
+    /// Reads the file itself, without falling back to the backup
+    pub fn load_file(path: &Path) -> io::Result<ConfigFile> {
+        let mut file = File::open(path)?;
+        let mut s = String::new();
+        file.read_to_string(&mut s)?;
+
+        ConfigFile::parse(&s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Falls back to the backup `save` keeps when the file is missing or can't be read
+    pub fn load(path: &Path) -> io::Result<ConfigFile> {
+        ConfigFile::load_file(path).or_else(|e| {
+            let backup = persist::backup_path(path);
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("Could not read {}, trying {}: {}", path.display(), backup.display(), e);
+            }
+            // the original error is more useful than the backup's
+            ConfigFile::load_file(&backup).map_err(|_| e)
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        persist::write_with_backup(path, self.to_json().to_string().as_bytes(), |s| ConfigFile::parse(s).is_ok())
+    }
+}
+
+impl ToJson for ConfigFile {
+    fn to_json(&self) -> Json {
+        let mut json = BTreeMap::new();
+        json.insert("maxTempF".to_string(), self.max_temp_f.to_json());
+        json.insert("minTempF".to_string(), self.min_temp_f.to_json());
+        json.insert("fanDurationHours".to_string(), self.fan_duration_hours.to_json());
+        json.insert("schedule".to_string(), self.schedule.to_json());
+        json.insert("mode".to_string(), self.mode.to_string().to_json());
+        json.insert("holdUntil".to_string(), self.hold_until.map_or(Json::Null, |until| until.to_rfc3339().to_json()));
+        json.insert("holdMaxTempF".to_string(), self.hold_max_temp_f.map_or(Json::Null, |temp| temp.to_json()));
+        json.insert("holdMinTempF".to_string(), self.hold_min_temp_f.map_or(Json::Null, |temp| temp.to_json()));
+        json.insert("version".to_string(), CONFIG_VERSION.to_json());
+        Json::Object(json)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::controller::config::Config;
+    use ::uom::temp::Temperature as T;
+    use rustc_serialize::json::{Json, ToJson};
+    use tempdir::TempDir;
+
+    fn entry() -> ScheduleEntry {
+        ScheduleEntry {
+            min_temp_f: 68,
+            max_temp_f: 75,
+            start: "12:00 AM".to_string(),
+            end: "11:59 PM".to_string(),
+            days: vec![0, 1, 2, 3, 4, 5, 6],
+            circulate_minutes: None,
+        }
+    }
+
+    #[test]
+    fn reads_what_the_web_ui_saved() {
+        let json = Json::from_str(r#"{"maxTempF":78,"minTempF":72,"fanDurationHours":1,
+            "schedule":[{"minTempF":68,"maxTempF":75,"start":"12:00 AM","end":"11:59 PM","days":[0,1,2,3,4,5,6]}]}"#).unwrap();
+
+        let config_file = ConfigFile::from_json(&json).unwrap();
+
+        assert_eq!(config_file, ConfigFile { max_temp_f: 78, min_temp_f: 72, fan_duration_hours: 1, schedule: vec![entry()], ..ConfigFile::new() });
+        assert_eq!(ConfigFile::from_json(&config_file.to_json()), Ok(config_file));
+    }
+
+    #[test]
+    fn rejects_incomplete_json() {
+        let json = Json::from_str(r#"{"maxTempF":78,"minTempF":72}"#).unwrap();
+
+        assert!(ConfigFile::from_json(&json).is_err());
+    }
+
+    #[test]
+    fn applies_the_schedule() {
+        let mut config = Config::new(T::in_f(80.0), T::in_f(60.0));
+        let config_file = ConfigFile { schedule: vec![entry()], ..ConfigFile::new() };
+
+        config_file.apply(&mut config);
+
+        assert!(config.max_temp == T::in_f(79.0));
+        let (min_range, _) = config.get_temp_ranges(UTC.ymd(2016, 7, 1).and_hms(12, 0, 0));
+        assert!(min_range.end == T::in_f(68.5));
+    }
+
+    #[test]
+    fn keeps_the_hold() {
+        let config_file = ConfigFile { hold_until: Some(UTC.ymd(2016, 7, 1).and_hms(18, 0, 0)), ..ConfigFile::new() };
+
+        assert_eq!(ConfigFile::from_json(&config_file.to_json()), Ok(config_file.clone()));
+
+        let mut config = Config::new(T::in_f(80.0), T::in_f(60.0));
+        config_file.apply(&mut config);
+        assert!(config.is_hold_mode(UTC.ymd(2016, 7, 1).and_hms(17, 0, 0)));
+    }
+
+    #[test]
+    fn goes_back_to_the_setpoints_from_before_the_hold() {
+        let config_file = ConfigFile {
+            hold_until: Some(UTC.ymd(2016, 7, 1).and_hms(18, 0, 0)),
+            hold_max_temp_f: Some(74),
+            hold_min_temp_f: Some(72),
+            ..ConfigFile::new()
+        };
+        assert_eq!(ConfigFile::from_json(&config_file.to_json()), Ok(config_file.clone()));
+
+        let mut config = Config::new(T::in_f(80.0), T::in_f(60.0));
+        config_file.apply(&mut config);
+        let (min_temp, max_temp) = config.get_setpoints(UTC.ymd(2016, 7, 1).and_hms(17, 0, 0));
+        assert!(min_temp == T::in_f(72.0) && max_temp == T::in_f(74.0));
+        let (min_temp, max_temp) = config.get_setpoints(UTC.ymd(2016, 7, 1).and_hms(18, 0, 0));
+        assert!(min_temp == T::in_f(70.0) && max_temp == T::in_f(79.0));
+    }
+
+    #[test]
+    fn holds_the_setpoints_a_version_4_file_was_left_with() {
+        let json = Json::from_str(r#"{"version":4,"maxTempF":74,"minTempF":72,"fanDurationHours":0,"schedule":[],
+            "mode":"auto","holdUntil":"2016-07-01T18:00:00+00:00"}"#).unwrap();
+
+        let config_file = ConfigFile::from_json(&json).unwrap();
+
+        assert_eq!((config_file.hold_max_temp_f, config_file.hold_min_temp_f), (None, None));
+        assert_eq!(config_file.hold_setpoints(), (74, 72));
+    }
+
+    #[test]
+    fn keeps_the_circulate_minutes_of_schedule_entries() {
+        let config_file = ConfigFile { schedule: vec![ScheduleEntry { circulate_minutes: Some(15), ..entry() }], ..ConfigFile::new() };
+
+        let json = config_file.to_json();
+        assert_eq!(json.find_path(&["schedule"]).and_then(|s| s.as_array()).unwrap()[0].find("circulateMinutes"),
+                   Some(&Json::U64(15)));
+        assert_eq!(ConfigFile::from_json(&json), Ok(config_file.clone()));
+
+        let mut config = Config::new(T::in_f(80.0), T::in_f(60.0));
+        config.set_circulate_minutes(5);
+        config_file.apply(&mut config);
+        assert_eq!(config.get_circulate_minutes(UTC.ymd(2016, 7, 1).and_hms(12, 0, 0)), 15);
+    }
+
+    #[test]
+    fn finds_problems_that_parse() {
+        let bad_entry = ScheduleEntry { start: "25:00".to_string(), days: vec![7], circulate_minutes: Some(61), ..entry() };
+        let config_file = ConfigFile {
+            min_temp_f: 80,
+            fan_duration_hours: 2000000000,
+            schedule: vec![entry(), bad_entry],
+            hold_until: Some(UTC.ymd(2016, 7, 1).and_hms(18, 0, 0)),
+            hold_max_temp_f: Some(70),
+            hold_min_temp_f: Some(72),
+            ..ConfigFile::new()
+        };
+
+        assert_eq!(ConfigFile::new().problems(), Vec::<String>::new());
+        assert_eq!(config_file.problems(), vec![
+            "minTempF 80 is above maxTempF 79".to_string(),
+            "the held minTempF 72 is above the held maxTempF 70".to_string(),
+            "fanDurationHours 2000000000 is more than 366 days".to_string(),
+            "schedule[1]: invalid time 25:00, expected e.g. 7:30 AM".to_string(),
+            "schedule[1]: invalid day 7, expected 0 (Monday) to 6".to_string(),
+            "schedule[1]: circulateMinutes 61 is more than an hour".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn rejects_versions_from_the_future() {
+        let json = Json::from_str(r#"{"version":99,"maxTempF":78,"minTempF":72,"fanDurationHours":1,"schedule":[],"mode":"auto"}"#).unwrap();
+
+        assert!(ConfigFile::from_json(&json).is_err());
+    }
+
+    #[test]
+    fn saves_over_a_longer_file_and_falls_back_to_the_backup() {
+        let dir = TempDir::new("config").unwrap();
+        let path = dir.path().join("config.json");
+        let long = ConfigFile { schedule: vec![entry(), entry()], ..ConfigFile::new() };
+        let short = ConfigFile { mode: SystemMode::Cool, ..ConfigFile::new() };
+
+        long.save(&path).unwrap();
+        short.save(&path).unwrap();
+        assert_eq!(ConfigFile::load(&path).unwrap(), short);
+
+        // a half-written file, as left by the old save
+        File::create(&path).unwrap().write_all(b"{\"maxTempF\":7").unwrap();
+        assert_eq!(ConfigFile::load(&path).unwrap(), long);
+
+        assert_eq!(ConfigFile::load(&dir.path().join("missing.json")).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -1,18 +1,34 @@
 pub mod config;
+pub mod config_file;
+pub mod circulation;
+pub mod outdoor;
 
 use ::uom::temp::*;
 use ::ac_control::compressor::*;
 use ::sensors::TempListener;
 use self::config::Config;
+use self::circulation::Circulation;
+use self::outdoor::OutdoorRules;
+use ::events::{Event, EventKind};
 use chrono::*;
+use std::mem;
 
 pub struct Controller<'a> {
     config: Config,
     compressor: &'a mut Compressor<'a>,
     temp: Temperature<F>,
+    circulation: Circulation,
+    outdoor_temp: Option<Temperature<F>>,
+    outdoor_rules: OutdoorRules,
+    /// What the last tick found, to tell what changed since
+    last_status: Option<Status>,
+    active_leg: Option<usize>,
+    hold_end: Option<DateTime<UTC>>,
+    outdoor_blocked: bool,
+    events: Vec<Event>,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum Status {
     TooHot,
     TooCold,
@@ -22,20 +38,54 @@ pub enum Status {
 
 
 impl<'a> Controller<'a> {
-    pub fn new(compressor: &'a mut Compressor<'a>, config: Config, temp: Temperature<F>) -> Controller { 
+    pub fn new(compressor: &'a mut Compressor<'a>, config: Config, temp: Temperature<F>) -> Controller<'a> { 
         Controller { 
-            config: config,
-            compressor: compressor,
-            temp:  temp,
+            config,
+            compressor,
+            temp,
+            circulation: Circulation::new(),
+            outdoor_temp: None,
+            outdoor_rules: OutdoorRules::default(),
+            last_status: None,
+            active_leg: None,
+            hold_end: None,
+            outdoor_blocked: false,
+            events: vec![],
         } 
     }
 
+    pub fn set_outdoor_rules(&mut self, rules: OutdoorRules) {
+        self.outdoor_rules = rules;
+    }
+
+    /// None when there is no outdoor sensor, or it stopped reporting
+    pub fn outdoor_temp_changed(&mut self, temp: Option<Temperature<F>>) {
+        self.outdoor_temp = temp;
+    }
+
+    pub fn get_outdoor_temp(&self) -> Option<Temperature<F>> {
+        self.outdoor_temp
+    }
+
     pub fn update_config(&mut self, config: Config) {
         self.config = config;
         let temp = self.temp;
         self.temp_changed(temp);
     }
 
+    /// What the temperature called for at the last tick
+    pub fn get_status(&self) -> Option<Status> {
+        self.last_status
+    }
+
+    pub fn get_compressor(&self) -> &Compressor<'a> {
+        self.compressor
+    }
+
+    pub fn get_compressor_mut(&mut self) -> &mut Compressor<'a> {
+        self.compressor
+    }
+
     pub fn temp_changed(&mut self, temp: Temperature<F>) {
         self.temp = temp;
     }
@@ -44,22 +94,84 @@ impl<'a> Controller<'a> {
         use ::controller::Status::*;
         use ::ac_control::compressor::CompressorMode::*;
 
-        self.compressor.set_fan_mode(self.config.is_fan_on(time));
+        // the blower runs whenever the compressor does, so that counts as circulating too
+        let air_moving = self.compressor.get_fan_mode() || self.compressor.get_mode() != Off;
+        let circulate = self.circulation.update(time, air_moving, self.config.get_circulate_minutes(time));
 
+        self.check_changes(time);
         let status = self.check_status(time, self.temp);
-        match status {
-            TooHot => self.compressor.set_mode(Cool),
-            TooCold => self.compressor.set_mode(HeatPump),
-            JustRight => self.compressor.set_mode(Off),
-            Hold => (),
+        if self.last_status != Some(status) {
+            self.last_status = Some(status);
+            self.events.push(Event::new(time, EventKind::StatusChanged { status, temp: self.temp }));
         }
+        let mode = match status {
+            TooHot => Cool,
+            TooCold => HeatPump,
+            JustRight => Off,
+            // keep going towards whatever was asked for, so a fan pre-run still finishes
+            Hold => self.compressor.get_target_mode(),
+        };
         info!("Status: {:?}", status);
+
+        if self.outdoor_rules.allows(mode, self.config.get_mode(), self.outdoor_temp) {
+            self.outdoor_blocked = false;
+            self.compressor.set_mode_at(mode, time);
+        } else {
+            info!("{:?} is not allowed in {:?} mode at this outdoor temperature", mode, self.config.get_mode());
+            if !self.outdoor_blocked {
+                self.outdoor_blocked = true;
+                let reason = format!("not allowed in {} mode at this outdoor temperature", self.config.get_mode());
+                self.events.push(Event::new(time, EventKind::CompressorBlocked { mode, reason }));
+            }
+            self.compressor.set_mode_at(Off, time);
+        }
+
+        // after the mode, so a fan overrun started by turning the compressor off isn't toggled
+        self.compressor.set_fan_mode_at(self.config.is_fan_on(time) || circulate, time);
+    }
+
+    /// Notes the hold starting or ending, and the schedule moving to another leg
+    fn check_changes(&mut self, time: DateTime<UTC>) {
+        // a hold that is changed to end at another time counts as a new one
+        let hold_end = self.config.get_hold_end().filter(|_| self.config.is_hold_mode(time));
+        if hold_end != self.hold_end {
+            self.hold_end = hold_end;
+            let kind = match hold_end {
+                Some(until) => EventKind::HoldStarted { until },
+                None => EventKind::HoldEnded,
+            };
+            self.events.push(Event::new(time, kind));
+        }
+
+        let leg = self.config.get_schedule().get_active_leg_index(time);
+        if leg != self.active_leg {
+            self.active_leg = leg;
+            self.events.push(Event::new(time, EventKind::ScheduleLeg { leg }));
+        }
+    }
+
+    /// What happened since the last call, including in the compressor, oldest first
+    pub fn take_events(&mut self) -> Vec<Event> {
+        let mut events = mem::take(&mut self.events);
+        events.extend(self.compressor.take_events());
+        events.sort_by_key(|event| event.time);
+        events
+    }
+
+    /// The next time after `time` something could change without a new reading: one of the
+    /// compressor's timers, the hold, fan timer or schedule, or the circulation coming due
+    pub fn next_deadline(&self, time: DateTime<UTC>) -> Option<DateTime<UTC>> {
+        let circulation = self.circulation.next_start(time, self.config.get_circulate_minutes(time));
+        [self.compressor.next_deadline(time), self.config.next_change(time), circulation].iter()
+            .flatten()
+            .min()
+            .cloned()
     }
 
     pub fn check_status(&self, time: DateTime<UTC>, temp: Temperature<F>) -> Status {
         use ::controller::Status::*;
         
-        let (min_range, max_range) = self.config.get_temp_ranges(time);
+        let (min_range, max_range) = self.outdoor_rules.adjust_ranges(self.config.get_temp_ranges(time), self.outdoor_temp);
 
         if temp > max_range.end {
             TooHot
@@ -84,108 +196,174 @@ impl<'a> TempListener for Controller<'a> {
 #[cfg(test)]
 mod test {
     use super::*;
-    use super::config::Config;
-    use ::uom::temp::*;
-    use ::ac_control::compressor::*;
+    use ::ac_control::compressor::Switches;
+    use super::config::SystemMode;
     use ::uom::temp::Temperature as T;
-    use chrono::*;
+    use std::io;
+
+    struct MockSwitches;
+    impl Switches for MockSwitches {
+        fn set_cool(&mut self, _on: bool) -> io::Result<()> { Ok(()) }
+        fn set_heat(&mut self, _on: bool) -> io::Result<()> { Ok(()) }
+        fn set_fan(&mut self, _on: bool) -> io::Result<()> { Ok(()) }
+    }
+
+    fn config() -> Config {
+        Config::new(Temperature::in_f(77.0), Temperature::in_f(74.0))
+    }
 
     #[test]
     fn it_turns_on_the_compressor_if_the_temperature_is_above_the_target() {
-        let mut compressor = Compressor::new();
-        {
-            let config = Config::new(Temperature::in_f(77.0), Temperature::in_f(74.0));
-            let mut controller = Controller::new(&mut compressor, config);
+        let mut switches = MockSwitches;
+        let mut compressor = Compressor::new(&mut switches);
+        let mut controller = Controller::new(&mut compressor, config(), T::in_f(79.0));
 
-            controller.temp_changed(T::in_f(79.0));
-        }
+        controller.time_changed(UTC::now());
 
-        assert_eq!(CompressorMode::Cool, compressor.get_mode());
+        assert_eq!(CompressorMode::Cool, controller.get_compressor().get_mode());
     }
 
     #[test]
     fn it_turns_on_the_heat_pump_if_the_temperature_is_below_the_target() {
-        let mut compressor = Compressor::new();
-        {
-            let config = Config::new(Temperature::in_f(77.0), Temperature::in_f(74.0));
-            let mut controller = Controller::new(&mut compressor, config);
+        let mut switches = MockSwitches;
+        let mut compressor = Compressor::new(&mut switches);
+        let mut controller = Controller::new(&mut compressor, config(), T::in_f(73.0));
 
-            controller.temp_changed(T::in_f(73.0));
-        }
+        controller.time_changed(UTC::now());
 
-        assert_eq!(CompressorMode::HeatPump, compressor.get_mode());
+        assert_eq!(CompressorMode::HeatPump, controller.get_compressor().get_mode());
     }
 
     #[test]
     fn it_turns_off_the_ac_if_the_temperature_is_within_the_range() {
-        let mut compressor = Compressor::new();
+        let mut switches = MockSwitches;
+        let mut compressor = Compressor::new(&mut switches);
         compressor.set_min_change_duration(Duration::zero());
         compressor.set_mode(CompressorMode::Cool);
-        {
-            let config = Config::new(Temperature::in_f(77.0), Temperature::in_f(74.0));
-            let mut controller = Controller::new(&mut compressor, config);
+        let mut controller = Controller::new(&mut compressor, config(), T::in_f(76.0));
 
-            controller.temp_changed(T::in_f(76.0));
-        }
+        controller.time_changed(UTC::now() + Duration::seconds(1));
 
-        assert_eq!(CompressorMode::Off, compressor.get_mode());
+        assert_eq!(CompressorMode::Off, controller.get_compressor().get_mode());
     }
 
     #[test]
     fn it_doesnt_change_the_ac_or_heat_if_temperature_is_in_a_hold_range() {
-        let mut compressor = Compressor::new();
+        let mut switches = MockSwitches;
+        let mut compressor = Compressor::new(&mut switches);
         compressor.set_mode(CompressorMode::Cool);
         {
-            let config = Config::new(Temperature::in_f(77.0), Temperature::in_f(74.0));
-            let mut controller = Controller::new(&mut compressor, config);
+            let mut controller = Controller::new(&mut compressor, config(), T::in_f(76.5));
 
-            controller.temp_changed(T::in_f(76.5));
-        }
+            controller.time_changed(UTC::now());
 
-        assert_eq!(CompressorMode::Cool, compressor.get_mode());
+            assert_eq!(CompressorMode::Cool, controller.get_compressor().get_mode());
+        }
 
-        let mut compressor = Compressor::new();
+        let mut switches = MockSwitches;
+        let mut compressor = Compressor::new(&mut switches);
         compressor.set_mode(CompressorMode::Off);
+        let mut controller = Controller::new(&mut compressor, config(), T::in_f(76.5));
 
-        {
-            let config = Config::new(Temperature::in_f(77.0), Temperature::in_f(74.0));
-            let mut controller = Controller::new(&mut compressor, config);
+        controller.time_changed(UTC::now());
 
-            controller.temp_changed(T::in_f(76.5));
-        }
+        assert_eq!(CompressorMode::Off, controller.get_compressor().get_mode());
+    }
+
+    #[test]
+    fn it_stops_heating_when_the_weather_locks_the_heat_pump_out() {
+        let mut switches = MockSwitches;
+        let mut compressor = Compressor::new(&mut switches);
+        compressor.set_min_change_duration(Duration::zero());
+        let mut controller = Controller::new(&mut compressor, config(), T::in_f(73.0));
+        controller.set_outdoor_rules(OutdoorRules { heat_pump_min: Some(T::in_f(20.0)), ..OutdoorRules::default() });
+
+        controller.outdoor_temp_changed(Some(T::in_f(30.0)));
+        controller.time_changed(UTC::now());
+        assert_eq!(CompressorMode::HeatPump, controller.get_compressor().get_mode());
+
+        controller.outdoor_temp_changed(Some(T::in_f(10.0)));
+        controller.time_changed(UTC::now() + Duration::seconds(1));
+        assert_eq!(CompressorMode::Off, controller.get_compressor().get_mode());
+    }
+
+    #[test]
+    fn it_wakes_up_for_the_fan_timer() {
+        let mut switches = MockSwitches;
+        let mut compressor = Compressor::new(&mut switches);
+        let mut config = config();
+        let now = UTC::now();
+        config.set_fan_until(Some(now + Duration::minutes(5)));
+        let controller = Controller::new(&mut compressor, config, T::in_f(76.0));
+
+        assert_eq!(controller.next_deadline(now), Some(now + Duration::minutes(5)));
+    }
+
+    #[test]
+    fn it_reports_status_and_hold_changes() {
+        let mut switches = MockSwitches;
+        let mut compressor = Compressor::new(&mut switches);
+        let mut config = config();
+        let now = UTC::now();
+        config.set_hold_until(Some(now + Duration::minutes(30)));
+        let mut controller = Controller::new(&mut compressor, config, T::in_f(76.0));
+
+        controller.time_changed(now);
+        controller.time_changed(now + Duration::minutes(1));
+        controller.time_changed(now + Duration::minutes(31));
+
+        let events: Vec<EventKind> = controller.take_events().into_iter().map(|event| event.kind).collect();
+        assert_eq!(events, vec![
+            EventKind::HoldStarted { until: now + Duration::minutes(30) },
+            EventKind::StatusChanged { status: Status::JustRight, temp: T::in_f(76.0) },
+            EventKind::HoldEnded,
+        ]);
+    }
+
+    #[test]
+    fn it_does_nothing_when_the_system_is_off() {
+        let mut switches = MockSwitches;
+        let mut compressor = Compressor::new(&mut switches);
+        let mut config = config();
+        config.set_mode(SystemMode::Off);
+        let mut controller = Controller::new(&mut compressor, config, T::in_f(79.0));
+
+        controller.time_changed(UTC::now());
 
-        assert_eq!(CompressorMode::Off, compressor.get_mode());
+        assert_eq!(CompressorMode::Off, controller.get_compressor().get_mode());
     }
 
     #[test]
     fn it_only_triggers_the_ac_when_1_deg_over_the_hold_temp() {
-        let mut compressor = &mut Compressor::new();
-        let config = Config::new(Temperature::in_f(77.0), Temperature::in_f(74.0));
-        let controller = Controller::new(&mut compressor, config);
+        let mut switches = MockSwitches;
+        let mut compressor = Compressor::new(&mut switches);
+        let controller = Controller::new(&mut compressor, config(), T::in_f(76.0));
+        let now = UTC::now();
 
         assert_eq!(Status::JustRight,
-                   controller.check_status(Temperature::in_f(76.4)));
+                   controller.check_status(now, Temperature::in_f(76.4)));
         assert_eq!(Status::Hold,
-                   controller.check_status(Temperature::in_f(76.5)));
+                   controller.check_status(now, Temperature::in_f(76.5)));
         assert_eq!(Status::Hold,
-                   controller.check_status(Temperature::in_f(77.9)));
+                   controller.check_status(now, Temperature::in_f(77.9)));
         assert_eq!(Status::TooHot,
-                   controller.check_status(Temperature::in_f(78.0)));
+                   controller.check_status(now, Temperature::in_f(78.0)));
     }
 
     #[test]
     fn it_only_triggers_the_heat_when_1_deg_under_the_hold_temp() {
-        let mut compressor = &mut Compressor::new();
-        let config = Config::new(Temperature::in_f(77.0), Temperature::in_f(74.0));
-        let controller = Controller::new(&mut compressor, config);
+        let mut switches = MockSwitches;
+        let mut compressor = Compressor::new(&mut switches);
+        let controller = Controller::new(&mut compressor, config(), T::in_f(76.0));
+        let now = UTC::now();
 
         assert_eq!(Status::JustRight,
-                   controller.check_status(Temperature::in_f(74.6)));
+                   controller.check_status(now, Temperature::in_f(74.6)));
         assert_eq!(Status::Hold,
-                   controller.check_status(Temperature::in_f(74.5)));
+                   controller.check_status(now, Temperature::in_f(74.5)));
         assert_eq!(Status::Hold,
-                   controller.check_status(Temperature::in_f(73.1)));
+                   controller.check_status(now, Temperature::in_f(73.1)));
         assert_eq!(Status::TooCold,
-                   controller.check_status(Temperature::in_f(73.0)));
+                   controller.check_status(now, Temperature::in_f(73.0)));
     }
 }
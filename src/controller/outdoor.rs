@@ -0,0 +1,151 @@
+use ::uom::temp::Temperature as T;
+use ::uom::temp::*;
+use ::ac_control::compressor::CompressorMode;
+use super::config::SystemMode;
+use std::ops::Range;
+
+/// How the outdoor temperature changes what the thermostat does. Any rule left as None is off, and
+/// none of them apply while the outdoor temperature is unknown.
+#[derive(Clone, Debug)]
+pub struct OutdoorRules {
+    /// The heat pump's balance point. Below it the heat pump can't keep up (or shouldn't run), so
+    /// heating with it is locked out.
+    pub heat_pump_min: Option<T<F>>,
+    /// Cooling is locked out below this, to protect the compressor
+    pub cool_min: Option<T<F>>,
+    /// In Auto mode, only heat when it is colder than this outside
+    pub auto_heat_below: Option<T<F>>,
+    /// In Auto mode, only cool when it is hotter than this outside
+    pub auto_cool_above: Option<T<F>>,
+    /// Below this the heating setpoint is raised by `setpoint_adjust`, since cold walls make the
+    /// house feel cooler than it reads
+    pub adjust_below: Option<T<F>>,
+    /// Above this the cooling setpoint is lowered by `setpoint_adjust`
+    pub adjust_above: Option<T<F>>,
+    pub setpoint_adjust: T<F>,
+}
+
+impl Default for OutdoorRules {
+    fn default() -> OutdoorRules {
+        OutdoorRules {
+            heat_pump_min: None,
+            cool_min: None,
+            auto_heat_below: None,
+            auto_cool_above: None,
+            adjust_below: None,
+            adjust_above: None,
+            setpoint_adjust: T::in_f(0.0),
+        }
+    }
+}
+
+fn below(outdoor: Option<T<F>>, limit: Option<T<F>>) -> bool {
+    match (outdoor, limit) {
+        (Some(outdoor), Some(limit)) => outdoor < limit,
+        _ => false,
+    }
+}
+
+fn above(outdoor: Option<T<F>>, limit: Option<T<F>>) -> bool {
+    match (outdoor, limit) {
+        (Some(outdoor), Some(limit)) => outdoor > limit,
+        _ => false,
+    }
+}
+
+impl OutdoorRules {
+    /// Whether the compressor may run in this mode, given the system mode and the weather
+    pub fn allows(&self, compressor_mode: CompressorMode, mode: SystemMode, outdoor: Option<T<F>>) -> bool {
+        match compressor_mode {
+            CompressorMode::Off => true,
+            CompressorMode::HeatPump => match mode {
+                SystemMode::Heat => !below(outdoor, self.heat_pump_min),
+                SystemMode::Auto => !below(outdoor, self.heat_pump_min) && !above(outdoor, self.auto_heat_below),
+                _ => false,
+            },
+            CompressorMode::Cool => match mode {
+                SystemMode::Cool => !below(outdoor, self.cool_min),
+                SystemMode::Auto => !below(outdoor, self.cool_min) && !below(outdoor, self.auto_cool_above),
+                _ => false,
+            },
+        }
+    }
+
+    /// Shifts the (min, max) temperature ranges from `Config::get_temp_ranges` for the weather
+    pub fn adjust_ranges(&self, ranges: (Range<T<F>>, Range<T<F>>), outdoor: Option<T<F>>) -> (Range<T<F>>, Range<T<F>>) {
+        let (mut min_range, mut max_range) = ranges;
+
+        if below(outdoor, self.adjust_below) {
+            min_range = (min_range.start + self.setpoint_adjust)..(min_range.end + self.setpoint_adjust);
+        }
+        if above(outdoor, self.adjust_above) {
+            max_range = (max_range.start - self.setpoint_adjust)..(max_range.end - self.setpoint_adjust);
+        }
+
+        (min_range, max_range)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn outdoor(f: f32) -> Option<T<F>> {
+        Some(T::in_f(f))
+    }
+
+    #[test]
+    fn locks_the_heat_pump_out_below_its_balance_point() {
+        let rules = OutdoorRules { heat_pump_min: Some(T::in_f(25.0)), ..OutdoorRules::default() };
+
+        assert!(rules.allows(CompressorMode::HeatPump, SystemMode::Heat, outdoor(30.0)));
+        assert!(!rules.allows(CompressorMode::HeatPump, SystemMode::Heat, outdoor(20.0)));
+        assert!(!rules.allows(CompressorMode::HeatPump, SystemMode::Auto, outdoor(20.0)));
+        assert!(rules.allows(CompressorMode::HeatPump, SystemMode::Heat, None));
+    }
+
+    #[test]
+    fn auto_mode_picks_heat_or_cool_by_the_weather() {
+        let rules = OutdoorRules {
+            auto_heat_below: Some(T::in_f(60.0)),
+            auto_cool_above: Some(T::in_f(65.0)),
+            ..OutdoorRules::default()
+        };
+
+        assert!(rules.allows(CompressorMode::HeatPump, SystemMode::Auto, outdoor(40.0)));
+        assert!(!rules.allows(CompressorMode::Cool, SystemMode::Auto, outdoor(40.0)));
+        assert!(!rules.allows(CompressorMode::HeatPump, SystemMode::Auto, outdoor(90.0)));
+        assert!(rules.allows(CompressorMode::Cool, SystemMode::Auto, outdoor(90.0)));
+        // the explicit modes leave it to the user
+        assert!(rules.allows(CompressorMode::Cool, SystemMode::Cool, outdoor(40.0)));
+    }
+
+    #[test]
+    fn system_mode_limits_the_compressor() {
+        let rules = OutdoorRules::default();
+
+        assert!(!rules.allows(CompressorMode::Cool, SystemMode::Heat, None));
+        assert!(!rules.allows(CompressorMode::HeatPump, SystemMode::Cool, None));
+        assert!(!rules.allows(CompressorMode::Cool, SystemMode::Off, None));
+        assert!(rules.allows(CompressorMode::Off, SystemMode::Off, None));
+    }
+
+    #[test]
+    fn adjusts_setpoints_in_extreme_weather() {
+        let rules = OutdoorRules {
+            adjust_below: Some(T::in_f(20.0)),
+            adjust_above: Some(T::in_f(95.0)),
+            setpoint_adjust: T::in_f(1.0),
+            ..OutdoorRules::default()
+        };
+        let ranges = || (T::in_f(69.0)..T::in_f(70.0), T::in_f(76.0)..T::in_f(77.0));
+
+        let (min_range, max_range) = rules.adjust_ranges(ranges(), outdoor(10.0));
+        assert!(min_range == (T::in_f(70.0)..T::in_f(71.0)));
+        assert!(max_range == (T::in_f(76.0)..T::in_f(77.0)));
+
+        let (min_range, max_range) = rules.adjust_ranges(ranges(), outdoor(100.0));
+        assert!(min_range == (T::in_f(69.0)..T::in_f(70.0)));
+        assert!(max_range == (T::in_f(75.0)..T::in_f(76.0)));
+    }
+}
@@ -0,0 +1,692 @@
+//! The command line: `run` starts the thermostat, the other commands look at or change the one
+//! that is running through its control socket. When it isn't running, `status` shows what it last
+//! wrote to status.json, and `set` and `hold` change config.json for the next start.
+//!
+//! The setpoints and mode come from these places, each overriding the ones before it:
+//!
+//! 1. the defaults: 79F / 70F, auto
+//! 2. config.json in the state directory, i.e. whatever was last set
+//! 3. the flags given to `run`, which are saved to config.json at startup
+//! 4. changes made while it runs, from the web UI, `set` or `hold`. These are saved to config.json
+//!    too, so they stay until `run` is next started with flags.
+
+use ::controller::config::SystemMode;
+use ::controller::config_file::{ConfigFile, MAX_DURATION_DAYS};
+use ::history::{Query, Store};
+use ::history::export::{self, Format};
+use ::persist;
+use ::settings::Settings;
+use chrono::*;
+use rustc_serialize::json::{Json, ToJson};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use super::paths::Paths;
+use super::socket::{self, Request};
+
+pub static USAGE: &str = "\
+Usage: thermostat [--config settings.json] [--state-dir dir] <command> [options]
+
+Commands:
+  run [--max-temp F] [--min-temp F] [--mode MODE] [--interval SECONDS] [--control-interval SECONDS]
+      Runs the thermostat, reading the temperature every --interval (120) seconds and checking
+      what to do every --control-interval (10) seconds, or as soon as a timer runs out. The
+      setpoints and mode given here override config.json, and are saved to it.
+  status
+      Shows what the running thermostat is doing
+  validate-config
+      Checks settings.json and config.json, and exits with 1 if anything is wrong with them
+  set [--max-temp F] [--min-temp F] [--mode MODE] [--fan-hours HOURS]
+      Changes the setpoints, the mode (auto, heat, cool or off), or runs the fan for HOURS
+  hold [--max-temp F] [--min-temp F] (--hours HOURS | --until TIME | --cancel)
+      Keeps the setpoints, ignoring the schedule, for HOURS or until TIME (e.g.
+      2016-07-01T18:00:00-05:00)
+  fan (--minutes MINUTES | --cancel)
+      Runs the fan for MINUTES, or stops the fan timer
+  history [--minutes MINUTES | --from TIME [--to TIME]] [--resolution MINUTES]
+      Shows the readings of the last MINUTES (60), or from one time to another, or their
+      averages over every MINUTES of --resolution
+  export (--days DAYS | --from TIME [--to TIME]) [--resolution MINUTES] [--format FORMAT] [--output FILE]
+      Writes the averages over every MINUTES (60) of the last DAYS (7), or from one time to
+      another, as csv or json (csv unless FILE ends in .json) to FILE or the standard output
+  send JSON
+      Sends a request to the control socket as it is, and prints the answer
+
+`thermostat max_temp_f [min_temp_f] [sleep_duration_s]` still works, and is the same as `run`.";
+
+/// Changes to the user's config. Anything left as None stays as it is.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Changes {
+    pub max_temp_f: Option<i32>,
+    pub min_temp_f: Option<i32>,
+    pub mode: Option<SystemMode>,
+    pub fan_duration_hours: Option<i32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunOptions {
+    pub changes: Changes,
+    /// How often to read the sensors
+    pub interval_s: u64,
+    /// How often the controller looks at the readings, on top of when its timers run out
+    pub control_interval_s: u64,
+}
+
+/// A number of minutes from the command line, the control socket or MQTT as a duration, for any
+/// number at all, which only gets that far when it is between 0 and `MAX_DURATION_DAYS`
+pub fn minutes(minutes: f64, what: &str) -> Result<Duration, String> {
+    if minutes.is_finite() && (0.0..=(MAX_DURATION_DAYS * 24 * 60) as f64).contains(&minutes) {
+        Ok(Duration::minutes(minutes as i64))
+    } else {
+        Err(format!("invalid {}, expected up to {} days", what, MAX_DURATION_DAYS))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Hold {
+    For(Duration),
+    Until(DateTime<UTC>),
+    Cancel,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    Run(RunOptions),
+    ValidateConfig,
+    /// Anything the control socket does
+    Request(Request),
+    /// A raw line for the control socket
+    Send(String),
+    Export(Export),
+    Help,
+}
+
+/// What `export` writes, and where to
+#[derive(Clone, Debug, PartialEq)]
+pub struct Export {
+    pub query: Query,
+    pub format: Format,
+    /// The standard output when None
+    pub output: Option<PathBuf>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        *self == Changes::default()
+    }
+
+    pub fn apply(&self, config_file: &mut ConfigFile) {
+        config_file.max_temp_f = self.max_temp_f.unwrap_or(config_file.max_temp_f);
+        config_file.min_temp_f = self.min_temp_f.unwrap_or(config_file.min_temp_f);
+        config_file.mode = self.mode.unwrap_or(config_file.mode);
+        config_file.fan_duration_hours = self.fan_duration_hours.unwrap_or(config_file.fan_duration_hours);
+    }
+}
+
+/// Splits `--flag value` and `--flag=value` into the flag and its value
+fn flags(args: Vec<String>) -> Result<Vec<(String, Option<String>)>, String> {
+    let mut flags = vec![];
+    let mut args = args.into_iter().peekable();
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            return Err(format!("unexpected argument {}", arg));
+        }
+        match arg.find('=') {
+            Some(index) => flags.push((arg[..index].to_string(), Some(arg[index + 1..].to_string()))),
+            None => {
+                let value = args.next_if(|value| !value.starts_with("--"));
+                flags.push((arg, value));
+            },
+        }
+    }
+
+    Ok(flags)
+}
+
+fn value<T: FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or(format!("{} needs a value", flag))?;
+    value.parse().map_err(|_| format!("invalid value {} for {}", value, flag))
+}
+
+/// Takes the flags `set` understands, and complains about the rest unless `other` takes them
+fn changes<O>(flags: Vec<(String, Option<String>)>, mut other: O) -> Result<Changes, String>
+    where O: FnMut(&str, Option<String>) -> Result<bool, String>
+{
+    let mut changes = Changes::default();
+    for (flag, v) in flags {
+        match flag.as_str() {
+            "--max-temp" => changes.max_temp_f = Some(value(&flag, v)?),
+            "--min-temp" => changes.min_temp_f = Some(value(&flag, v)?),
+            "--mode" => changes.mode = Some(v.ok_or("--mode needs a value".to_string())?.parse()?),
+            "--fan-hours" => changes.fan_duration_hours = Some(value(&flag, v)?),
+            _ => if !other(&flag, v)? {
+                return Err(format!("unknown option {}", flag));
+            },
+        }
+    }
+    Ok(changes)
+}
+
+fn time_value(flag: &str, v: Option<String>) -> Result<DateTime<UTC>, String> {
+    DateTime::parse_from_rfc3339(&v.unwrap_or_default())
+        .map(|time| time.with_timezone(&UTC))
+        .map_err(|e| format!("invalid time for {}: {}", flag, e))
+}
+
+/// The old `max_temp_f [min_temp_f] [sleep_duration_s]` arguments
+fn positional(args: &[String]) -> Result<RunOptions, String> {
+    if args.len() > 3 {
+        return Err(format!("unexpected argument {}", args[3]));
+    }
+    // these used to take fractions of a degree, config.json never did
+    let degrees = |name, arg: &String| value::<f32>(name, Some(arg.clone())).map(|f| f.round() as i32);
+    let max_temp_f = degrees("max_temp_f", &args[0])?;
+
+    Ok(RunOptions {
+        changes: Changes {
+            max_temp_f: Some(max_temp_f),
+            min_temp_f: Some(args.get(1).map_or(Ok(max_temp_f), |min| degrees("min_temp_f", min))?),
+            ..Changes::default()
+        },
+        interval_s: args.get(2).map_or(Ok(120), |interval| value("sleep_duration_s", Some(interval.clone())))?,
+        control_interval_s: 10,
+    })
+}
+
+/// Parses the arguments (without the program name). `--config` and `--state-dir` can go anywhere.
+pub fn parse(args: Vec<String>, mut paths: Paths) -> Result<(Paths, Action), String> {
+    let mut rest = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.find('=') {
+            Some(index) if arg.starts_with("--") => (arg[..index].to_string(), Some(arg[index + 1..].to_string())),
+            _ => (arg.clone(), None),
+        };
+        match flag.as_str() {
+            "--config" => paths.settings = PathBuf::from(inline.or_else(|| args.next()).ok_or("--config needs a file")?),
+            "--state-dir" => paths.state_dir = PathBuf::from(inline.or_else(|| args.next()).ok_or("--state-dir needs a directory")?),
+            _ => rest.push(arg),
+        }
+    }
+
+    let command = match rest.first() {
+        Some(command) => command.clone(),
+        None => return Err("missing command".to_string()),
+    };
+    if command.parse::<f32>().is_ok() {
+        warn!("`thermostat {}` is deprecated, use `thermostat run --max-temp ...` instead", rest.join(" "));
+        return positional(&rest).map(|options| (paths, Action::Run(options)));
+    }
+
+    if command == "send" {
+        return match rest.len() {
+            2 => Ok((paths, Action::Send(rest.pop().unwrap()))),
+            _ => Err("send takes the request as one argument".to_string()),
+        };
+    }
+
+    let flags = flags(rest.split_off(1))?;
+    let no_flags = |action| match flags.first() {
+        Some((flag, _)) => Err(format!("unknown option {}", flag)),
+        None => Ok(action),
+    };
+
+    let action = match command.as_str() {
+        "run" => {
+            let (mut interval_s, mut control_interval_s) = (120, 10);
+            let changes = changes(flags, |flag, v| match flag {
+                "--interval" => value(flag, v).map(|v| { interval_s = v; true }),
+                "--control-interval" => value(flag, v).map(|v| { control_interval_s = v; true }),
+                _ => Ok(false),
+            })?;
+            if changes.fan_duration_hours.is_some() {
+                return Err("unknown option --fan-hours".to_string());
+            }
+            if interval_s == 0 || control_interval_s == 0 {
+                return Err("the intervals have to be at least a second".to_string());
+            }
+            Action::Run(RunOptions { changes, interval_s, control_interval_s })
+        },
+        "status" => no_flags(Action::Request(Request::Status))?,
+        "validate-config" => no_flags(Action::ValidateConfig)?,
+        "set" => {
+            let changes = changes(flags, |_, _| Ok(false))?;
+            if changes.is_empty() {
+                return Err("nothing to set".to_string());
+            }
+            Action::Request(Request::Set(changes))
+        },
+        "hold" => {
+            let mut hold = None;
+            let changes = changes(flags, |flag, v| {
+                hold = Some(match flag {
+                    "--hours" => Hold::For(minutes(value::<f64>(flag, v)? * 60.0, flag)?),
+                    "--until" => Hold::Until(time_value(flag, v)?),
+                    "--cancel" => Hold::Cancel,
+                    _ => return Ok(false),
+                });
+                Ok(true)
+            })?;
+            if changes.mode.is_some() || changes.fan_duration_hours.is_some() {
+                return Err("hold only takes --max-temp and --min-temp".to_string());
+            }
+            Action::Request(Request::Hold(changes, hold.ok_or("hold needs --hours, --until or --cancel".to_string())?))
+        },
+        "fan" => {
+            let mut fan = None;
+            changes(flags, |flag, v| {
+                fan = Some(match flag {
+                    "--minutes" => Some(minutes(value(flag, v)?, flag)?),
+                    "--cancel" => None,
+                    _ => return Ok(false),
+                });
+                Ok(true)
+            }).and_then(|changes| if changes.is_empty() { Ok(()) } else { Err("fan only takes --minutes or --cancel".to_string()) })?;
+            Action::Request(Request::Fan(fan.ok_or("fan needs --minutes or --cancel".to_string())?))
+        },
+        "export" => {
+            let mut query = Query { resolution: Some(Duration::hours(1)), ..Query::last(Duration::days(7)) };
+            let (mut format, mut output) = (None, None::<PathBuf>);
+            changes(flags, |flag, v| {
+                match flag {
+                    "--days" => query.last = minutes(value::<f64>(flag, v)? * 24.0 * 60.0, flag)?,
+                    "--from" => query.start = Some(time_value(flag, v)?),
+                    "--to" => query.end = Some(time_value(flag, v)?),
+                    "--resolution" => query.resolution = Some(minutes(value(flag, v)?, flag)?),
+                    "--format" => format = Some(value(flag, v)?),
+                    "--output" => output = Some(value(flag, v)?),
+                    _ => return Ok(false),
+                }
+                Ok(true)
+            }).and_then(|changes| if changes.is_empty() { Ok(()) } else {
+                Err("export only takes --days, --from, --to, --resolution, --format and --output".to_string())
+            })?;
+            if query.resolution.is_none_or(|resolution| resolution <= Duration::zero()) {
+                return Err("--resolution needs to be at least a minute".to_string());
+            }
+            let json_file = output.as_ref().is_some_and(|output| output.extension().is_some_and(|e| e == "json"));
+            let format = format.unwrap_or(if json_file { Format::Json } else { Format::Csv });
+            Action::Export(Export { query, format, output })
+        },
+        "history" => {
+            let mut query = Query::last(Duration::hours(1));
+            changes(flags, |flag, v| {
+                match flag {
+                    "--minutes" => query.last = minutes(value(flag, v)?, flag)?,
+                    "--from" => query.start = Some(time_value(flag, v)?),
+                    "--to" => query.end = Some(time_value(flag, v)?),
+                    "--resolution" => query.resolution = Some(minutes(value(flag, v)?, flag)?).filter(|r| *r > Duration::zero()),
+                    _ => return Ok(false),
+                }
+                Ok(true)
+            }).and_then(|changes| if changes.is_empty() { Ok(()) } else {
+                Err("history only takes --minutes, --from, --to and --resolution".to_string())
+            })?;
+            Action::Request(Request::History(query))
+        },
+        "help" | "--help" | "-h" => Action::Help,
+        other => return Err(format!("unknown command {}", other)),
+    };
+
+    Ok((paths, action))
+}
+
+fn read_json(path: &Path) -> io::Result<Json> {
+    let mut s = String::new();
+    File::open(path)?.read_to_string(&mut s)?;
+    Json::from_str(&s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+/// config.json, or the defaults if it isn't there yet
+fn load_config(paths: &Paths) -> Result<ConfigFile, String> {
+    match ConfigFile::load(&paths.config()) {
+        Ok(config_file) => Ok(config_file),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(ConfigFile::new()),
+        Err(e) => Err(format!("cannot read {}: {}", paths.config().display(), e)),
+    }
+}
+
+fn save_config(paths: &Paths, config_file: &ConfigFile) -> Result<(), String> {
+    paths.create_state_dir()
+        .and_then(|_| config_file.save(&paths.config()))
+        .map_err(|e| format!("cannot write {}: {}", paths.config().display(), e))
+}
+
+/// The running thermostat's answer, or None when it isn't running
+fn ask(paths: &Paths, request: &Json) -> Option<Result<BTreeMap<String, Json>, String>> {
+    match socket::send(&paths.socket(), request) {
+        Ok(answer) => Some(socket::unwrap_answer(answer)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound || e.kind() == io::ErrorKind::ConnectionRefused => None,
+        Err(e) => Some(Err(format!("cannot talk to the thermostat: {}", e))),
+    }
+}
+
+/// Numbers to one decimal place, and nothing for null
+fn text(json: &Json, key: &str) -> String {
+    match json.find(key) {
+        None | Some(&Json::Null) => "-".to_string(),
+        Some(Json::String(s)) => s.clone(),
+        Some(Json::F64(f)) => format!("{:.1}", f),
+        Some(value) => value.to_string(),
+    }
+}
+
+/// Times in the local time zone, to the second
+fn time(json: &Json, key: &str) -> String {
+    let text = text(json, key);
+    DateTime::parse_from_rfc3339(&text)
+        .map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or(text)
+}
+
+fn print_status(json: &Json) {
+    println!("temperature:  {}F", text(json, "currentTempF"));
+    println!("humidity:     {}%", text(json, "humidity"));
+    println!("outdoor:      {}F", text(json, "outdoorTempF"));
+    println!("setpoints:    {}F - {}F", text(json, "minTempF"), text(json, "maxTempF"));
+    println!("mode:         {}", text(json, "mode"));
+    println!("hold until:   {}", time(json, "holdUntil"));
+    println!("running:      {}", text(json, "compressorMode"));
+    println!("fan:          {}", if text(json, "fanOn") == "true" { "on" } else { "off" });
+    println!("fan until:    {}", time(json, "fanUntil"));
+    println!("fault:        {}", text(json, "fault"));
+    for reminder in json.find("reminders").and_then(|r| r.as_array()).into_iter().flatten() {
+        println!("reminder:     {}", reminder.as_string().unwrap_or(""));
+    }
+}
+
+fn print_history(json: &Json) {
+    println!("{:<19} {:>6} {:>6} {:>8} {:>9} {:>9} fan", "time", "temp", "rh", "outdoor", "setpoints", "running");
+    for sample in json.as_array().into_iter().flatten() {
+        println!("{:<19} {:>6} {:>6} {:>8} {:>9} {:>9} {}", time(sample, "time"), text(sample, "currentTempF"),
+                 text(sample, "humidity"), text(sample, "outdoorTempF"), setpoints(sample), text(sample, "compressorMode"),
+                 if text(sample, "fanOn") == "true" { "on" } else { "off" });
+    }
+}
+
+/// Averages, with how much of the time cooling, heating and the fan were on
+fn print_summaries(json: &Json) {
+    println!("{:<19} {:>6} {:>6} {:>8} {:>9} {:>5} {:>5} {:>5}", "start", "temp", "rh", "outdoor", "setpoints", "cool", "heat", "fan");
+    for summary in json.as_array().into_iter().flatten() {
+        let percent = |key| summary.find(key).and_then(|v| v.as_f64()).map_or("-".to_string(), |v| format!("{:.0}%", v * 100.0));
+        println!("{:<19} {:>6} {:>6} {:>8} {:>9} {:>5} {:>5} {:>5}", time(summary, "start"), text(summary, "currentTempF"),
+                 text(summary, "humidity"), text(summary, "outdoorTempF"), setpoints(summary),
+                 percent("cooling"), percent("heating"), percent("fan"));
+    }
+}
+
+fn setpoints(json: &Json) -> String {
+    let degrees = |key| json.find(key).and_then(|v| v.as_f64()).map_or("-".to_string(), |v| format!("{:.0}", v));
+    format!("{}-{}", degrees("minTempF"), degrees("maxTempF"))
+}
+
+/// What `history` shows when the thermostat isn't running, straight from the files
+fn stored_history(paths: &Paths, query: &Query) -> Result<(), String> {
+    let json = Store::open(paths.history()).query(query, UTC::now())
+        .map_err(|e| format!("could not read {}: {}", paths.history().display(), e))?;
+    print_query(query, &json);
+    Ok(())
+}
+
+fn print_query(query: &Query, json: &Json) {
+    match query.resolution {
+        Some(_) => print_summaries(json),
+        None => print_history(json),
+    }
+}
+
+/// What `status` shows when the thermostat isn't running
+fn last_status(paths: &Paths) -> Result<(), String> {
+    let json = read_json(&paths.status())
+        .map_err(|e| format!("the thermostat isn't running, and there is no {}: {}", paths.status().display(), e))?;
+    println!("The thermostat isn't running. Last status, from {}:", time(&json, "updated"));
+    print_status(&json);
+    Ok(())
+}
+
+/// What `set` and `hold` do when the thermostat isn't running
+fn change_config_file(paths: &Paths, request: &Request) -> Result<(), String> {
+    let mut config_file = load_config(paths)?;
+    socket::change_config(request, &mut config_file, UTC::now())?;
+    save_config(paths, &config_file)?;
+    println!("The thermostat isn't running, saved to {} for when it starts", paths.config().display());
+    Ok(())
+}
+
+fn request(paths: &Paths, request: &Request) -> Result<(), String> {
+    let answer = match ask(paths, &request.to_json()) {
+        Some(answer) => answer?,
+        None => return match *request {
+            Request::Status => last_status(paths),
+            Request::Set(_) | Request::Hold(..) => change_config_file(paths, request),
+            Request::History(ref query) => stored_history(paths, query),
+            _ => Err(format!("the thermostat isn't running (nothing listens on {})", paths.socket().display())),
+        },
+    };
+
+    match *request {
+        Request::Status => answer.get("status").map(print_status),
+        Request::History(ref query) => answer.get("history").map(|json| print_query(query, json)),
+        _ => Some(()),
+    };
+    Ok(())
+}
+
+/// Reads the history files directly, so it works whether or not the thermostat runs
+fn export(paths: &Paths, e: &Export) -> Result<(), String> {
+    let (start, end) = e.query.range(UTC::now()).ok_or("the range is too long".to_string())?;
+    let summaries = Store::open(paths.history()).summaries(start, end, e.query.resolution.unwrap_or(Duration::hours(1)), UTC::now())
+        .map_err(|error| format!("could not read {}: {}", paths.history().display(), error))?;
+
+    let written = match e.output {
+        Some(ref path) => File::create(path).and_then(|mut file| export::write(e.format, &summaries, &mut file)),
+        None => export::write(e.format, &summaries, &mut io::stdout()),
+    };
+    written.map_err(|error| format!("could not write the export: {}", error))
+}
+
+fn validate_config(paths: &Paths) -> Result<(), String> {
+    let mut problems = 0;
+    let mut report = |path: &Path, found: Vec<String>| {
+        if found.is_empty() {
+            println!("{}: ok", path.display());
+        }
+        for problem in &found {
+            println!("{}: {}", path.display(), problem);
+        }
+        problems += found.len();
+    };
+
+    match read_json(&paths.settings) {
+        Ok(json) => report(&paths.settings, Settings::problems(&json)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => println!("{}: not there, using the defaults", paths.settings.display()),
+        Err(e) => report(&paths.settings, vec![e.to_string()]),
+    }
+
+    // not `load`, the backup would hide a broken file
+    match ConfigFile::load_file(&paths.config()) {
+        Ok(config_file) => report(&paths.config(), config_file.problems()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => println!("{}: not there, using the defaults", paths.config().display()),
+        Err(e) => report(&paths.config(), vec![e.to_string()]),
+    }
+
+    match problems {
+        0 => Ok(()),
+        1 => Err("found 1 problem".to_string()),
+        n => Err(format!("found {} problems", n)),
+    }
+}
+
+/// Carries out anything but `run`, which is up to the caller
+pub fn execute(action: &Action, paths: &Paths) -> Result<(), String> {
+    match *action {
+        Action::Run(_) => unreachable!(),
+        Action::Help => {
+            println!("{}", USAGE);
+            Ok(())
+        },
+        Action::ValidateConfig => validate_config(paths),
+        Action::Request(ref r) => request(paths, r),
+        Action::Export(ref e) => export(paths, e),
+        Action::Send(ref line) => {
+            let json = Json::from_str(line).map_err(|e| format!("invalid request: {}", e))?;
+            let answer = socket::send(&paths.socket(), &json).map_err(|e| format!("cannot talk to the thermostat: {}", e))?;
+            println!("{}", answer);
+            Ok(())
+        },
+    }
+}
+
+/// The status file the running thermostat keeps for `status`
+pub fn write_status(paths: &Paths, mut status: Json) -> io::Result<()> {
+    if let Some(object) = status.as_object_mut() {
+        object.insert("updated".to_string(), Json::String(UTC::now().to_rfc3339()));
+    }
+    persist::write_atomic(&paths.status(), status.to_string().as_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn defaults() -> Paths {
+        Paths { settings: PathBuf::from("settings.json"), state_dir: PathBuf::from(".") }
+    }
+
+    fn action(a: &[&str]) -> Result<Action, String> {
+        parse(args(a), defaults()).map(|(_, action)| action)
+    }
+
+    #[test]
+    fn takes_the_paths_from_anywhere() {
+        let (paths, action) = parse(args(&["--config", "/etc/t.json", "status", "--state-dir=/srv/t"]), defaults()).unwrap();
+
+        assert_eq!(paths, Paths { settings: PathBuf::from("/etc/t.json"), state_dir: PathBuf::from("/srv/t") });
+        assert_eq!(action, Action::Request(Request::Status));
+        assert!(parse(args(&["status", "--config"]), defaults()).is_err());
+    }
+
+    #[test]
+    fn parses_run() {
+        assert_eq!(action(&["run", "--max-temp", "78", "--mode=cool", "--interval", "30", "--control-interval=5"]), Ok(Action::Run(RunOptions {
+            changes: Changes { max_temp_f: Some(78), mode: Some(SystemMode::Cool), ..Changes::default() },
+            interval_s: 30,
+            control_interval_s: 5,
+        })));
+        assert_eq!(action(&["run"]), Ok(Action::Run(RunOptions { changes: Changes::default(), interval_s: 120, control_interval_s: 10 })));
+        assert!(action(&["run", "--max-temp", "warm"]).is_err());
+        assert!(action(&["run", "--mode", "dry"]).is_err());
+        assert!(action(&["run", "--fan-hours", "1"]).is_err());
+        assert!(action(&["run", "--control-interval", "0"]).is_err());
+    }
+
+    #[test]
+    fn still_takes_the_old_positional_arguments() {
+        assert_eq!(action(&["79", "74", "30"]), Ok(Action::Run(RunOptions {
+            changes: Changes { max_temp_f: Some(79), min_temp_f: Some(74), ..Changes::default() },
+            interval_s: 30,
+            control_interval_s: 10,
+        })));
+        assert_eq!(action(&["78.6"]), Ok(Action::Run(RunOptions {
+            changes: Changes { max_temp_f: Some(79), min_temp_f: Some(79), ..Changes::default() },
+            interval_s: 120,
+            control_interval_s: 10,
+        })));
+        assert!(action(&["79", "cold"]).is_err());
+    }
+
+    #[test]
+    fn rejects_what_it_doesnt_know() {
+        assert!(action(&[]).is_err());
+        assert!(action(&["start"]).is_err());
+        assert!(action(&["status", "--verbose"]).is_err());
+        assert!(action(&["set"]).is_err());
+        assert!(action(&["set", "78"]).is_err());
+        assert!(action(&["hold", "--max-temp", "78"]).is_err());
+        assert!(action(&["hold", "--mode", "cool", "--cancel"]).is_err());
+    }
+
+    #[test]
+    fn parses_hold() {
+        assert_eq!(action(&["hold", "--min-temp", "70", "--hours", "1.5"]), Ok(Action::Request(Request::Hold(
+            Changes { min_temp_f: Some(70), ..Changes::default() }, Hold::For(Duration::minutes(90))))));
+        assert_eq!(action(&["hold", "--until", "2016-07-01T18:00:00Z"]), Ok(Action::Request(Request::Hold(
+            Changes::default(), Hold::Until(UTC.ymd(2016, 7, 1).and_hms(18, 0, 0))))));
+        assert_eq!(action(&["hold", "--cancel"]), Ok(Action::Request(Request::Hold(Changes::default(), Hold::Cancel))));
+        assert!(action(&["hold", "--until", "tomorrow"]).is_err());
+    }
+
+    #[test]
+    fn parses_fan_history_and_send() {
+        assert_eq!(action(&["fan", "--minutes", "30"]), Ok(Action::Request(Request::Fan(Some(Duration::minutes(30))))));
+        assert_eq!(action(&["fan", "--cancel"]), Ok(Action::Request(Request::Fan(None))));
+        assert_eq!(action(&["history"]), Ok(Action::Request(Request::History(Query::last(Duration::hours(1))))));
+        assert_eq!(action(&["history", "--from", "2016-07-01T00:00:00Z", "--resolution", "60"]), Ok(Action::Request(Request::History(Query {
+            start: Some(UTC.ymd(2016, 7, 1).and_hms(0, 0, 0)),
+            resolution: Some(Duration::hours(1)),
+            ..Query::last(Duration::hours(1))
+        }))));
+        assert_eq!(action(&["send", r#"{"command":"status"}"#]), Ok(Action::Send(r#"{"command":"status"}"#.to_string())));
+        assert!(action(&["fan"]).is_err());
+        assert!(action(&["fan", "--minutes", "30", "--mode", "cool"]).is_err());
+        assert!(action(&["send"]).is_err());
+    }
+
+    #[test]
+    fn parses_export() {
+        let week = Query { resolution: Some(Duration::hours(1)), ..Query::last(Duration::days(7)) };
+        assert_eq!(action(&["export"]), Ok(Action::Export(Export { query: week.clone(), format: Format::Csv, output: None })));
+        assert_eq!(action(&["export", "--days", "30", "--resolution", "15", "--output", "july.json"]), Ok(Action::Export(Export {
+            query: Query { last: Duration::days(30), resolution: Some(Duration::minutes(15)), ..week.clone() },
+            format: Format::Json,
+            output: Some(PathBuf::from("july.json")),
+        })));
+        assert_eq!(action(&["export", "--output", "july.json", "--format", "csv"]).map(|a| match a {
+            Action::Export(e) => e.format,
+            _ => unreachable!(),
+        }), Ok(Format::Csv));
+        assert!(action(&["export", "--resolution", "0"]).is_err());
+        assert!(action(&["export", "--format", "xls"]).is_err());
+    }
+
+    #[test]
+    fn set_and_hold_change_config_json_while_stopped() {
+        let dir = TempDir::new("cli").unwrap();
+        let paths = Paths { settings: dir.path().join("settings.json"), state_dir: dir.path().join("state") };
+
+        execute(&Action::Request(Request::Set(Changes { mode: Some(SystemMode::Heat), ..Changes::default() })), &paths).unwrap();
+        execute(&Action::Request(Request::Hold(Changes { max_temp_f: Some(75), ..Changes::default() }, Hold::For(Duration::hours(2)))), &paths).unwrap();
+
+        let config_file = ConfigFile::load(&paths.config()).unwrap();
+        assert_eq!(config_file.mode, SystemMode::Heat);
+        assert_eq!((config_file.max_temp_f, config_file.hold_max_temp_f), (79, Some(75)));
+        assert!(config_file.hold_until.is_some_and(|until| until > UTC::now() + Duration::minutes(119)));
+
+        // min above max
+        assert!(execute(&Action::Request(Request::Set(Changes { min_temp_f: Some(80), ..Changes::default() })), &paths).is_err());
+        execute(&Action::Request(Request::Hold(Changes::default(), Hold::Cancel)), &paths).unwrap();
+        assert_eq!(ConfigFile::load(&paths.config()).unwrap().hold_until, None);
+    }
+
+    #[test]
+    fn validates_both_files() {
+        let dir = TempDir::new("cli").unwrap();
+        let paths = Paths { settings: dir.path().join("settings.json"), state_dir: dir.path().to_path_buf() };
+
+        assert_eq!(validate_config(&paths), Ok(()));
+
+        File::create(&paths.settings).unwrap().write_all(br#"{ "gpio": { "backend": "spi" } }"#).unwrap();
+        File::create(paths.config()).unwrap().write_all(b"{\"maxTempF\":7").unwrap();
+        assert_eq!(validate_config(&paths), Err("found 2 problems".to_string()));
+    }
+}
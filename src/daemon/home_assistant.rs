@@ -0,0 +1,121 @@
+//! Home Assistant MQTT discovery, for when `mqtt` in settings.json has a `discoveryPrefix`: a
+//! climate entity for the thermostat, sensors for its runtime and a problem sensor for output
+//! faults, all reading and writing the topics in `mqtt`. They are published under
+//! `<prefix>/<component>/<client id>/<entity>/config`, and again whenever Home Assistant comes
+//! back online.
+
+use ::mqtt::Message;
+use ::settings::MqttSettings;
+use rustc_serialize::json::{Json, ToJson};
+use std::collections::BTreeMap;
+
+/// How long the fan runs when Home Assistant sets the fan mode to on
+pub const FAN_MODE_ON_HOURS: i64 = 1;
+
+fn object(fields: Vec<(&str, Json)>) -> Json {
+    Json::Object(fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+}
+
+fn strings(values: &[&str]) -> Json {
+    Json::Array(values.iter().map(|value| value.to_json()).collect())
+}
+
+/// Where Home Assistant says it is online
+pub fn status_topic(prefix: &str) -> String {
+    format!("{}/status", prefix)
+}
+
+/// The client id, with only what Home Assistant allows in an id
+fn node_id(settings: &MqttSettings) -> String {
+    settings.client_id.chars().filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-').collect()
+}
+
+/// The discovery payloads, to publish retained. `humidity` says whether there is a humidity
+/// reading to show.
+pub fn discovery(settings: &MqttSettings, prefix: &str, humidity: bool) -> Vec<Message> {
+    let node = node_id(settings);
+    let topic = |name: &str| format!("{}/{}", settings.topic, name).to_json();
+    let entity = |component: &str, id: &str, fields: Vec<(&str, Json)>| {
+        let mut config: BTreeMap<String, Json> = fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect();
+        config.insert("unique_id".to_string(), format!("{}_{}", node, id).to_json());
+        config.insert("availability_topic".to_string(), topic("availability"));
+        config.insert("device".to_string(), object(vec![
+            ("identifiers", strings(&[&node])),
+            ("name", "Thermostat".to_json()),
+            ("model", "thermostat".to_json()),
+            ("sw_version", env!("CARGO_PKG_VERSION").to_json()),
+        ]));
+        Message::new(&format!("{}/{}/{}/{}/config", prefix, component, node, id), &Json::Object(config).to_string(), true)
+    };
+    let runtime = |id: &str, name: &str, field: &str| entity("sensor", id, vec![
+        ("name", name.to_json()),
+        ("state_topic", topic("runtime")),
+        ("value_template", format!("{{{{ (value_json.{} / 3600) | round(2) }}}}", field).to_json()),
+        ("unit_of_measurement", "h".to_json()),
+        ("device_class", "duration".to_json()),
+        ("state_class", "total_increasing".to_json()),
+    ]);
+
+    let mut climate = vec![
+        ("name", Json::Null),
+        ("modes", strings(&["heat_cool", "heat", "cool", "off"])),
+        ("mode_state_topic", topic("mode")),
+        // auto keeps the temperature between the setpoints, which Home Assistant calls heat_cool
+        ("mode_state_template", "{{ 'heat_cool' if value == 'auto' else value }}".to_json()),
+        ("mode_command_topic", topic("set/mode")),
+        ("mode_command_template", "{{ 'auto' if value == 'heat_cool' else value }}".to_json()),
+        ("fan_modes", strings(&["auto", "on"])),
+        ("fan_mode_state_topic", topic("fan_mode")),
+        ("fan_mode_command_topic", topic("set/fan_mode")),
+        ("current_temperature_topic", topic("temperature")),
+        ("temperature_high_state_topic", topic("max_temp")),
+        ("temperature_high_command_topic", topic("set/max_temp")),
+        ("temperature_low_state_topic", topic("min_temp")),
+        ("temperature_low_command_topic", topic("set/min_temp")),
+        ("action_topic", topic("action")),
+        ("temperature_unit", "F".to_json()),
+        ("temp_step", 1.to_json()),
+        ("precision", 0.1.to_json()),
+    ];
+    if humidity {
+        climate.push(("current_humidity_topic", topic("humidity")));
+    }
+
+    vec![
+        entity("climate", "climate", climate),
+        runtime("cool_today", "Cooling today", "daily.cool"),
+        runtime("heat_today", "Heating today", "daily.heat"),
+        runtime("fan_today", "Fan today", "daily.fan"),
+        runtime("filter", "Filter runtime", "filter"),
+        entity("binary_sensor", "fault", vec![
+            ("name", "Output fault".to_json()),
+            ("state_topic", topic("fault")),
+            ("value_template", "{{ 'ON' if value else 'OFF' }}".to_json()),
+            ("device_class", "problem".to_json()),
+        ]),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn describes_the_climate_entity_on_our_topics() {
+        let settings = MqttSettings { client_id: "hall thermostat".to_string(), ..MqttSettings::default() };
+        let messages = discovery(&settings, "homeassistant", false);
+
+        let climate = &messages[0];
+        assert_eq!(climate.topic, "homeassistant/climate/hallthermostat/climate/config");
+        assert!(climate.retain);
+        let config = Json::from_str(&climate.payload).unwrap();
+        assert_eq!(config.find("unique_id"), Some(&"hallthermostat_climate".to_json()));
+        assert_eq!(config.find("temperature_high_command_topic"), Some(&"thermostat/set/max_temp".to_json()));
+        assert_eq!(config.find("availability_topic"), Some(&"thermostat/availability".to_json()));
+        assert!(config.find("current_humidity_topic").is_none());
+
+        let filter = messages.iter().find(|m| m.topic == "homeassistant/sensor/hallthermostat/filter/config").unwrap();
+        assert_eq!(Json::from_str(&filter.payload).unwrap().find("value_template"),
+                   Some(&"{{ (value_json.filter / 3600) | round(2) }}".to_json()));
+    }
+}
@@ -0,0 +1,250 @@
+//! A small HTTP listener for things that poll the thermostat, set up with `metrics` in
+//! settings.json:
+//!
+//! - `/metrics`, the status for Prometheus
+//! - `/history`, taking the control socket's history parameters, e.g.
+//!   `/history?from=2016-07-01T00:00:00Z&resolutionMinutes=60`
+//!
+//! Every request gets its own connection, which is closed after the answer. Each connection is
+//! served on a thread of its own, so that a slow client doesn't hold up the scrapes, but only so
+//! many at once, and each only for so long and so many bytes.
+
+use ::history::Store;
+use ::status::StatusReport;
+use ::status::metrics;
+use chrono::*;
+use rustc_serialize::json::Json;
+use std::collections::BTreeMap;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration as StdDuration, Instant};
+
+use super::socket::Request;
+
+/// How long a client gets to send its request, all of it
+const READ_TIMEOUT: StdDuration = StdDuration::from_secs(2);
+/// How much of the request line and headers is read
+const MAX_REQUEST_BYTES: u64 = 8192;
+/// Connections past this many are closed right away
+const MAX_CONNECTIONS: usize = 8;
+
+/// Reads from the stream until `deadline`, after which reads time out
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl<'a> Read for Deadline<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left == StdDuration::ZERO {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "the request took too long"));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+/// One of the `MAX_CONNECTIONS`, given back when dropped
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn ok(content_type: &'static str, body: String) -> Response {
+        Response { status: "200 OK", content_type, body }
+    }
+
+    fn error(status: &'static str, message: &str) -> Response {
+        Response { status, content_type: "text/plain", body: format!("{}\n", message) }
+    }
+}
+
+fn unescape(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut unescaped = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|hex| ::std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                unescaped.push(byte);
+                i += 3;
+            },
+            (byte, _) => {
+                unescaped.push(byte);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+/// The query string as a history request for the control socket, with numbers as numbers
+fn history_request(query: &str) -> Result<Request, String> {
+    let mut json = BTreeMap::new();
+    for (key, value) in query.split('&').filter(|pair| !pair.is_empty()).map(|pair| pair.split_once('=').unwrap_or((pair, ""))) {
+        let value = unescape(value);
+        json.insert(unescape(key), value.parse::<f64>().map_or(Json::String(value), Json::F64));
+    }
+    json.insert("command".to_string(), Json::String("history".to_string()));
+    Request::from_json(&Json::Object(json))
+}
+
+fn respond(target: &str, history: &Path, status: &RwLock<StatusReport>) -> Response {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    match path {
+        "/metrics" => Response::ok(metrics::CONTENT_TYPE, metrics::render(&status.read().unwrap(), UTC::now())),
+        "/history" => match history_request(query) {
+            Ok(Request::History(query)) => match Store::open(history.to_path_buf()).query(&query, UTC::now()) {
+                Ok(json) => Response::ok("application/json", json.to_string()),
+                Err(e) => Response::error("500 Internal Server Error", &format!("could not read the history: {}", e)),
+            },
+            Ok(_) => unreachable!(),
+            Err(e) => Response::error("400 Bad Request", &e),
+        },
+        _ => Response::error("404 Not Found", "not found, try /metrics or /history"),
+    }
+}
+
+fn serve(stream: TcpStream, history: &Path, status: &RwLock<StatusReport>) -> io::Result<()> {
+    let mut reader = BufReader::new(Deadline { stream: &stream, deadline: Instant::now() + READ_TIMEOUT }.take(MAX_REQUEST_BYTES));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers don't matter, but are read so that closing doesn't reset the connection
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(target)) => respond(target, history, status),
+        (Some(_), Some(_)) => Response::error("405 Method Not Allowed", "only GET is supported"),
+        _ => Response::error("400 Bad Request", "invalid request"),
+    };
+    write!(&stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+           response.status, response.content_type, response.body.len(), response.body)
+}
+
+/// Starts listening on `address`, for as long as the thermostat runs. Returns the address it
+/// listens on, which has the port picked when `address` has port 0.
+pub fn listen(address: SocketAddr, history: PathBuf, status: Arc<RwLock<StatusReport>>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(address)?;
+    let address = listener.local_addr()?;
+    let connections = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(_) if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS => {
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                    warn!("Closing an HTTP connection, {} are open already", MAX_CONNECTIONS);
+                },
+                Ok(stream) => {
+                    let (slot, history, status) = (Slot(connections.clone()), history.clone(), status.clone());
+                    thread::spawn(move || {
+                        let _slot = slot;
+                        if let Err(e) = serve(stream, &history, &status) {
+                            warn!("Could not answer an HTTP request: {}", e);
+                        }
+                    });
+                },
+                Err(e) => warn!("Could not accept an HTTP connection: {}", e),
+            }
+        }
+    });
+    Ok(address)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::history::Query;
+
+    #[test]
+    fn reads_the_history_query_string() {
+        assert_eq!(history_request(""), Ok(Request::History(Query::last(Duration::hours(1)))));
+        assert_eq!(history_request("from=2016-07-01T00%3A00%3A00%2B02%3A00&resolutionMinutes=60"), Ok(Request::History(Query {
+            start: Some(UTC.ymd(2016, 6, 30).and_hms(22, 0, 0)),
+            resolution: Some(Duration::hours(1)),
+            ..Query::last(Duration::hours(1))
+        })));
+        assert!(history_request("minutes=soon").is_err());
+        assert!(history_request("minutes=1e12").is_err());
+    }
+
+    #[test]
+    fn serves_metrics_and_history() {
+        let dir = ::tempdir::TempDir::new("http").unwrap();
+        let status = RwLock::new(StatusReport::new());
+
+        let metrics = respond("/metrics", dir.path(), &status);
+        assert_eq!(metrics.status, "200 OK");
+        assert!(metrics.body.contains("thermostat_fan_on 0"));
+        assert_eq!(respond("/history?minutes=5", dir.path(), &status).body, "[]");
+        assert_eq!(respond("/history?from=yesterday", dir.path(), &status).status, "400 Bad Request");
+        assert_eq!(respond("/history?minutes=1e12", dir.path(), &status).status, "400 Bad Request");
+        assert_eq!(respond("/", dir.path(), &status).status, "404 Not Found");
+    }
+
+    #[test]
+    fn answers_while_another_client_is_slow() {
+        let dir = ::tempdir::TempDir::new("http").unwrap();
+        let address = listen("127.0.0.1:0".parse().unwrap(), dir.path().to_path_buf(), Arc::new(RwLock::new(StatusReport::new()))).unwrap();
+
+        // connected, but doesn't get around to asking
+        let _slow = TcpStream::connect(address).unwrap();
+        let scrape = TcpStream::connect(address).unwrap();
+        scrape.set_read_timeout(Some(READ_TIMEOUT / 2)).unwrap();
+        write!(&scrape, "GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+
+        let mut response = String::new();
+        BufReader::new(&scrape).read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn stops_reading_long_requests() {
+        let dir = ::tempdir::TempDir::new("http").unwrap();
+        let address = listen("127.0.0.1:0".parse().unwrap(), dir.path().to_path_buf(), Arc::new(RwLock::new(StatusReport::new()))).unwrap();
+
+        // a request line that doesn't end
+        let client = TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(READ_TIMEOUT / 2)).unwrap();
+        write!(&client, "GET /{}", "a".repeat(MAX_REQUEST_BYTES as usize - 5)).unwrap();
+
+        let mut response = String::new();
+        BufReader::new(&client).read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+    }
+
+    #[test]
+    fn only_serves_so_many_connections_at_once() {
+        let dir = ::tempdir::TempDir::new("http").unwrap();
+        let address = listen("127.0.0.1:0".parse().unwrap(), dir.path().to_path_buf(), Arc::new(RwLock::new(StatusReport::new()))).unwrap();
+
+        let _idle: Vec<_> = (0..MAX_CONNECTIONS).map(|_| TcpStream::connect(address).unwrap()).collect();
+        let extra = TcpStream::connect(address).unwrap();
+        extra.set_read_timeout(Some(READ_TIMEOUT / 2)).unwrap();
+
+        // closed without an answer, rather than left waiting
+        assert_eq!(BufReader::new(&extra).read(&mut [0; 16]).unwrap(), 0);
+    }
+}
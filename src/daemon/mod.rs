@@ -0,0 +1,544 @@
+//! The control loop, shared by the headless thermostat and the one with the web UI
+
+use ::uom::temp::*;
+use ::controller::*;
+use ::controller::config::Config;
+use ::controller::config_file::ConfigFile;
+use ::ac_control::compressor::*;
+use ::sensors::*;
+use ::settings::{Settings, GpioSettings, SensorSettings};
+#[cfg(not(feature = "sim"))]
+use ::settings::SensorSource;
+use ::status::StatusReport;
+use chrono::*;
+use ::history::{Sample, Store};
+use ::events::{self, Event, EventKind, EventLog, SensorFaults};
+use rustc_serialize::json::ToJson;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
+use std::sync::mpsc::{RecvTimeoutError, Receiver, Sender};
+use std::sync::{RwLock, Arc};
+use std::time::{Duration as StdDuration, Instant, SystemTime};
+
+#[cfg(feature = "sim")]
+use ::platform::sim;
+#[cfg(not(feature = "sim"))]
+use ::platform::linux;
+
+pub mod cli;
+pub mod home_assistant;
+pub mod http;
+pub mod mqtt;
+pub mod notify;
+pub mod paths;
+pub mod signals;
+pub mod socket;
+
+use self::cli::{Action, Changes, RunOptions};
+use self::notify::{Health, Notifier};
+use self::paths::Paths;
+use self::socket::{Answer, Request};
+
+/// How big events.jsonl gets before it's rotated, and how many of the rotated ones to keep
+const EVENT_LOG_BYTES: u64 = 1024 * 1024;
+const EVENT_LOG_KEEP: u32 = 4;
+/// The timers only run out once their time has passed, so wait for them a little longer
+const DEADLINE_SLACK: StdDuration = StdDuration::from_millis(100);
+/// How long to wait at startup for a sensor without a reading yet, e.g. a remote one
+const FIRST_READING_TIMEOUT: StdDuration = StdDuration::from_secs(5 * 60);
+const FIRST_READING_RETRY: StdDuration = StdDuration::from_secs(1);
+
+pub enum Command {
+    /// New settings from the UI, which also get saved to config.json
+    UpdateConfig(ConfigFile),
+    /// A request from the control socket, and where to send the answer
+    Request(Request, Sender<Answer>),
+    /// Turn everything off and return from `run`
+    Shutdown,
+}
+
+/// Handles the command line. Commands other than `run` are carried out here, and the process
+/// exits; for `run` it is up to the caller to start the thermostat with the options.
+pub fn parse_args() -> (Paths, RunOptions) {
+    let (paths, action) = match cli::parse(env::args().skip(1).collect(), Paths::default_for_user()) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("thermostat: {}\n\n{}", e, cli::USAGE);
+            process::exit(2);
+        },
+    };
+
+    match action {
+        Action::Run(options) => (paths, options),
+        action => match cli::execute(&action, &paths) {
+            Ok(()) => process::exit(0),
+            Err(e) => {
+                eprintln!("thermostat: {}", e);
+                process::exit(1);
+            },
+        },
+    }
+}
+
+/// config.json with the flags given to `run` on top. Those are saved right away, so that later
+/// changes start from them.
+pub fn startup_config(paths: &Paths, options: &RunOptions) -> ConfigFile {
+    let mut config_file = ConfigFile::load(&paths.config()).unwrap_or_default();
+    if !options.changes.is_empty() {
+        options.changes.apply(&mut config_file);
+        if let Err(e) = paths.create_state_dir().and_then(|_| config_file.save(&paths.config())) {
+            println!("Could not write to file {}", e);
+        }
+    }
+    config_file
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn load_settings(path: &Path) -> Settings {
+    match Settings::load(path) {
+        Ok(settings) => settings,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Settings::default(),
+        Err(e) => {
+            println!("Could not read {}, using defaults: {}", path.display(), e);
+            Settings::default()
+        }
+    }
+}
+
+#[cfg(feature = "sim")]
+fn open_switches(_settings: &GpioSettings) -> Box<dyn Switches> {
+    Box::new(sim::SimSwitches)
+}
+
+#[cfg(not(feature = "sim"))]
+fn open_switches(settings: &GpioSettings) -> Box<dyn Switches> {
+    use ::platform::linux::gpio::LineSwitches;
+    use ::settings::GpioBackend;
+
+    // the last arm is only reachable when some backend wasn't built in
+    #[allow(unreachable_patterns)]
+    let switches = match settings.backend {
+        #[cfg(feature = "mraa")]
+        GpioBackend::Mraa => LineSwitches::mraa(settings),
+        #[cfg(feature = "gpio-cdev")]
+        GpioBackend::Cdev(ref chip) => LineSwitches::cdev(chip, settings),
+        GpioBackend::Sysfs(ref root) => LineSwitches::sysfs(root, settings),
+        ref backend => Err(io::Error::other(format!("built without support for {:?}", backend))),
+    };
+
+    Box::new(switches.expect("Cannot set up the relay outputs"))
+}
+
+#[cfg(feature = "sim")]
+fn open_sensor(_settings: &Settings) -> Box<dyn TempReader> {
+    Box::new(sim::SimTemp)
+}
+
+#[cfg(not(feature = "sim"))]
+fn open_sensor(settings: &Settings) -> Box<dyn TempReader> {
+    open_source(&settings.sensors.source, &settings.sensors.indoor, &settings.sensors)
+}
+
+#[cfg(feature = "sim")]
+fn open_outdoor_sensor(_settings: &Settings) -> Option<Box<dyn TempReader>> {
+    Some(Box::new(sim::SimOutdoorTemp))
+}
+
+#[cfg(not(feature = "sim"))]
+fn open_outdoor_sensor(settings: &Settings) -> Option<Box<dyn TempReader>> {
+    settings.outdoor.source.as_ref().map(|source| open_source(source, &settings.outdoor.name, &settings.sensors))
+}
+
+/// `name` picks the sensor for sources that have several
+#[cfg(not(feature = "sim"))]
+fn open_source(source: &SensorSource, name: &Option<String>, settings: &SensorSettings) -> Box<dyn TempReader> {
+    use ::sensors::{outdoor, udp};
+
+    match *source {
+        SensorSource::Mcu => Box::new(linux::McuTemp),
+        SensorSource::W1(ref root) => Box::new(linux::w1::W1Temp::new(root, settings.names.clone(), name.clone())),
+        SensorSource::Sysfs(ref path) => Box::new(linux::hwmon::SysfsTemp(linux::hwmon::SysfsAttribute::new(path, settings.scale))),
+        SensorSource::Udp(port) => {
+            let sensors = udp::UdpSensors::bind(("0.0.0.0", port)).expect("Cannot listen for remote sensors");
+            Box::new(udp::UdpTemp::new(sensors, settings.names.clone(), name.clone(), settings.stale_after))
+        },
+        SensorSource::File(ref path) => Box::new(outdoor::FileTemp::new(path, settings.stale_after)),
+        SensorSource::Http(ref url) => Box::new(outdoor::HttpTemp::new(url)),
+    }
+}
+
+#[cfg(feature = "sim")]
+fn open_humidity_sensor(_settings: &SensorSettings) -> Option<Box<dyn HumidityReader>> {
+    None
+}
+
+#[cfg(not(feature = "sim"))]
+fn open_humidity_sensor(settings: &SensorSettings) -> Option<Box<dyn HumidityReader>> {
+    settings.humidity.as_ref().map(|path| {
+        Box::new(linux::hwmon::SysfsHumidity(linux::hwmon::SysfsAttribute::new(path, settings.scale))) as Box<dyn HumidityReader>
+    })
+}
+
+/// A config from the web UI, unless it makes no sense like the ones the control socket turns down
+fn web_update(update: ConfigFile) -> Option<ConfigFile> {
+    match update.problems().first() {
+        Some(problem) => {
+            warn!("Ignoring the config from the web UI: {}", problem);
+            None
+        },
+        None => Some(update),
+    }
+}
+
+/// Answers a request from the control socket, changing the config when asked to. Also says
+/// whether the config changed.
+fn answer(request: &Request, config_file: &mut ConfigFile, config: &mut Config, status: &StatusReport, history: &Store) -> (Answer, bool) {
+    let now = UTC::now();
+    let mut fields = BTreeMap::new();
+
+    let changed = match *request {
+        Request::Status => {
+            fields.insert("status".to_string(), status.to_json());
+            false
+        },
+        Request::History(ref query) => {
+            match history.query(query, now) {
+                Ok(json) => { fields.insert("history".to_string(), json); },
+                Err(e) => return (Err(format!("Could not read the history: {}", e)), false),
+            }
+            false
+        },
+        Request::Fan(Some(duration)) => {
+            config.set_fan_on(duration);
+            true
+        },
+        Request::Fan(None) => {
+            config.cancel_fan_mode();
+            // or it would start again with the next restart
+            config_file.fan_duration_hours = 0;
+            true
+        },
+        Request::Set(_) | Request::Hold(..) => {
+            if let Err(e) = socket::change_config(request, config_file, now) {
+                return (Err(e), false);
+            }
+            let fan_end = config.get_fan_end();
+            config_file.apply(config);
+            // only a new fan duration restarts the fan timer
+            if !matches!(*request, Request::Set(Changes { fan_duration_hours: Some(_), .. })) {
+                config.set_fan_until(fan_end);
+            }
+            true
+        },
+    };
+
+    if changed {
+        fields.insert("config".to_string(), config_file.to_json());
+    }
+    (Ok(fields), changed)
+}
+
+/// Brings the shared status, status.json and what systemd shows up to date
+fn report(status: &RwLock<StatusReport>, controller: &Controller, config: &Config, config_file: &ConfigFile, paths: &Paths, notifier: &mut Notifier) {
+    let now = UTC::now();
+    let (min_temp, max_temp) = config.get_setpoints(now);
+    let mut status = status.write().unwrap();
+
+    status.update_compressor(controller.get_compressor());
+    status.min_temp = Some(min_temp);
+    status.max_temp = Some(max_temp);
+    status.mode = config.get_mode();
+    status.hold_until = config_file.hold_until.filter(|until| *until > now);
+    status.fan_until = config.get_fan_end().filter(|until| *until > now);
+    status.controller_status = controller.get_status();
+    if let Err(e) = cli::write_status(paths, status.to_json()) {
+        warn!("Could not write {}: {}", paths.status().display(), e);
+    }
+    notifier.status(&status);
+}
+
+/// Counts the controller's events for the metrics, and logs them
+fn record(events: Vec<Event>, status: &RwLock<StatusReport>, event_log: &mut EventLog) {
+    status.write().unwrap().counters.count(&events);
+    event_log.write_all(events);
+}
+
+/// Counts a failed reading, and logs when the sensor starts or stops failing
+fn sensor_read(sensor: &str, result: Result<(), String>, faults: &mut SensorFaults, status: &RwLock<StatusReport>, event_log: &mut EventLog) {
+    if result.is_err() {
+        status.write().unwrap().counters.sensor_error(sensor);
+    }
+    event_log.write_all(faults.check(UTC::now(), sensor, result).into_iter().collect());
+}
+
+/// Has SIGTERM and SIGINT shut the thermostat down cleanly. Call it before starting any threads.
+pub fn handle_signals(commands: Sender<Command>) {
+    if let Err(e) = signals::forward(commands) {
+        println!("Could not set up signal handling, SIGTERM will kill the thermostat as is: {}", e);
+    }
+}
+
+/// Opens the control socket for the thermostat that is about to run
+pub fn listen(paths: &Paths, commands: Sender<Command>, status: Arc<RwLock<StatusReport>>) {
+    let result = paths.create_state_dir().and_then(|_| socket::listen(&paths.socket(), commands, status));
+    if let Err(e) = result {
+        println!("Could not open the control socket {}: {}", paths.socket().display(), e);
+    }
+}
+
+/// Publishes to and takes commands from the MQTT broker in settings.json, if there is one
+pub fn connect_mqtt(paths: &Paths, commands: Sender<Command>, status: Arc<RwLock<StatusReport>>) {
+    mqtt::start(load_settings(&paths.settings).mqtt, commands, status);
+}
+
+/// Runs the thermostat until it gets `Command::Shutdown`. Commands are handled as they come in,
+/// the sensors are read every `interval_s` and the controller runs every `control_interval_s`,
+/// after each reading and whenever one of its timers runs out. Changes to config.json, e.g. from
+/// `thermostat set` while the control socket was down, are picked up with the readings.
+///
+/// When run by systemd with `Type=notify`, it says it's ready once the outputs are off and the
+/// temperature has been read, and pings the watchdog for as long as the controller keeps running
+/// and the temperature can be read.
+pub fn run(mut config_file: ConfigFile, options: &RunOptions, paths: &Paths, commands: Receiver<Command>, status: Arc<RwLock<StatusReport>>) {
+    let settings = load_settings(&paths.settings);
+    if let Err(e) = paths.create_state_dir() {
+        println!("Could not create {}, nothing will be saved: {}", paths.state_dir.display(), e);
+    }
+
+    if let Some(address) = settings.metrics.listen {
+        if let Err(e) = http::listen(address, paths.history(), status.clone()) {
+            println!("Could not listen on {} for /metrics: {}", address, e);
+        }
+    }
+
+    let mut switches = open_switches(&settings.gpio);
+    let mut compressor = Compressor::new(&mut *switches);
+    compressor.set_fan_timing(settings.fan.pre_run, settings.fan.overrun);
+    compressor.set_filter_limit(settings.maintenance.filter_runtime);
+    compressor.set_runtime_file(paths.runtime());
+    compressor.reset_outputs_at(UTC::now());
+    let config_path = paths.config();
+
+    let mut temp_sensor = TempSensor::new(open_sensor(&settings));
+    let mut humidity_sensor = open_humidity_sensor(&settings.sensors);
+    let mut outdoor_sensor = open_outdoor_sensor(&settings);
+    let mut history = Store::open(paths.history());
+    let mut notifier = Notifier::from_env();
+
+    // the outputs stay off until there is a temperature to go by
+    let mut shutting_down = false;
+    let first_reading = temp_sensor.first_reading(FIRST_READING_TIMEOUT, || match commands.recv_timeout(FIRST_READING_RETRY) {
+        Ok(Command::Request(_, reply)) => {
+            let _ = reply.send(Err("waiting for the first temperature reading".to_string()));
+            true
+        },
+        Ok(Command::UpdateConfig(update)) => {
+            if let Some(update) = web_update(update) {
+                config_file = update;
+                if let Err(e) = config_file.save(&config_path) {
+                    println!("Could not write to file {}", e);
+                }
+            }
+            true
+        },
+        Ok(Command::Shutdown) | Err(RecvTimeoutError::Disconnected) => {
+            shutting_down = true;
+            false
+        },
+        Err(RecvTimeoutError::Timeout) => true,
+    });
+    let temp = match first_reading {
+        Some(temp) => temp,
+        None if shutting_down => {
+            println!("Shutting down");
+            notifier.stopping();
+            compressor.finish_shutdown_at(UTC::now());
+            return;
+        },
+        None => {
+            error!("Cannot continue without an initial temperature: {}", temp_sensor.get_error().unwrap_or("no reading"));
+            compressor.finish_shutdown_at(UTC::now());
+            process::exit(1);
+        },
+    };
+
+    let mut config = Config::new(Temperature::in_f(config_file.max_temp_f as f32), Temperature::in_f(config_file.min_temp_f as f32));
+    config.set_circulate_minutes(settings.fan.circulate_minutes);
+    config_file.apply(&mut config);
+    let mut config_modified = modified(&config_path);
+
+    let mut controller = Controller::new(&mut compressor, config.clone(), temp);
+    controller.set_outdoor_rules(settings.outdoor.rules.clone());
+    {
+        let mut status = status.write().unwrap();
+        status.temp = Some(temp);
+        status.humidity = temp_sensor.get_humidity();
+        status.last_reading = Some(UTC::now());
+    }
+
+    let mut event_log = EventLog::new(paths.events(), EVENT_LOG_BYTES, EVENT_LOG_KEEP);
+    let mut sensor_faults = SensorFaults::default();
+    notifier.ready(&status.read().unwrap());
+    let sensor_period = StdDuration::from_secs(options.interval_s);
+    let control_period = StdDuration::from_secs(options.control_interval_s);
+    let mut health = Health::new(control_period, sensor_period);
+    let mut next_reading = Instant::now();
+    let mut next_control = Instant::now();
+
+    'control: loop {
+        let reading_due = Instant::now() >= next_reading;
+        if reading_due {
+            next_reading = Instant::now() + sensor_period;
+
+            let config_modified_now = modified(&config_path);
+            if config_modified_now != config_modified {
+                config_modified = config_modified_now;
+                match ConfigFile::load_file(&config_path) {
+                    // rewriting the same settings shouldn't restart the fan timer
+                    Ok(ref update) if *update == config_file => (),
+                    Ok(update) => {
+                        println!("{} changed: {}", config_path.display(), update.to_json());
+                        let changes = events::diff(&config_file.to_json(), &update.to_json());
+                        event_log.write_all(vec![Event::new(UTC::now(), EventKind::ConfigChanged { source: "config.json".to_string(), changes })]);
+                        config_file = update;
+                        config_file.apply(&mut config);
+                        controller.update_config(config.clone());
+                    },
+                    Err(e) => warn!("Ignoring the change to {}: {}", config_path.display(), e),
+                }
+            }
+
+            if let Some(temp) = temp_sensor.get_updated_temp() {
+                println!("Temp changed {}", temp);
+                controller.on_temp_updated(temp);
+                status.write().unwrap().temp = Some(temp);
+            }
+            if !temp_sensor.is_failing() {
+                health.read();
+                status.write().unwrap().last_reading = Some(UTC::now());
+            }
+            if humidity_sensor.is_none() {
+                // from sensors that send it with the temperature, like the remote ones
+                status.write().unwrap().humidity = temp_sensor.get_humidity();
+            }
+            let indoor_result = temp_sensor.get_error().map_or(Ok(()), |e| Err(e.to_string()));
+            sensor_read("indoor", indoor_result, &mut sensor_faults, &status, &mut event_log);
+
+            if let Some(ref mut humidity_sensor) = humidity_sensor {
+                let result = match humidity_sensor.get_humidity() {
+                    Ok(humidity) => {
+                        status.write().unwrap().humidity = Some(humidity);
+                        Ok(())
+                    },
+                    Err(e) => {
+                        warn!("Could not read the humidity: {}", e);
+                        Err(e.to_string())
+                    },
+                };
+                sensor_read("humidity", result, &mut sensor_faults, &status, &mut event_log);
+            }
+
+            if let Some(ref mut outdoor_sensor) = outdoor_sensor {
+                // the weather rules are suspended until the outdoor sensor is back
+                let outdoor_temp = outdoor_sensor.get_temp().map_err(|e| {
+                    warn!("Could not read the outdoor temperature: {}", e);
+                    e.to_string()
+                });
+                let result = outdoor_temp.as_ref().map(|_| ()).map_err(|e| e.clone());
+                sensor_read("outdoor", result, &mut sensor_faults, &status, &mut event_log);
+                let outdoor_temp = outdoor_temp.ok();
+                controller.outdoor_temp_changed(outdoor_temp);
+                status.write().unwrap().outdoor_temp = outdoor_temp;
+            }
+
+            // new readings are acted on right away
+            next_control = Instant::now();
+        }
+
+        if Instant::now() >= next_control {
+            controller.time_changed(UTC::now());
+            record(controller.take_events(), &status, &mut event_log);
+            report(&status, &controller, &config, &config_file, paths, &mut notifier);
+            health.ticked();
+            next_control = Instant::now() + control_period;
+        }
+        if reading_due {
+            if let Err(e) = history.add(Sample::from_status(UTC::now(), &status.read().unwrap())) {
+                warn!("Could not write to {}: {}", paths.history().display(), e);
+            }
+        }
+        notifier.watchdog(&health);
+
+        // a timer running out, e.g. the end of a hold or the compressor's lockout, is acted on
+        // as it happens rather than at the next control period
+        let now = UTC::now();
+        if let Some(Ok(until)) = controller.next_deadline(now).map(|deadline| (deadline - now).to_std()) {
+            next_control = cmp::min(next_control, Instant::now() + until + DEADLINE_SLACK);
+        }
+
+        // wait for a command until the next reading, control period or watchdog ping
+        let wake = cmp::min(next_reading, next_control);
+        let wake = notifier.watchdog_due().map_or(wake, |due| cmp::min(due, wake));
+        let before = config_file.to_json();
+        let changed_by = match commands.recv_timeout(wake.saturating_duration_since(Instant::now())) {
+            Ok(Command::UpdateConfig(update)) => web_update(update).map(|update| {
+                println!("Config updated: {}", update.to_json());
+                config_file = update;
+                config_file.apply(&mut config);
+                "web"
+            }),
+            Ok(Command::Request(request, reply)) => {
+                let (answer, changed) = answer(&request, &mut config_file, &mut config, &status.read().unwrap(), &history);
+                // the client may have hung up already
+                let _ = reply.send(answer);
+                if changed { Some("socket") } else { None }
+            },
+            Ok(Command::Shutdown) => break 'control,
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => panic!("Command channel disconnected!"),
+        };
+
+        if let Some(source) = changed_by {
+            let changes = events::diff(&before, &config_file.to_json());
+            event_log.write_all(vec![Event::new(UTC::now(), EventKind::ConfigChanged { source: source.to_string(), changes })]);
+            controller.update_config(config.clone());
+            if let Err(e) = config_file.save(&config_path) {
+                println!("Could not write to file {}", e);
+            }
+            config_modified = modified(&config_path);
+            next_control = Instant::now();
+        }
+    }
+
+    println!("Shutting down");
+    notifier.stopping();
+    let fan_off_at = controller.get_compressor_mut().shut_down_at(UTC::now());
+    record(controller.take_events(), &status, &mut event_log);
+    report(&status, &controller, &config, &config_file, paths, &mut notifier);
+
+    // let the fan finish its overrun, unless asked again to shut down
+    while let Ok(timeout) = (fan_off_at - UTC::now()).to_std() {
+        match commands.recv_timeout(timeout) {
+            // anyone asking gets told the thermostat is shutting down, and config changes are dropped
+            Ok(Command::Request(_, reply)) => {
+                let _ = reply.send(Err("shutting down".to_string()));
+            },
+            Ok(Command::UpdateConfig(_)) => (),
+            Ok(Command::Shutdown) | Err(_) => break,
+        }
+    }
+
+    controller.get_compressor_mut().finish_shutdown_at(UTC::now());
+    if let Err(e) = config_file.save(&config_path) {
+        println!("Could not write to file {}", e);
+    }
+    report(&status, &controller, &config, &config_file, paths, &mut notifier);
+}
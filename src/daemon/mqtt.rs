@@ -0,0 +1,257 @@
+//! The thermostat on MQTT, when `mqtt` in settings.json names a broker. Under its topic
+//! (`thermostat` unless set otherwise):
+//!
+//! - `availability` is `online`, or `offline` once the thermostat goes away
+//! - `temperature`, `humidity`, `outdoor_temperature`, `min_temp`, `max_temp`, `mode`,
+//!   `compressor_mode`, `fan` (`on` or `off`), `status` and `hold_until` are retained, and
+//!   published as they change. They are empty while unknown, e.g. `humidity` without a humidity
+//!   sensor. So are `action` (`cooling`, `heating`, `idle` or `off`), `fan_mode` (`on` while the
+//!   fan timer runs, otherwise `auto`), `runtime` (the runtime counters as JSON, in seconds) and
+//!   `fault`, for Home Assistant.
+//! - `set/max_temp`, `set/min_temp` and `set/mode` change the settings, `set/fan` runs the fan for
+//!   so many minutes and `set/hold` keeps the setpoints for so many hours or until a time. Both
+//!   take `cancel` too. Setpoints have to be 40F to 100F, and the fan and holds last a year at
+//!   most. `set/fan_mode` is `on` or `auto` like `fan_mode`. These go to the control
+//!   loop the same way the control socket's do.
+//!
+//! With a `discoveryPrefix`, Home Assistant finds the thermostat on these; see `home_assistant`.
+//! Whenever the connection fails it is made again, waiting longer after each failure.
+
+use ::ac_control::compressor::CompressorMode;
+use ::controller::config::SystemMode;
+use ::mqtt::{Client, Message, Options};
+use ::settings::MqttSettings;
+use ::status::StatusReport;
+use ::uom::temp::*;
+use chrono::*;
+use rustc_serialize::json::ToJson;
+use std::collections::BTreeMap;
+use std::io;
+use std::ops::RangeInclusive;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration as StdDuration;
+
+use super::Command;
+use super::cli::{self, Changes, Hold};
+use super::home_assistant::{self, FAN_MODE_ON_HOURS};
+use super::socket::Request;
+
+/// The setpoints anyone can set over MQTT
+const SETPOINTS_F: RangeInclusive<f32> = 40.0..=100.0;
+/// How often the status is checked for changes to publish
+const POLL: StdDuration = StdDuration::from_secs(1);
+const MIN_BACKOFF: StdDuration = StdDuration::from_secs(1);
+const MAX_BACKOFF: StdDuration = StdDuration::from_secs(5 * 60);
+const ANSWER_TIMEOUT: StdDuration = StdDuration::from_secs(30);
+
+/// What gets published for the status, by topic
+fn state(status: &StatusReport) -> Vec<(&'static str, String)> {
+    let degrees = |temp: Option<Temperature<F>>| temp.map_or(String::new(), |temp| format!("{:.1}", temp.value()));
+    vec![
+        ("temperature", degrees(status.temp)),
+        ("humidity", status.humidity.map_or(String::new(), |humidity| format!("{:.1}", humidity))),
+        ("outdoor_temperature", degrees(status.outdoor_temp)),
+        ("min_temp", degrees(status.min_temp)),
+        ("max_temp", degrees(status.max_temp)),
+        ("mode", status.mode.to_string()),
+        ("compressor_mode", format!("{:?}", status.compressor_mode)),
+        ("fan", if status.fan { "on" } else { "off" }.to_string()),
+        ("status", status.controller_status.map_or(String::new(), |s| format!("{:?}", s))),
+        ("hold_until", status.hold_until.map_or(String::new(), |until| until.to_rfc3339())),
+        ("action", match (status.mode, status.compressor_mode) {
+            (SystemMode::Off, _) => "off",
+            (_, CompressorMode::Cool) => "cooling",
+            (_, CompressorMode::HeatPump) => "heating",
+            (_, CompressorMode::Off) => "idle",
+        }.to_string()),
+        ("fan_mode", if status.fan_until.is_some() { "on" } else { "auto" }.to_string()),
+        ("runtime", status.runtime.to_json().to_string()),
+        ("fault", status.fault.as_ref().map_or(String::new(), |fault| fault.to_string())),
+    ]
+}
+
+/// The request for a message to `set/<name>`
+fn command(name: &str, payload: &str) -> Result<Request, String> {
+    let degrees = || payload.parse::<f32>().ok()
+        .filter(|temp| SETPOINTS_F.contains(temp))
+        .map(|temp| temp.round() as i32)
+        .ok_or(format!("invalid temperature {}, expected {}F to {}F", payload, SETPOINTS_F.start(), SETPOINTS_F.end()));
+    match name {
+        "max_temp" => Ok(Request::Set(Changes { max_temp_f: Some(degrees()?), ..Changes::default() })),
+        "min_temp" => Ok(Request::Set(Changes { min_temp_f: Some(degrees()?), ..Changes::default() })),
+        "mode" => Ok(Request::Set(Changes { mode: Some(payload.parse()?), ..Changes::default() })),
+        "fan" => match payload {
+            "cancel" | "0" => Ok(Request::Fan(None)),
+            minutes => match minutes.parse::<u32>() {
+                Ok(minutes) => cli::minutes(minutes as f64, "fan minutes").map(|duration| Request::Fan(Some(duration))),
+                Err(_) => Err(format!("invalid fan minutes {}", minutes)),
+            },
+        },
+        "fan_mode" => match payload {
+            "on" => Ok(Request::Fan(Some(Duration::hours(FAN_MODE_ON_HOURS)))),
+            "auto" => Ok(Request::Fan(None)),
+            other => Err(format!("unknown fan mode {}, expected on or auto", other)),
+        },
+        "hold" => {
+            let hold = match payload {
+                "cancel" => Hold::Cancel,
+                hours => match hours.parse::<f32>() {
+                    Ok(hours) if hours > 0.0 => Hold::For(cli::minutes(hours as f64 * 60.0, "hold hours")?),
+                    _ => Hold::Until(DateTime::parse_from_rfc3339(payload)
+                        .map_err(|_| format!("invalid hold {}, expected hours, a time or cancel", payload))?
+                        .with_timezone(&UTC)),
+                },
+            };
+            Ok(Request::Hold(Changes::default(), hold))
+        },
+        other => Err(format!("unknown command {}", other)),
+    }
+}
+
+/// Passes the request on to the control loop, and returns false once that has gone away
+fn ask(commands: &Sender<Command>, request: Request, message: &Message) -> bool {
+    let (reply, answer) = channel();
+    if commands.send(Command::Request(request, reply)).is_err() {
+        return false;
+    }
+    match answer.recv_timeout(ANSWER_TIMEOUT) {
+        Ok(Ok(_)) => (),
+        Ok(Err(e)) => warn!("Could not do {} {}: {}", message.topic, message.payload, e),
+        Err(_) => warn!("No answer to {} {}", message.topic, message.payload),
+    }
+    true
+}
+
+/// One connection to the broker, for as long as it lasts. Returns Ok once the thermostat stops.
+fn session(settings: &MqttSettings, broker: &str, commands: &Sender<Command>, status: &RwLock<StatusReport>,
+           backoff: &mut StdDuration) -> io::Result<()> {
+    let topic = |name: &str| format!("{}/{}", settings.topic, name);
+    let mut client = Client::connect(&Options {
+        address: broker.to_string(),
+        client_id: settings.client_id.clone(),
+        username: settings.username.clone(),
+        password: settings.password.clone(),
+        keep_alive: settings.keep_alive.to_std().unwrap_or(StdDuration::from_secs(60)),
+        will: Some(Message::new(&topic("availability"), "offline", true)),
+    })?;
+    println!("Connected to the MQTT broker {}", broker);
+    *backoff = MIN_BACKOFF;
+
+    let discovery_status = settings.discovery_prefix.as_ref().map(|prefix| home_assistant::status_topic(prefix));
+    client.subscribe(&[topic("set/+")].iter().cloned().chain(discovery_status.clone()).collect::<Vec<_>>())?;
+    client.publish(&Message::new(&topic("availability"), "online", true))?;
+    let commands_prefix = topic("set/");
+    let mut published = BTreeMap::new();
+    let mut discovery_due = true;
+    loop {
+        // nothing until the control loop has reported, or the setpoints would go out empty
+        let status = status.read().unwrap().clone();
+        let state = if status.max_temp.is_some() { state(&status) } else { vec![] };
+        if let Some(ref prefix) = settings.discovery_prefix {
+            if discovery_due && !state.is_empty() {
+                for message in home_assistant::discovery(settings, prefix, status.humidity.is_some()) {
+                    client.publish(&message)?;
+                }
+                discovery_due = false;
+            }
+        }
+        for (name, payload) in state {
+            if published.get(name) != Some(&payload) {
+                client.publish(&Message::new(&topic(name), &payload, true))?;
+                published.insert(name, payload);
+            }
+        }
+
+        let message = match client.poll(POLL)? {
+            Some(message) => message,
+            None => continue,
+        };
+        // Home Assistant forgets what isn't retained when it restarts
+        if discovery_status.as_ref() == Some(&message.topic) {
+            if message.payload == "online" {
+                discovery_due = true;
+                published.clear();
+            }
+            continue;
+        }
+        // a retained command would be done again with every reconnect
+        if message.retain {
+            warn!("Ignoring the retained command {} {}", message.topic, message.payload);
+            continue;
+        }
+        if let Some(name) = message.topic.strip_prefix(commands_prefix.as_str()) {
+            match command(name, message.payload.trim()) {
+                Ok(request) => if !ask(commands, request, &message) {
+                    return client.disconnect();
+                },
+                Err(e) => warn!("Ignoring {} {}: {}", message.topic, message.payload, e),
+            }
+        }
+    }
+}
+
+/// Connects to the broker in the background, if `settings` name one
+pub fn start(settings: MqttSettings, commands: Sender<Command>, status: Arc<RwLock<StatusReport>>) {
+    let broker = match settings.broker.clone() {
+        Some(broker) => broker,
+        None => return,
+    };
+
+    thread::spawn(move || {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            match session(&settings, &broker, &commands, &status, &mut backoff) {
+                Ok(()) => return,
+                Err(e) => println!("Lost the MQTT broker {}, trying again in {}s: {}", broker, backoff.as_secs(), e),
+            }
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::controller::config::SystemMode;
+
+    #[test]
+    fn turns_messages_into_requests() {
+        assert_eq!(command("max_temp", "76.4"), Ok(Request::Set(Changes { max_temp_f: Some(76), ..Changes::default() })));
+        assert_eq!(command("mode", "cool"), Ok(Request::Set(Changes { mode: Some(SystemMode::Cool), ..Changes::default() })));
+        assert_eq!(command("fan", "30"), Ok(Request::Fan(Some(Duration::minutes(30)))));
+        assert_eq!(command("fan", "cancel"), Ok(Request::Fan(None)));
+        assert_eq!(command("fan_mode", "on"), Ok(Request::Fan(Some(Duration::hours(1)))));
+        assert_eq!(command("hold", "1.5"), Ok(Request::Hold(Changes::default(), Hold::For(Duration::minutes(90)))));
+        assert_eq!(command("hold", "2016-07-01T18:00:00Z"),
+                   Ok(Request::Hold(Changes::default(), Hold::Until(UTC.ymd(2016, 7, 1).and_hms(18, 0, 0)))));
+        assert!(command("hold", "-1").is_err());
+        assert!(command("min_temp", "warm").is_err());
+        assert!(command("max_temp", "NaN").is_err());
+        assert!(command("max_temp", "inf").is_err());
+        assert!(command("max_temp", "1e10").is_err());
+        assert!(command("hold", "inf").is_err());
+        assert!(command("hold", "1e10").is_err());
+        assert!(command("fan", "4000000000").is_err());
+        assert!(command("reboot", "").is_err());
+    }
+
+    #[test]
+    fn publishes_unknown_readings_as_empty() {
+        let mut status = StatusReport::new();
+        status.temp = Some(Temperature::in_f(72.25));
+        status.fan = true;
+
+        let state: BTreeMap<_, _> = state(&status).into_iter().collect();
+        assert_eq!(state["temperature"], "72.2");
+        assert_eq!(state["humidity"], "");
+        assert_eq!(state["mode"], "auto");
+        assert_eq!(state["compressor_mode"], "Off");
+        assert_eq!(state["fan"], "on");
+        assert_eq!(state["action"], "idle");
+        assert_eq!(state["fan_mode"], "auto");
+        assert_eq!(state["fault"], "");
+    }
+}
@@ -0,0 +1,228 @@
+//! Telling systemd how the thermostat is doing, for a `Type=notify` unit with a `WatchdogSec=`.
+//! Without `NOTIFY_SOCKET` in the environment all of this does nothing.
+
+use ::status::StatusReport;
+use std::env;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::time::{Duration, Instant};
+
+/// How many periods in a row the control loop can miss a tick or a reading before the watchdog
+/// stops getting pinged
+const MISSED_PERIODS: u32 = 3;
+
+pub struct Notifier {
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    /// How often to ping the watchdog, and when the next ping is due
+    watchdog: Option<(Duration, Instant)>,
+    last_status: String,
+}
+
+impl Notifier {
+    /// Set up from what systemd puts in the environment
+    pub fn from_env() -> Notifier {
+        let socket = env::var("NOTIFY_SOCKET").ok().filter(|socket| !socket.is_empty());
+        // the watchdog settings are only meant for us if WATCHDOG_PID is missing or our pid
+        let for_us = env::var("WATCHDOG_PID").ok().is_none_or(|pid| pid == process::id().to_string());
+        let watchdog = env::var("WATCHDOG_USEC").ok()
+            .filter(|_| for_us)
+            .and_then(|usec| usec.parse().ok())
+            .map(Duration::from_micros);
+
+        match socket {
+            Some(socket) => Notifier::connect(&socket, watchdog).unwrap_or_else(|e| {
+                println!("Could not open NOTIFY_SOCKET {}, systemd won't hear from the thermostat: {}", socket, e);
+                Notifier::disabled()
+            }),
+            None => Notifier::disabled(),
+        }
+    }
+
+    /// `socket` is a path, or an abstract socket name starting with @. The watchdog gets pinged
+    /// twice per `watchdog` timeout.
+    pub fn connect(socket: &str, watchdog: Option<Duration>) -> io::Result<Notifier> {
+        let address = match socket.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(socket)?,
+        };
+        Ok(Notifier {
+            socket: Some((UnixDatagram::unbound()?, address)),
+            watchdog: watchdog.map(|timeout| (timeout / 2, Instant::now() + timeout / 2)),
+            last_status: String::new(),
+        })
+    }
+
+    pub fn disabled() -> Notifier {
+        Notifier { socket: None, watchdog: None, last_status: String::new() }
+    }
+
+    fn send(&self, message: &str) {
+        if let Some((ref socket, ref address)) = self.socket {
+            if let Err(e) = socket.send_to_addr(message.as_bytes(), address) {
+                warn!("Could not notify systemd of {}: {}", message, e);
+            }
+        }
+    }
+
+    /// The thermostat has read the temperature and set up the outputs
+    pub fn ready(&mut self, status: &StatusReport) {
+        self.last_status = status_line(status);
+        self.send(&format!("READY=1\nSTATUS={}", self.last_status));
+    }
+
+    /// Updates the status systemctl shows, when it changed
+    pub fn status(&mut self, status: &StatusReport) {
+        let line = status_line(status);
+        if line != self.last_status {
+            self.send(&format!("STATUS={}", line));
+            self.last_status = line;
+        }
+    }
+
+    pub fn stopping(&self) {
+        self.send("STOPPING=1");
+    }
+
+    /// When `watchdog` should be called next, if there is a watchdog at all
+    pub fn watchdog_due(&self) -> Option<Instant> {
+        self.watchdog.map(|(_, due)| due)
+    }
+
+    /// Pings the watchdog when it's due, but only if the thermostat is healthy. If it's not,
+    /// systemd restarts it once the timeout runs out.
+    pub fn watchdog(&mut self, health: &Health) {
+        let now = Instant::now();
+        match self.watchdog {
+            Some((interval, due)) if due <= now => {
+                match health.problem() {
+                    None => self.send("WATCHDOG=1"),
+                    Some(problem) => warn!("Not pinging the watchdog, {}", problem),
+                }
+                self.watchdog = Some((interval, now + interval));
+            },
+            _ => (),
+        }
+    }
+}
+
+/// What the watchdog goes by: the control loop finishing its ticks, and the temperature being read
+pub struct Health {
+    tick_period: Duration,
+    reading_period: Duration,
+    last_tick: Instant,
+    last_reading: Instant,
+}
+
+impl Health {
+    pub fn new(tick_period: Duration, reading_period: Duration) -> Health {
+        let now = Instant::now();
+        Health { tick_period, reading_period, last_tick: now, last_reading: now }
+    }
+
+    /// The controller has run, and the status has been reported
+    pub fn ticked(&mut self) {
+        self.last_tick = Instant::now();
+    }
+
+    pub fn read(&mut self) {
+        self.last_reading = Instant::now();
+    }
+
+    /// What is wrong, if anything
+    pub fn problem(&self) -> Option<&'static str> {
+        if self.last_tick.elapsed() >= self.tick_period * MISSED_PERIODS {
+            Some("the controller hasn't run in a while")
+        } else if self.last_reading.elapsed() >= self.reading_period * MISSED_PERIODS {
+            Some("the temperature hasn't been read in a while")
+        } else {
+            None
+        }
+    }
+}
+
+/// The one-line status systemctl shows, e.g. "72.5F, Cool with fan, mode auto 68-76F"
+pub fn status_line(status: &StatusReport) -> String {
+    let temp = status.temp.map_or("no temperature".to_string(), |temp| format!("{:.1}F", temp.value()));
+    let setpoints = match (status.min_temp, status.max_temp) {
+        (Some(min), Some(max)) => format!(" {:.0}-{:.0}F", min.value(), max.value()),
+        _ => String::new(),
+    };
+    let fault = if status.fault.is_some() { ", output fault" } else { "" };
+    let fan = if status.fan { " with fan" } else { "" };
+    format!("{}, {:?}{}, mode {}{}{}", temp, status.compressor_mode, fan, status.mode, setpoints, fault)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::uom::temp::*;
+    use tempdir::TempDir;
+
+    fn listen() -> (TempDir, UnixDatagram, String) {
+        let dir = TempDir::new("notify").unwrap();
+        let path = dir.path().join("notify.sock");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        (dir, socket, path.to_str().unwrap().to_string())
+    }
+
+    fn receive(socket: &UnixDatagram) -> String {
+        let mut buffer = [0; 256];
+        let length = socket.recv(&mut buffer).unwrap();
+        String::from_utf8(buffer[..length].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn sends_ready_and_changed_status() {
+        let (_dir, socket, path) = listen();
+        let mut notifier = Notifier::connect(&path, None).unwrap();
+        let mut status = StatusReport::new();
+        status.temp = Some(Temperature::in_f(72.3));
+
+        notifier.ready(&status);
+        assert_eq!(receive(&socket), "READY=1\nSTATUS=72.3F, Off, mode auto");
+
+        notifier.status(&status);
+        status.fan = true;
+        notifier.status(&status);
+        assert_eq!(receive(&socket), "STATUS=72.3F, Off with fan, mode auto");
+    }
+
+    #[test]
+    fn pings_the_watchdog_only_while_healthy() {
+        let (_dir, socket, path) = listen();
+        let mut notifier = Notifier::connect(&path, Some(Duration::from_millis(20))).unwrap();
+        let healthy = Health::new(Duration::from_secs(60), Duration::from_secs(60));
+        let stuck = Health::new(Duration::ZERO, Duration::from_secs(60));
+
+        notifier.watchdog(&healthy);
+        assert!(notifier.watchdog_due().unwrap() > Instant::now());
+
+        ::std::thread::sleep(Duration::from_millis(10));
+        notifier.watchdog(&stuck);
+        ::std::thread::sleep(Duration::from_millis(10));
+        notifier.watchdog(&healthy);
+        assert_eq!(receive(&socket), "WATCHDOG=1");
+
+        socket.set_nonblocking(true).unwrap();
+        assert!(socket.recv(&mut [0; 16]).is_err());
+    }
+
+    #[test]
+    fn needs_the_controller_to_tick_as_well_as_readings() {
+        let mut health = Health::new(Duration::from_millis(10), Duration::from_secs(60));
+        assert_eq!(health.problem(), None);
+
+        ::std::thread::sleep(Duration::from_millis(40));
+        health.read();
+        assert_eq!(health.problem(), Some("the controller hasn't run in a while"));
+
+        health.ticked();
+        assert_eq!(health.problem(), None);
+
+        let health = Health::new(Duration::from_secs(60), Duration::ZERO);
+        assert_eq!(health.problem(), Some("the temperature hasn't been read in a while"));
+    }
+}
@@ -0,0 +1,125 @@
+//! Where the thermostat finds its settings and keeps its state
+
+use std::env;
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Only the thermostat (and its group) has any business reading the state directory
+const STATE_DIR_MODE: u32 = 0o750;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Paths {
+    /// The installation's settings.json, which the thermostat only reads
+    pub settings: PathBuf,
+    /// Everything the thermostat writes: the user's config, runtime counters, history and holds
+    pub state_dir: PathBuf,
+}
+
+fn running_as_root() -> bool {
+    fs::metadata("/proc/self").map(|proc_self| proc_self.uid() == 0).unwrap_or(false)
+}
+
+impl Paths {
+    /// /etc/thermostat and /var/lib/thermostat for a system service, otherwise the XDG config and
+    /// state directories
+    pub fn default_for_user() -> Paths {
+        Paths::defaults(running_as_root(), |var| env::var(var).ok().filter(|value| !value.is_empty()))
+    }
+
+    fn defaults<E>(root: bool, env: E) -> Paths where E: Fn(&str) -> Option<String> {
+        if root {
+            return Paths {
+                settings: PathBuf::from("/etc/thermostat/settings.json"),
+                state_dir: PathBuf::from("/var/lib/thermostat"),
+            };
+        }
+
+        let home = env("HOME").map_or_else(|| PathBuf::from("."), PathBuf::from);
+        let config_home = env("XDG_CONFIG_HOME").map_or_else(|| home.join(".config"), PathBuf::from);
+        let state_home = env("XDG_STATE_HOME").map_or_else(|| home.join(".local/state"), PathBuf::from);
+
+        Paths {
+            settings: config_home.join("thermostat/settings.json"),
+            state_dir: state_home.join("thermostat"),
+        }
+    }
+
+    /// The user's config, as last set from the web UI
+    pub fn config(&self) -> PathBuf {
+        self.state_dir.join("config.json")
+    }
+
+    pub fn runtime(&self) -> PathBuf {
+        self.state_dir.join("runtime.json")
+    }
+
+    /// What the running thermostat is doing, for `thermostat status`
+    pub fn status(&self) -> PathBuf {
+        self.state_dir.join("status.json")
+    }
+
+    /// Every decision the thermostat made, see `events`. Older ones are in events.jsonl.1 and on.
+    pub fn events(&self) -> PathBuf {
+        self.state_dir.join("events.jsonl")
+    }
+
+    /// Every reading for the last week and 5 minute averages for the last year, see `history`
+    pub fn history(&self) -> PathBuf {
+        self.state_dir.join("history")
+    }
+
+    /// The control socket, see `socket`
+    pub fn socket(&self) -> PathBuf {
+        self.state_dir.join("thermostat.sock")
+    }
+
+    /// Creates the state directory if needed, and makes sure other users can't read it
+    pub fn create_state_dir(&self) -> io::Result<()> {
+        create_private_dir(&self.state_dir)
+    }
+}
+
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    DirBuilder::new().recursive(true).mode(STATE_DIR_MODE).create(dir)?;
+    // an existing directory keeps whatever mode it had
+    fs::set_permissions(dir, fs::Permissions::from_mode(STATE_DIR_MODE))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn system_service_uses_etc_and_var_lib() {
+        let paths = Paths::defaults(true, |_| None);
+
+        assert_eq!(paths.settings, PathBuf::from("/etc/thermostat/settings.json"));
+        assert_eq!(paths.config(), PathBuf::from("/var/lib/thermostat/config.json"));
+    }
+
+    #[test]
+    fn users_get_the_xdg_directories() {
+        let paths = Paths::defaults(false, |var| match var {
+            "HOME" => Some("/home/jon".to_string()),
+            "XDG_STATE_HOME" => Some("/tmp/state".to_string()),
+            _ => None,
+        });
+
+        assert_eq!(paths.settings, PathBuf::from("/home/jon/.config/thermostat/settings.json"));
+        assert_eq!(paths.runtime(), PathBuf::from("/tmp/state/thermostat/runtime.json"));
+    }
+
+    #[test]
+    fn creates_the_state_dir_privately() {
+        let dir = TempDir::new("paths").unwrap();
+        let paths = Paths { settings: dir.path().join("settings.json"), state_dir: dir.path().join("var/lib/thermostat") };
+
+        paths.create_state_dir().unwrap();
+
+        let mode = fs::metadata(&paths.state_dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, STATE_DIR_MODE);
+    }
+}
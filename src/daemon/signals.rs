@@ -0,0 +1,44 @@
+//! Turning SIGTERM (from systemd) and SIGINT (Ctrl-C) into a `Command::Shutdown`, so the control
+//! loop gets to leave the outputs in a safe state
+
+use libc;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::mpsc::Sender;
+use std::thread;
+
+use super::Command;
+
+/// Blocks the signals in this thread, and in every thread it starts from now on, and has a thread
+/// of its own wait for them instead. Call it before starting any other thread, or that one could
+/// still be killed by them.
+pub fn forward(commands: Sender<Command>) -> io::Result<()> {
+    let signals = unsafe {
+        let mut signals: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        match libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut()) {
+            0 => signals,
+            e => return Err(io::Error::from_raw_os_error(e)),
+        }
+    };
+
+    thread::spawn(move || loop {
+        let mut signal = 0;
+        match unsafe { libc::sigwait(&signals, &mut signal) } {
+            0 => {
+                info!("Got signal {}, shutting down", signal);
+                if commands.send(Command::Shutdown).is_err() {
+                    return;
+                }
+            },
+            e => {
+                error!("Waiting for signals failed: {}", io::Error::from_raw_os_error(e));
+                return;
+            },
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,478 @@
+//! The control socket: a Unix domain socket in the state directory, for querying and commanding
+//! the running thermostat without going through the web UI.
+//!
+//! Each request is one line of JSON, and gets one line of JSON back:
+//!
+//! ```text
+//! {"command":"status"}
+//! {"command":"set","maxTempF":78,"minTempF":70,"mode":"cool"}
+//! {"command":"hold","maxTempF":76,"hours":2}        or "until":"2016-07-01T18:00:00-05:00"
+//! {"command":"cancelHold"}
+//! {"command":"fan","minutes":30}
+//! {"command":"cancelFan"}
+//! {"command":"history","minutes":60}
+//! {"command":"history","from":"2016-07-01T00:00:00Z","to":"2016-07-02T00:00:00Z","resolutionMinutes":60}
+//! ```
+//!
+//! `history` answers with the samples, or with averages when given a resolution; see `history`.
+//!
+//! Answers are `{"ok":true, ...}` with `status`, `config` or `history` as asked, or
+//! `{"ok":false,"error":"..."}`.
+
+use ::controller::config_file::{ConfigFile, MAX_DURATION_DAYS};
+use ::history::Query;
+use ::status::StatusReport;
+use chrono::*;
+use rustc_serialize::json::{Json, ToJson};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use super::Command;
+use super::cli::{self, Changes, Hold};
+
+/// The thermostat's group may control it too
+const SOCKET_MODE: u32 = 0o660;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    Status,
+    Set(Changes),
+    Hold(Changes, Hold),
+    /// Runs the fan for this long, or stops the fan timer for None
+    Fan(Option<Duration>),
+    History(Query),
+}
+
+/// What the control loop answers a request with: the fields to send back, or what went wrong
+pub type Answer = Result<BTreeMap<String, Json>, String>;
+
+fn int(json: &Json, key: &str) -> Result<Option<i32>, String> {
+    match json.find(key) {
+        None | Some(&Json::Null) => Ok(None),
+        Some(value) => value.as_i64().and_then(|i| i32::try_from(i).ok()).map(Some).ok_or(format!("invalid {}", key)),
+    }
+}
+
+fn minutes(json: &Json, key: &str, scale: f64) -> Result<Option<Duration>, String> {
+    match json.find(key) {
+        None => Ok(None),
+        Some(value) => match value.as_f64() {
+            Some(value) => cli::minutes(value * scale, key).map(Some),
+            None => Err(format!("invalid {}", key)),
+        },
+    }
+}
+
+fn time(json: &Json, key: &str) -> Result<Option<DateTime<UTC>>, String> {
+    match json.find(key).and_then(|t| t.as_string()) {
+        None => Ok(None),
+        Some(time) => DateTime::parse_from_rfc3339(time)
+            .map(|time| Some(time.with_timezone(&UTC)))
+            .map_err(|e| format!("invalid {} {}: {}", key, time, e)),
+    }
+}
+
+impl Request {
+    pub fn from_json(json: &Json) -> Result<Request, String> {
+        let changes = || -> Result<Changes, String> {
+            Ok(Changes {
+                max_temp_f: int(json, "maxTempF")?,
+                min_temp_f: int(json, "minTempF")?,
+                mode: match json.find("mode").and_then(|m| m.as_string()) {
+                    Some(mode) => Some(mode.parse()?),
+                    None => None,
+                },
+                fan_duration_hours: int(json, "fanDurationHours")?,
+            })
+        };
+
+        match json.find("command").and_then(|c| c.as_string()) {
+            Some("status") => Ok(Request::Status),
+            Some("set") => Ok(Request::Set(changes()?)),
+            Some("hold") => {
+                let changes = changes()?;
+                if changes.mode.is_some() || changes.fan_duration_hours.is_some() {
+                    return Err("hold only takes maxTempF and minTempF".to_string());
+                }
+                let hold = match (minutes(json, "hours", 60.0)?, json.find("until").and_then(|u| u.as_string())) {
+                    (Some(duration), None) => Hold::For(duration),
+                    (None, Some(until)) => Hold::Until(DateTime::parse_from_rfc3339(until)
+                        .map_err(|e| format!("invalid until {}: {}", until, e))?
+                        .with_timezone(&UTC)),
+                    _ => return Err("hold needs either hours or until".to_string()),
+                };
+                Ok(Request::Hold(changes, hold))
+            },
+            Some("cancelHold") => Ok(Request::Hold(Changes::default(), Hold::Cancel)),
+            Some("fan") => minutes(json, "minutes", 1.0)?
+                .map(|duration| Request::Fan(Some(duration)))
+                .ok_or("fan needs minutes".to_string()),
+            Some("cancelFan") => Ok(Request::Fan(None)),
+            Some("history") => Ok(Request::History(Query {
+                start: time(json, "from")?,
+                end: time(json, "to")?,
+                last: minutes(json, "minutes", 1.0)?.unwrap_or_else(|| Duration::hours(1)),
+                resolution: minutes(json, "resolutionMinutes", 1.0)?.filter(|resolution| *resolution > Duration::zero()),
+            })),
+            Some(command) => Err(format!("unknown command {}", command)),
+            None => Err("missing command".to_string()),
+        }
+    }
+}
+
+impl ToJson for Request {
+    fn to_json(&self) -> Json {
+        let mut json = BTreeMap::new();
+        let changes = |changes: &Changes| {
+            let mut json = BTreeMap::new();
+            if let Some(max_temp_f) = changes.max_temp_f {
+                json.insert("maxTempF".to_string(), max_temp_f.to_json());
+            }
+            if let Some(min_temp_f) = changes.min_temp_f {
+                json.insert("minTempF".to_string(), min_temp_f.to_json());
+            }
+            if let Some(mode) = changes.mode {
+                json.insert("mode".to_string(), mode.to_string().to_json());
+            }
+            if let Some(hours) = changes.fan_duration_hours {
+                json.insert("fanDurationHours".to_string(), hours.to_json());
+            }
+            json
+        };
+        let command = match *self {
+            Request::Status => "status",
+            Request::Set(ref set) => {
+                json = changes(set);
+                "set"
+            },
+            Request::Hold(_, Hold::Cancel) => "cancelHold",
+            Request::Hold(ref set, ref hold) => {
+                json = changes(set);
+                match *hold {
+                    Hold::For(duration) => json.insert("hours".to_string(), (duration.num_minutes() as f64 / 60.0).to_json()),
+                    Hold::Until(until) => json.insert("until".to_string(), until.to_rfc3339().to_json()),
+                    Hold::Cancel => None,
+                };
+                "hold"
+            },
+            Request::Fan(Some(duration)) => {
+                json.insert("minutes".to_string(), duration.num_minutes().to_json());
+                "fan"
+            },
+            Request::Fan(None) => "cancelFan",
+            Request::History(ref query) => {
+                json.insert("minutes".to_string(), query.last.num_minutes().to_json());
+                if let Some(start) = query.start {
+                    json.insert("from".to_string(), start.to_rfc3339().to_json());
+                }
+                if let Some(end) = query.end {
+                    json.insert("to".to_string(), end.to_rfc3339().to_json());
+                }
+                if let Some(resolution) = query.resolution {
+                    json.insert("resolutionMinutes".to_string(), resolution.num_minutes().to_json());
+                }
+                "history"
+            },
+        };
+        json.insert("command".to_string(), command.to_json());
+        Json::Object(json)
+    }
+}
+
+fn answer_json(answer: Answer) -> Json {
+    let mut json = match answer {
+        Ok(fields) => fields,
+        Err(e) => {
+            let mut json = BTreeMap::new();
+            json.insert("error".to_string(), e.to_json());
+            json
+        },
+    };
+    let ok = !json.contains_key("error");
+    json.insert("ok".to_string(), ok.to_json());
+    Json::Object(json)
+}
+
+/// Answers the status itself, and passes everything else on to the control loop
+fn answer(line: &str, commands: &Sender<Command>, status: &RwLock<StatusReport>) -> Answer {
+    let request = Json::from_str(line).map_err(|e| e.to_string()).and_then(|json| Request::from_json(&json))?;
+    if request == Request::Status {
+        let mut fields = BTreeMap::new();
+        fields.insert("status".to_string(), status.read().unwrap().to_json());
+        return Ok(fields);
+    }
+
+    let (reply, answer) = channel();
+    commands.send(Command::Request(request, reply)).map_err(|_| "the thermostat is shutting down".to_string())?;
+    answer.recv().map_err(|_| "the thermostat is shutting down".to_string())?
+}
+
+fn serve(stream: UnixStream, commands: Sender<Command>, status: Arc<RwLock<StatusReport>>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        writeln!(writer, "{}", answer_json(answer(&line, &commands, &status)))?;
+    }
+    Ok(())
+}
+
+/// Starts listening on the socket at `path`, replacing a socket left behind by an earlier run
+pub fn listen(path: &Path, commands: Sender<Command>, status: Arc<RwLock<StatusReport>>) -> io::Result<()> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("another thermostat is listening on {}", path.display())));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(SOCKET_MODE))?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let (commands, status) = (commands.clone(), status.clone());
+                    thread::spawn(move || if let Err(e) = serve(stream, commands, status) {
+                        warn!("Control socket client went away: {}", e);
+                    });
+                },
+                Err(e) => warn!("Could not accept a control socket connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Sends one request to the running thermostat and waits for the answer. Fails with NotFound or
+/// ConnectionRefused when the thermostat isn't running.
+pub fn send(path: &Path, request: &Json) -> io::Result<Json> {
+    let stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(::std::time::Duration::from_secs(30)))?;
+    writeln!(&stream, "{}", request)?;
+
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    Json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+/// The answer's fields, or its error
+pub fn unwrap_answer(json: Json) -> Answer {
+    match json {
+        Json::Object(mut fields) => match fields.remove("ok") {
+            Some(Json::Boolean(true)) => Ok(fields),
+            _ => Err(fields.get("error").and_then(|e| e.as_string()).unwrap_or("invalid answer").to_string()),
+        },
+        _ => Err("invalid answer".to_string()),
+    }
+}
+
+/// Applies a change request to the config, checking that the result makes sense
+pub fn change_config(request: &Request, config_file: &mut ConfigFile, now: DateTime<UTC>) -> Result<(), String> {
+    let mut changed = config_file.clone();
+    match *request {
+        Request::Set(ref changes) => {
+            changes.apply(&mut changed);
+            // or the new setpoints would only be in effect once the hold is over
+            if changed.is_held(now) {
+                changed.hold_max_temp_f = changes.max_temp_f.or(changed.hold_max_temp_f);
+                changed.hold_min_temp_f = changes.min_temp_f.or(changed.hold_min_temp_f);
+            }
+        },
+        Request::Hold(ref changes, ref hold) => {
+            // the setpoints themselves stay as they are, for when the hold is over
+            let (max_temp_f, min_temp_f) = if changed.is_held(now) {
+                changed.hold_setpoints()
+            } else {
+                (changed.max_temp_f, changed.min_temp_f)
+            };
+            changed.hold_until = match *hold {
+                Hold::For(duration) => Some(now.checked_add(duration).ok_or("the hold is too long".to_string())?),
+                Hold::Until(until) if until <= now => return Err(format!("the hold would be over already at {}", until.to_rfc3339())),
+                Hold::Until(until) if until - now > Duration::days(MAX_DURATION_DAYS) =>
+                    return Err(format!("the hold until {} is more than {} days", until.to_rfc3339(), MAX_DURATION_DAYS)),
+                Hold::Until(until) => Some(until),
+                Hold::Cancel => None,
+            };
+            if changed.hold_until.is_some() {
+                changed.hold_max_temp_f = Some(changes.max_temp_f.unwrap_or(max_temp_f));
+                changed.hold_min_temp_f = Some(changes.min_temp_f.unwrap_or(min_temp_f));
+            } else {
+                changed.hold_max_temp_f = None;
+                changed.hold_min_temp_f = None;
+            }
+        },
+        _ => return Err("not a config change".to_string()),
+    }
+
+    if let Some(problem) = changed.problems().first() {
+        return Err(problem.clone());
+    }
+    *config_file = changed;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::controller::config::SystemMode;
+    use tempdir::TempDir;
+
+    fn request(s: &str) -> Result<Request, String> {
+        Request::from_json(&Json::from_str(s).unwrap())
+    }
+
+    #[test]
+    fn parses_the_documented_requests() {
+        assert_eq!(request(r#"{"command":"status"}"#), Ok(Request::Status));
+        assert_eq!(request(r#"{"command":"set","maxTempF":78,"minTempF":70,"mode":"cool"}"#), Ok(Request::Set(Changes {
+            max_temp_f: Some(78), min_temp_f: Some(70), mode: Some(SystemMode::Cool), fan_duration_hours: None,
+        })));
+        assert_eq!(request(r#"{"command":"hold","maxTempF":76,"hours":2}"#),
+                   Ok(Request::Hold(Changes { max_temp_f: Some(76), ..Changes::default() }, Hold::For(Duration::hours(2)))));
+        assert_eq!(request(r#"{"command":"hold","until":"2016-07-01T18:00:00-05:00"}"#),
+                   Ok(Request::Hold(Changes::default(), Hold::Until(UTC.ymd(2016, 7, 1).and_hms(23, 0, 0)))));
+        assert_eq!(request(r#"{"command":"cancelHold"}"#), Ok(Request::Hold(Changes::default(), Hold::Cancel)));
+        assert_eq!(request(r#"{"command":"fan","minutes":30}"#), Ok(Request::Fan(Some(Duration::minutes(30)))));
+        assert_eq!(request(r#"{"command":"cancelFan"}"#), Ok(Request::Fan(None)));
+        assert_eq!(request(r#"{"command":"history","minutes":60}"#), Ok(Request::History(Query::last(Duration::hours(1)))));
+        assert_eq!(request(r#"{"command":"history","from":"2016-07-01T00:00:00Z","to":"2016-07-02T00:00:00Z","resolutionMinutes":60}"#),
+                   Ok(Request::History(Query {
+                       start: Some(UTC.ymd(2016, 7, 1).and_hms(0, 0, 0)),
+                       end: Some(UTC.ymd(2016, 7, 2).and_hms(0, 0, 0)),
+                       last: Duration::hours(1),
+                       resolution: Some(Duration::hours(1)),
+                   })));
+
+        assert!(request(r#"{"command":"set","maxTempF":"hot"}"#).is_err());
+        assert!(request(r#"{"command":"set","maxTempF":4294967372}"#).is_err());
+        assert!(request(r#"{"command":"hold","maxTempF":76}"#).is_err());
+        assert!(request(r#"{"command":"fan","minutes":-5}"#).is_err());
+        assert!(request(r#"{"command":"history","from":"yesterday"}"#).is_err());
+        assert!(request(r#"{"command":"reboot"}"#).is_err());
+    }
+
+    #[test]
+    fn requests_survive_the_round_trip() {
+        for request in &[
+            Request::Status,
+            Request::Set(Changes { mode: Some(SystemMode::Heat), fan_duration_hours: Some(1), ..Changes::default() }),
+            Request::Hold(Changes { min_temp_f: Some(68), ..Changes::default() }, Hold::For(Duration::minutes(90))),
+            Request::Hold(Changes::default(), Hold::Until(UTC.ymd(2016, 7, 1).and_hms(23, 0, 0))),
+            Request::Hold(Changes::default(), Hold::Cancel),
+            Request::Fan(Some(Duration::minutes(15))),
+            Request::Fan(None),
+            Request::History(Query::last(Duration::minutes(30))),
+            Request::History(Query {
+                start: Some(UTC.ymd(2016, 7, 1).and_hms(0, 0, 0)),
+                end: None,
+                last: Duration::hours(1),
+                resolution: Some(Duration::minutes(5)),
+            }),
+        ] {
+            assert_eq!(Request::from_json(&request.to_json()).as_ref(), Ok(request));
+        }
+    }
+
+    #[test]
+    fn checks_config_changes() {
+        let now = UTC.ymd(2016, 7, 1).and_hms(12, 0, 0);
+        let mut config_file = ConfigFile::new();
+
+        change_config(&request(r#"{"command":"hold","minTempF":72,"hours":1}"#).unwrap(), &mut config_file, now).unwrap();
+        assert_eq!(config_file.hold_setpoints(), (79, 72));
+        assert_eq!(config_file.hold_until, Some(now + Duration::hours(1)));
+
+        assert!(change_config(&request(r#"{"command":"set","minTempF":90}"#).unwrap(), &mut config_file, now).is_err());
+        assert_eq!(config_file.hold_setpoints(), (79, 72));
+        assert!(change_config(&request(r#"{"command":"set","fanDurationHours":2000000000}"#).unwrap(), &mut config_file, now).is_err());
+        assert_eq!(config_file.fan_duration_hours, 0);
+    }
+
+    #[test]
+    fn restores_the_setpoints_after_a_hold() {
+        let now = UTC.ymd(2016, 7, 1).and_hms(12, 0, 0);
+        let mut config_file = ConfigFile::new();
+
+        change_config(&request(r#"{"command":"hold","maxTempF":74,"minTempF":72,"hours":1}"#).unwrap(), &mut config_file, now).unwrap();
+        // a longer hold keeps the held setpoints it isn't given
+        change_config(&request(r#"{"command":"hold","maxTempF":75,"hours":2}"#).unwrap(), &mut config_file, now).unwrap();
+        assert_eq!(config_file.hold_setpoints(), (75, 72));
+        assert_eq!((config_file.max_temp_f, config_file.min_temp_f), (79, 70));
+
+        // setting them during the hold takes effect right away
+        change_config(&request(r#"{"command":"set","maxTempF":76}"#).unwrap(), &mut config_file, now).unwrap();
+        assert_eq!(config_file.hold_setpoints(), (76, 72));
+
+        change_config(&request(r#"{"command":"cancelHold"}"#).unwrap(), &mut config_file, now).unwrap();
+        assert_eq!(config_file.hold_setpoints(), (76, 70));
+        assert_eq!(config_file.hold_until, None);
+    }
+
+    #[test]
+    fn only_holds_until_a_time_in_the_next_year() {
+        let now = UTC.ymd(2016, 7, 1).and_hms(12, 0, 0);
+        let mut config_file = ConfigFile::new();
+        let hold = |until: &str| request(&format!(r#"{{"command":"hold","until":"{}"}}"#, until)).unwrap();
+
+        assert!(change_config(&hold("2016-07-01T11:00:00Z"), &mut config_file, now).is_err());
+        assert!(change_config(&hold("9999-01-01T00:00:00Z"), &mut config_file, now).is_err());
+        assert_eq!(config_file.hold_until, None);
+        change_config(&hold("2016-07-02T12:00:00Z"), &mut config_file, now).unwrap();
+        assert_eq!(config_file.hold_until, Some(now + Duration::days(1)));
+    }
+
+    #[test]
+    fn rejects_durations_that_dont_fit() {
+        assert!(request(r#"{"command":"fan","minutes":1e300}"#).is_err());
+        assert!(request(r#"{"command":"hold","hours":1e12}"#).is_err());
+        assert!(request(r#"{"command":"history","minutes":1e12}"#).is_err());
+        assert!(request(r#"{"command":"history","resolutionMinutes":-1}"#).is_err());
+        assert_eq!(request(r#"{"command":"fan","minutes":1440}"#), Ok(Request::Fan(Some(Duration::days(1)))));
+    }
+
+    #[test]
+    fn answers_over_the_socket() {
+        let dir = TempDir::new("socket").unwrap();
+        let path = dir.path().join("thermostat.sock");
+        let (tx, commands) = channel();
+        let status = Arc::new(RwLock::new(StatusReport::new()));
+        listen(&path, tx, status).unwrap();
+
+        // stands in for the control loop
+        thread::spawn(move || {
+            for command in commands.iter() {
+                if let Command::Request(request, reply) = command {
+                    let mut fields = BTreeMap::new();
+                    fields.insert("echo".to_string(), request.to_json());
+                    reply.send(Ok(fields)).unwrap();
+                }
+            }
+        });
+
+        let answer = unwrap_answer(send(&path, &Request::Status.to_json()).unwrap()).unwrap();
+        assert_eq!(answer["status"].find("mode"), Some(&"auto".to_json()));
+
+        let fan = Request::Fan(Some(Duration::minutes(5)));
+        let answer = unwrap_answer(send(&path, &fan.to_json()).unwrap()).unwrap();
+        assert_eq!(Request::from_json(&answer["echo"]), Ok(fan));
+
+        let answer = unwrap_answer(send(&path, &Json::from_str(r#"{"command":"reboot"}"#).unwrap()).unwrap());
+        assert_eq!(answer, Err("unknown command reboot".to_string()));
+
+        // a second thermostat can't take the socket over
+        assert_eq!(listen(&path, channel().0, Arc::new(RwLock::new(StatusReport::new()))).unwrap_err().kind(), io::ErrorKind::AddrInUse);
+    }
+}
@@ -0,0 +1,276 @@
+//! A record of every decision the thermostat makes, written to events.jsonl one JSON object per
+//! line, so it can be worked out afterwards why it did or didn't cool or heat
+
+use ::ac_control::compressor::CompressorMode;
+use ::controller::Status;
+use ::uom::temp::*;
+use chrono::*;
+use rustc_serialize::json::{Json, ToJson};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventKind {
+    /// What the temperature calls for changed
+    StatusChanged { status: Status, temp: Temperature<F> },
+    CompressorRequested { mode: CompressorMode },
+    CompressorApplied { from: CompressorMode, to: CompressorMode },
+    /// A requested mode can't be applied yet, or at all
+    CompressorBlocked { mode: CompressorMode, reason: String },
+    /// `timed` when the fan only runs for the pre-run or overrun
+    FanChanged { on: bool, timed: bool },
+    /// Each setting that changed, with its old and new value
+    ConfigChanged { source: String, changes: BTreeMap<String, (Json, Json)> },
+    /// The schedule leg (by its position in the schedule) that took over, or None when it ended
+    ScheduleLeg { leg: Option<usize> },
+    HoldStarted { until: DateTime<UTC> },
+    HoldEnded,
+    SensorFault { sensor: String, reason: String },
+    SensorRecovered { sensor: String },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub time: DateTime<UTC>,
+    pub kind: EventKind,
+}
+
+impl Event {
+    pub fn new(time: DateTime<UTC>, kind: EventKind) -> Event {
+        Event { time, kind }
+    }
+}
+
+fn mode(mode: CompressorMode) -> Json {
+    format!("{:?}", mode).to_json()
+}
+
+impl ToJson for Event {
+    fn to_json(&self) -> Json {
+        use self::EventKind::*;
+
+        let mut json = BTreeMap::new();
+        let mut field = |name: &str, value: Json| { json.insert(name.to_string(), value); };
+        field("time", self.time.to_rfc3339().to_json());
+        let event = match self.kind {
+            StatusChanged { status, temp } => {
+                field("status", format!("{:?}", status).to_json());
+                field("currentTempF", temp.value().to_json());
+                "statusChanged"
+            },
+            CompressorRequested { mode: requested } => {
+                field("mode", mode(requested));
+                "compressorRequested"
+            },
+            CompressorApplied { from, to } => {
+                field("from", mode(from));
+                field("to", mode(to));
+                "compressorApplied"
+            },
+            CompressorBlocked { mode: blocked, ref reason } => {
+                field("mode", mode(blocked));
+                field("reason", reason.to_json());
+                "compressorBlocked"
+            },
+            FanChanged { on, timed } => {
+                field("on", on.to_json());
+                field("timed", timed.to_json());
+                "fanChanged"
+            },
+            ConfigChanged { ref source, ref changes } => {
+                field("source", source.to_json());
+                field("changes", Json::Object(changes.iter().map(|(name, (from, to))| {
+                    let mut change = BTreeMap::new();
+                    change.insert("from".to_string(), from.clone());
+                    change.insert("to".to_string(), to.clone());
+                    (name.clone(), Json::Object(change))
+                }).collect()));
+                "configChanged"
+            },
+            ScheduleLeg { leg } => {
+                field("leg", leg.map_or(Json::Null, |leg| leg.to_json()));
+                "scheduleLeg"
+            },
+            HoldStarted { until } => {
+                field("until", until.to_rfc3339().to_json());
+                "holdStarted"
+            },
+            HoldEnded => "holdEnded",
+            SensorFault { ref sensor, ref reason } => {
+                field("sensor", sensor.to_json());
+                field("reason", reason.to_json());
+                "sensorFault"
+            },
+            SensorRecovered { ref sensor } => {
+                field("sensor", sensor.to_json());
+                "sensorRecovered"
+            },
+        };
+        field("event", event.to_json());
+        Json::Object(json)
+    }
+}
+
+/// The top level fields that differ between two JSON objects, e.g. two versions of config.json
+pub fn diff(before: &Json, after: &Json) -> BTreeMap<String, (Json, Json)> {
+    let empty = BTreeMap::new();
+    let before = before.as_object().unwrap_or(&empty);
+    let after = after.as_object().unwrap_or(&empty);
+
+    before.keys().chain(after.keys()).collect::<BTreeSet<_>>().into_iter()
+        .filter_map(|name| {
+            let from = before.get(name).cloned().unwrap_or(Json::Null);
+            let to = after.get(name).cloned().unwrap_or(Json::Null);
+            if from != to { Some((name.clone(), (from, to))) } else { None }
+        })
+        .collect()
+}
+
+/// Turns sensor readings into an event when a sensor starts failing, and when it works again
+#[derive(Default)]
+pub struct SensorFaults {
+    failing: BTreeSet<String>,
+}
+
+impl SensorFaults {
+    pub fn check(&mut self, time: DateTime<UTC>, sensor: &str, result: Result<(), String>) -> Option<Event> {
+        match result {
+            Err(reason) => if self.failing.insert(sensor.to_string()) {
+                return Some(Event::new(time, EventKind::SensorFault { sensor: sensor.to_string(), reason }));
+            },
+            Ok(()) => if self.failing.remove(sensor) {
+                return Some(Event::new(time, EventKind::SensorRecovered { sensor: sensor.to_string() }));
+            },
+        }
+        None
+    }
+}
+
+/// Appends events to a file, moving it to `<file>.1` once it is `max_bytes` long. The older files
+/// move along to `<file>.2` and so on, and the oldest past `keep` is deleted.
+pub struct EventLog {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: u32,
+    file: Option<File>,
+}
+
+impl EventLog {
+    pub fn new(path: PathBuf, max_bytes: u64, keep: u32) -> EventLog {
+        EventLog { path, max_bytes, keep, file: None }
+    }
+
+    pub fn write(&mut self, event: &Event) -> io::Result<()> {
+        let line = format!("{}\n", event.to_json());
+
+        let size = match self.file {
+            Some(ref file) => file.metadata()?.len(),
+            None => fs::metadata(&self.path).map(|metadata| metadata.len()).unwrap_or(0),
+        };
+        if size > 0 && size + line.len() as u64 > self.max_bytes {
+            self.file = None;
+            self.rotate()?;
+        }
+
+        if self.file.is_none() {
+            self.file = Some(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        }
+        self.file.as_mut().unwrap().write_all(line.as_bytes())
+    }
+
+    /// Writes the events, logging rather than returning any error
+    pub fn write_all(&mut self, events: Vec<Event>) {
+        for event in events {
+            if let Err(e) = self.write(&event) {
+                warn!("Could not write to {}: {}", self.path.display(), e);
+            }
+        }
+    }
+
+    fn rotated(&self, n: u32) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".{}", n));
+        self.path.with_file_name(name)
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        for n in (1..self.keep).rev() {
+            rename_if_there(&self.rotated(n), &self.rotated(n + 1))?;
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, self.rotated(1))
+        } else {
+            fs::remove_file(&self.path)
+        }
+    }
+}
+
+fn rename_if_there(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn at(second: u32) -> DateTime<UTC> {
+        UTC.ymd(2016, 7, 1).and_hms(12, 0, second)
+    }
+
+    #[test]
+    fn writes_the_event_and_its_fields() {
+        let event = Event::new(at(0), EventKind::CompressorBlocked { mode: CompressorMode::Cool, reason: "lockout".to_string() });
+
+        assert_eq!(event.to_json().to_string(),
+                   r#"{"event":"compressorBlocked","mode":"Cool","reason":"lockout","time":"2016-07-01T12:00:00+00:00"}"#);
+    }
+
+    #[test]
+    fn diffs_the_top_level_fields() {
+        let before = Json::from_str(r#"{"maxTempF":78,"minTempF":70,"mode":"auto"}"#).unwrap();
+        let after = Json::from_str(r#"{"maxTempF":76,"minTempF":70,"holdUntil":null}"#).unwrap();
+
+        let changes = diff(&before, &after);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes["maxTempF"], (Json::U64(78), Json::U64(76)));
+        assert_eq!(changes["mode"], ("auto".to_json(), Json::Null));
+    }
+
+    #[test]
+    fn reports_a_sensor_fault_once() {
+        let mut faults = SensorFaults::default();
+
+        assert!(faults.check(at(0), "indoor", Ok(())).is_none());
+        assert!(faults.check(at(1), "indoor", Err("crc mismatch".to_string())).is_some());
+        assert!(faults.check(at(2), "indoor", Err("crc mismatch".to_string())).is_none());
+        assert!(faults.check(at(2), "outdoor", Ok(())).is_none());
+        assert_eq!(faults.check(at(3), "indoor", Ok(())),
+                   Some(Event::new(at(3), EventKind::SensorRecovered { sensor: "indoor".to_string() })));
+    }
+
+    #[test]
+    fn rotates_the_file() {
+        let dir = TempDir::new("events").unwrap();
+        let path = dir.path().join("events.jsonl");
+        let line_length = Event::new(at(0), EventKind::HoldEnded).to_json().to_string().len() as u64 + 1;
+        let mut log = EventLog::new(path.clone(), line_length * 2, 2);
+
+        for second in 0..7 {
+            log.write(&Event::new(at(second), EventKind::HoldEnded)).unwrap();
+        }
+
+        let lines = |path: &Path| fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(lines(&path), 1);
+        assert_eq!(lines(&dir.path().join("events.jsonl.1")), 2);
+        assert_eq!(lines(&dir.path().join("events.jsonl.2")), 2);
+        assert!(!dir.path().join("events.jsonl.3").exists());
+        assert!(fs::read_to_string(&path).unwrap().contains("12:00:06"));
+    }
+}
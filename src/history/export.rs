@@ -0,0 +1,91 @@
+//! The history as a file for a spreadsheet: one row per interval, with the averages and how much
+//! of the interval the compressor and fan ran
+
+use super::Summary;
+use rustc_serialize::json::ToJson;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Csv,
+    Json,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            Format::Csv => "csv",
+            Format::Json => "json",
+        })
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            other => Err(format!("unknown format {}, expected csv or json", other)),
+        }
+    }
+}
+
+const CSV_HEADER: &str = "start,end,samples,temp_f,humidity,outdoor_temp_f,min_temp_f,max_temp_f,mode,fan,cooling,heating";
+
+fn cell(value: Option<f32>) -> String {
+    value.map_or(String::new(), |value| format!("{:.2}", value))
+}
+
+/// Writes the summaries. The fan, cooling and heating columns are the fraction of the interval
+/// each was on, and the mode is the one at the end of the interval.
+pub fn write<W: Write>(format: Format, summaries: &[Summary], out: &mut W) -> io::Result<()> {
+    match format {
+        Format::Json => writeln!(out, "{}", summaries.to_json().pretty()),
+        Format::Csv => {
+            writeln!(out, "{}", CSV_HEADER)?;
+            for summary in summaries {
+                writeln!(out, "{},{},{},{},{},{},{},{},{},{:.3},{:.3},{:.3}",
+                         summary.start.to_rfc3339(), (summary.start + summary.length).to_rfc3339(), summary.samples,
+                         cell(summary.temp), cell(summary.humidity), cell(summary.outdoor_temp),
+                         cell(summary.min_temp), cell(summary.max_temp), summary.mode,
+                         summary.fan, summary.cooling, summary.heating)?;
+            }
+            Ok(())
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::controller::config::SystemMode;
+    use chrono::*;
+
+    #[test]
+    fn writes_a_row_per_interval() {
+        let summary = Summary {
+            start: UTC.ymd(2016, 7, 1).and_hms(12, 0, 0),
+            length: Duration::hours(1),
+            samples: 30,
+            temp: Some(74.25),
+            humidity: None,
+            outdoor_temp: Some(91.0),
+            min_temp: Some(70.0),
+            max_temp: Some(76.0),
+            cooling: 0.5,
+            heating: 0.0,
+            fan: 0.75,
+            mode: SystemMode::Cool,
+        };
+
+        let mut csv = vec![];
+        write(Format::Csv, &[summary], &mut csv).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap(), format!("{}\n{}\n", CSV_HEADER,
+                   "2016-07-01T12:00:00+00:00,2016-07-01T13:00:00+00:00,30,74.25,,91.00,70.00,76.00,cool,0.750,0.500,0.000"));
+    }
+}
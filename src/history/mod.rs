@@ -0,0 +1,315 @@
+//! What the thermostat measured and did, for looking back at later. The samples are kept on disk
+//! by `Store`: as they were taken for a week, and averaged over 5 minutes for a year.
+
+use ::uom::temp::*;
+use ::ac_control::compressor::CompressorMode;
+use ::controller::Status;
+use ::controller::config::SystemMode;
+use ::persist;
+use ::status::StatusReport;
+use chrono::*;
+use rustc_serialize::json::{Json, ToJson};
+use std::collections::BTreeMap;
+
+pub mod export;
+pub mod store;
+
+pub use self::store::Store;
+
+/// One reading of the control loop
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub time: DateTime<UTC>,
+    pub temp: Option<Temperature<F>>,
+    pub humidity: Option<f32>,
+    pub outdoor_temp: Option<Temperature<F>>,
+    pub min_temp: Option<Temperature<F>>,
+    pub max_temp: Option<Temperature<F>>,
+    pub mode: SystemMode,
+    pub status: Option<Status>,
+    pub compressor_mode: CompressorMode,
+    pub fan: bool,
+}
+
+/// Rounded, as the sensors aren't any more precise and f32s don't print well
+fn number(value: Option<f32>) -> Json {
+    value.map_or(Json::Null, |value| ((value as f64 * 100.0).round() / 100.0).to_json())
+}
+
+fn temp(json: &Json, key: &str) -> Option<Temperature<F>> {
+    json.find(key).and_then(|t| t.as_f64()).map(|t| Temperature::in_f(t as f32))
+}
+
+fn compressor_mode(name: &str) -> Option<CompressorMode> {
+    match name {
+        "Cool" => Some(CompressorMode::Cool),
+        "HeatPump" => Some(CompressorMode::HeatPump),
+        "Off" => Some(CompressorMode::Off),
+        _ => None,
+    }
+}
+
+fn status(name: &str) -> Option<Status> {
+    match name {
+        "TooHot" => Some(Status::TooHot),
+        "TooCold" => Some(Status::TooCold),
+        "JustRight" => Some(Status::JustRight),
+        "Hold" => Some(Status::Hold),
+        _ => None,
+    }
+}
+
+fn time(json: &Json, key: &str) -> Result<DateTime<UTC>, String> {
+    let time = json.find(key).and_then(|t| t.as_string()).ok_or(format!("missing {}", key))?;
+    DateTime::parse_from_rfc3339(time).map(|t| t.with_timezone(&UTC)).map_err(|e| format!("invalid {}: {}", key, e))
+}
+
+impl Sample {
+    pub fn from_status(time: DateTime<UTC>, status: &StatusReport) -> Sample {
+        Sample {
+            time,
+            temp: status.temp,
+            humidity: status.humidity,
+            outdoor_temp: status.outdoor_temp,
+            min_temp: status.min_temp,
+            max_temp: status.max_temp,
+            mode: status.mode,
+            status: status.controller_status,
+            compressor_mode: status.compressor_mode,
+            fan: status.fan,
+        }
+    }
+
+    pub fn from_json(json: &Json) -> Result<Sample, String> {
+        Ok(Sample {
+            time: time(json, "time")?,
+            temp: temp(json, "currentTempF"),
+            humidity: json.find("humidity").and_then(|h| h.as_f64()).map(|h| h as f32),
+            outdoor_temp: temp(json, "outdoorTempF"),
+            min_temp: temp(json, "minTempF"),
+            max_temp: temp(json, "maxTempF"),
+            mode: json.find("mode").and_then(|m| m.as_string()).and_then(|m| m.parse().ok()).unwrap_or(SystemMode::Auto),
+            status: json.find("controllerStatus").and_then(|s| s.as_string()).and_then(status),
+            compressor_mode: json.find("compressorMode").and_then(|m| m.as_string()).and_then(compressor_mode)
+                .ok_or("invalid compressorMode")?,
+            fan: json.find("fanOn").and_then(|f| f.as_boolean()).unwrap_or(false),
+        })
+    }
+}
+
+impl ToJson for Sample {
+    fn to_json(&self) -> Json {
+        let mut json = BTreeMap::new();
+        json.insert("time".to_string(), self.time.to_rfc3339().to_json());
+        json.insert("currentTempF".to_string(), number(self.temp.map(|t| t.value())));
+        json.insert("humidity".to_string(), number(self.humidity));
+        json.insert("outdoorTempF".to_string(), number(self.outdoor_temp.map(|t| t.value())));
+        json.insert("minTempF".to_string(), number(self.min_temp.map(|t| t.value())));
+        json.insert("maxTempF".to_string(), number(self.max_temp.map(|t| t.value())));
+        json.insert("mode".to_string(), self.mode.to_string().to_json());
+        json.insert("controllerStatus".to_string(), self.status.map_or(Json::Null, |s| format!("{:?}", s).to_json()));
+        json.insert("compressorMode".to_string(), format!("{:?}", self.compressor_mode).to_json());
+        json.insert("fanOn".to_string(), self.fan.to_json());
+        Json::Object(json)
+    }
+}
+
+/// A mean over whatever had a value, weighted by how many samples went into each value
+#[derive(Clone, Copy, Default)]
+struct Mean {
+    sum: f64,
+    weight: f64,
+}
+
+impl Mean {
+    fn add(&mut self, value: Option<f32>, weight: f64) {
+        if let Some(value) = value {
+            self.sum += value as f64 * weight;
+            self.weight += weight;
+        }
+    }
+
+    fn get(&self) -> Option<f32> {
+        if self.weight > 0.0 { Some((self.sum / self.weight) as f32) } else { None }
+    }
+}
+
+/// The samples of an interval, averaged. `cooling`, `heating` and `fan` are the fraction of
+/// samples that had them on, i.e. the duty cycle.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Summary {
+    pub start: DateTime<UTC>,
+    pub length: Duration,
+    pub samples: u32,
+    pub temp: Option<f32>,
+    pub humidity: Option<f32>,
+    pub outdoor_temp: Option<f32>,
+    pub min_temp: Option<f32>,
+    pub max_temp: Option<f32>,
+    pub cooling: f32,
+    pub heating: f32,
+    pub fan: f32,
+    /// The mode at the end of the interval
+    pub mode: SystemMode,
+}
+
+/// The start of the interval of `length` that `time` is in, counting from the epoch
+pub fn interval_start(time: DateTime<UTC>, length: Duration) -> DateTime<UTC> {
+    let seconds = time.timestamp();
+    UTC.timestamp(seconds - seconds.rem_euclid(length.num_seconds().max(1)), 0)
+}
+
+impl Summary {
+    pub fn of_samples(start: DateTime<UTC>, length: Duration, samples: &[&Sample]) -> Summary {
+        let parts: Vec<Summary> = samples.iter().map(|sample| Summary {
+            start: sample.time,
+            length: Duration::zero(),
+            samples: 1,
+            temp: sample.temp.map(|t| t.value()),
+            humidity: sample.humidity,
+            outdoor_temp: sample.outdoor_temp.map(|t| t.value()),
+            min_temp: sample.min_temp.map(|t| t.value()),
+            max_temp: sample.max_temp.map(|t| t.value()),
+            cooling: if sample.compressor_mode == CompressorMode::Cool { 1.0 } else { 0.0 },
+            heating: if sample.compressor_mode == CompressorMode::HeatPump { 1.0 } else { 0.0 },
+            fan: if sample.fan { 1.0 } else { 0.0 },
+            mode: sample.mode,
+        }).collect();
+        Summary::of_summaries(start, length, &parts.iter().collect::<Vec<_>>())
+    }
+
+    /// Combines shorter summaries, e.g. the 5 minute ones into hours
+    pub fn of_summaries(start: DateTime<UTC>, length: Duration, parts: &[&Summary]) -> Summary {
+        let mut means = [Mean::default(); 8];
+        for part in parts {
+            let weight = part.samples as f64;
+            let values = [part.temp, part.humidity, part.outdoor_temp, part.min_temp, part.max_temp,
+                          Some(part.cooling), Some(part.heating), Some(part.fan)];
+            for (mean, value) in means.iter_mut().zip(values.iter()) {
+                mean.add(*value, weight);
+            }
+        }
+
+        Summary {
+            start,
+            length,
+            samples: parts.iter().map(|part| part.samples).sum(),
+            temp: means[0].get(),
+            humidity: means[1].get(),
+            outdoor_temp: means[2].get(),
+            min_temp: means[3].get(),
+            max_temp: means[4].get(),
+            cooling: means[5].get().unwrap_or(0.0),
+            heating: means[6].get().unwrap_or(0.0),
+            fan: means[7].get().unwrap_or(0.0),
+            mode: parts.last().map_or(SystemMode::Auto, |part| part.mode),
+        }
+    }
+
+    pub fn from_json(json: &Json) -> Result<Summary, String> {
+        let value = |key| json.find(key).and_then(|v| v.as_f64()).map(|v| v as f32);
+        Ok(Summary {
+            start: time(json, "start")?,
+            length: json.find("seconds").and_then(|s| s.as_i64()).and_then(persist::seconds).ok_or("missing or invalid seconds")?,
+            samples: json.find("samples").and_then(|s| s.as_u64()).ok_or("missing samples")? as u32,
+            temp: value("currentTempF"),
+            humidity: value("humidity"),
+            outdoor_temp: value("outdoorTempF"),
+            min_temp: value("minTempF"),
+            max_temp: value("maxTempF"),
+            cooling: value("cooling").unwrap_or(0.0),
+            heating: value("heating").unwrap_or(0.0),
+            fan: value("fan").unwrap_or(0.0),
+            mode: json.find("mode").and_then(|m| m.as_string()).and_then(|m| m.parse().ok()).unwrap_or(SystemMode::Auto),
+        })
+    }
+}
+
+impl ToJson for Summary {
+    fn to_json(&self) -> Json {
+        let mut json = BTreeMap::new();
+        json.insert("start".to_string(), self.start.to_rfc3339().to_json());
+        json.insert("seconds".to_string(), self.length.num_seconds().to_json());
+        json.insert("samples".to_string(), self.samples.to_json());
+        json.insert("currentTempF".to_string(), number(self.temp));
+        json.insert("humidity".to_string(), number(self.humidity));
+        json.insert("outdoorTempF".to_string(), number(self.outdoor_temp));
+        json.insert("minTempF".to_string(), number(self.min_temp));
+        json.insert("maxTempF".to_string(), number(self.max_temp));
+        json.insert("cooling".to_string(), number(Some(self.cooling)));
+        json.insert("heating".to_string(), number(Some(self.heating)));
+        json.insert("fan".to_string(), number(Some(self.fan)));
+        json.insert("mode".to_string(), self.mode.to_string().to_json());
+        Json::Object(json)
+    }
+}
+
+/// A time range to look up, either as the samples or averaged over `resolution`. Without a start
+/// it is the `last` so long before the end, which is now unless given.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Query {
+    pub start: Option<DateTime<UTC>>,
+    pub end: Option<DateTime<UTC>>,
+    pub last: Duration,
+    pub resolution: Option<Duration>,
+}
+
+impl Query {
+    pub fn last(duration: Duration) -> Query {
+        Query { start: None, end: None, last: duration, resolution: None }
+    }
+
+    /// None when the start would be before any time there is
+    pub fn range(&self, now: DateTime<UTC>) -> Option<(DateTime<UTC>, DateTime<UTC>)> {
+        let end = self.end.unwrap_or(now);
+        Some((self.start.or_else(|| end.checked_sub(self.last))?, end))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample(minute: u32, temp: f32, compressor_mode: CompressorMode) -> Sample {
+        Sample {
+            time: UTC.ymd(2016, 7, 1).and_hms(12, minute, 0),
+            temp: Some(Temperature::in_f(temp)),
+            compressor_mode,
+            ..Sample::from_status(UTC::now(), &StatusReport::new())
+        }
+    }
+
+    #[test]
+    fn reads_back_what_it_writes() {
+        let mut sample = sample(0, 72.5, CompressorMode::HeatPump);
+        sample.status = Some(Status::TooCold);
+        sample.mode = SystemMode::Heat;
+
+        assert_eq!(Sample::from_json(&sample.to_json()), Ok(sample));
+    }
+
+    #[test]
+    fn averages_samples_and_summaries() {
+        let start = UTC.ymd(2016, 7, 1).and_hms(12, 0, 0);
+        let first = [sample(0, 70.0, CompressorMode::Cool), sample(1, 72.0, CompressorMode::Off)];
+        let second = [sample(5, 76.0, CompressorMode::Off)];
+
+        let first = Summary::of_samples(start, Duration::minutes(5), &first.iter().collect::<Vec<_>>());
+        assert_eq!(first.temp, Some(71.0));
+        assert_eq!(first.cooling, 0.5);
+
+        let second = Summary::of_samples(start + Duration::minutes(5), Duration::minutes(5), &second.iter().collect::<Vec<_>>());
+        let both = Summary::of_summaries(start, Duration::minutes(10), &[&first, &second]);
+        assert_eq!(both.samples, 3);
+        assert_eq!(both.temp, Some(72.666664));
+        assert_eq!(Summary::from_json(&first.to_json()), Ok(first));
+    }
+
+    #[test]
+    fn finds_the_interval() {
+        let time = UTC.ymd(2016, 7, 1).and_hms(12, 7, 31);
+
+        assert_eq!(interval_start(time, Duration::minutes(5)), UTC.ymd(2016, 7, 1).and_hms(12, 5, 0));
+        assert_eq!(interval_start(time, Duration::hours(1)), UTC.ymd(2016, 7, 1).and_hms(12, 0, 0));
+    }
+}
@@ -0,0 +1,318 @@
+//! The history on disk, as JSON lines in the state directory:
+//!
+//! - `history/raw/2016-07-01.jsonl`, every sample of a (UTC) day, kept for a week
+//! - `history/5min/2016-07.jsonl`, the 5 minute averages of a month, kept for a year
+//!
+//! Both are only ever appended to, so reading them while the thermostat runs is fine.
+
+use super::{interval_start, Query, Sample, Summary};
+use chrono::*;
+use rustc_serialize::json::{Json, ToJson};
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+const RAW_DAYS: i64 = 7;
+const AVERAGE_DAYS: i64 = 366;
+
+pub fn average_length() -> Duration {
+    Duration::minutes(5)
+}
+
+pub struct Store {
+    dir: PathBuf,
+    /// The samples of the 5 minutes that are still going, to be averaged once they are over
+    current: Vec<Sample>,
+}
+
+impl Store {
+    /// Picks up where the last run left off, so that its last 5 minutes still get averaged
+    pub fn open(dir: PathBuf) -> Store {
+        let mut store = Store { dir, current: vec![] };
+        let newest = store.raw_files().ok().and_then(|files| files.last().cloned());
+        if let Some((_, path)) = newest {
+            let samples = read_lines(&path, Sample::from_json).unwrap_or_default();
+            if let Some(last) = samples.last().map(|sample| interval_start(sample.time, average_length())) {
+                store.current = samples.into_iter().filter(|sample| sample.time >= last).collect();
+            }
+        }
+        store
+    }
+
+    fn raw_dir(&self) -> PathBuf {
+        self.dir.join("raw")
+    }
+
+    fn average_dir(&self) -> PathBuf {
+        self.dir.join("5min")
+    }
+
+    fn raw_path(&self, day: NaiveDate) -> PathBuf {
+        self.raw_dir().join(format!("{}.jsonl", day.format("%Y-%m-%d")))
+    }
+
+    fn average_path(&self, month: NaiveDate) -> PathBuf {
+        self.average_dir().join(format!("{}.jsonl", month.format("%Y-%m")))
+    }
+
+    /// Keeps the sample, and the average of the last 5 minutes once the sample is past them
+    pub fn add(&mut self, sample: Sample) -> io::Result<()> {
+        append(&self.raw_path(sample.time.naive_utc().date()), &sample.to_json())?;
+
+        let interval = interval_start(sample.time, average_length());
+        let finished = self.current.first().map(|first| interval_start(first.time, average_length()))
+            .filter(|current| *current < interval);
+        if let Some(start) = finished {
+            let summary = Summary::of_samples(start, average_length(), &self.current.iter().collect::<Vec<_>>());
+            self.current.clear();
+            append(&self.average_path(start.naive_utc().date()), &summary.to_json())?;
+            self.prune(sample.time)?;
+        }
+        self.current.push(sample);
+        Ok(())
+    }
+
+    /// Deletes what is past its retention
+    pub fn prune(&self, now: DateTime<UTC>) -> io::Result<()> {
+        let raw_cutoff = (now - Duration::days(RAW_DAYS)).naive_utc().date();
+        for (day, path) in self.raw_files()? {
+            if day < raw_cutoff {
+                fs::remove_file(path)?;
+            }
+        }
+
+        // a month goes once all of it is past the cutoff
+        let average_cutoff = (now - Duration::days(AVERAGE_DAYS)).naive_utc().date();
+        for (month, path) in self.average_files()? {
+            if next_month(month) <= average_cutoff {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn raw_files(&self) -> io::Result<Vec<(NaiveDate, PathBuf)>> {
+        dated_files(&self.raw_dir(), "")
+    }
+
+    fn average_files(&self) -> io::Result<Vec<(NaiveDate, PathBuf)>> {
+        dated_files(&self.average_dir(), "-01")
+    }
+
+    /// The samples taken from `start` up to `end`, oldest first
+    pub fn samples(&self, start: DateTime<UTC>, end: DateTime<UTC>) -> io::Result<Vec<Sample>> {
+        let mut samples = vec![];
+        for (day, path) in self.raw_files()? {
+            if day >= start.naive_utc().date() && day <= end.naive_utc().date() {
+                samples.extend(read_lines(&path, Sample::from_json)?.into_iter()
+                    .filter(|sample| sample.time >= start && sample.time < end));
+            }
+        }
+        Ok(samples)
+    }
+
+    /// The 5 minute averages that start from `start` up to `end`, oldest first
+    pub fn averages(&self, start: DateTime<UTC>, end: DateTime<UTC>) -> io::Result<Vec<Summary>> {
+        let first_month = start.naive_utc().date().with_day(1).unwrap();
+        let mut averages = vec![];
+        for (month, path) in self.average_files()? {
+            if month >= first_month && month <= end.naive_utc().date() {
+                averages.extend(read_lines(&path, Summary::from_json)?.into_iter()
+                    .filter(|summary| summary.start >= start && summary.start < end));
+            }
+        }
+        Ok(averages)
+    }
+
+    /// Averages over `resolution`. These come from the samples while they are still kept, and
+    /// from the 5 minute averages before that, so a finer resolution than 5 minutes only works
+    /// for the last week.
+    pub fn summaries(&self, start: DateTime<UTC>, end: DateTime<UTC>, resolution: Duration, now: DateTime<UTC>) -> io::Result<Vec<Summary>> {
+        let mut summaries: Vec<Summary> = vec![];
+        let mut add = |start, parts: Vec<Summary>| {
+            let parts: Vec<&Summary> = parts.iter().collect();
+            summaries.push(Summary::of_summaries(start, resolution, &parts));
+        };
+
+        if start >= now - Duration::days(RAW_DAYS) {
+            let samples = self.samples(start, end)?;
+            for (start, group) in group_by_interval(samples.iter(), |sample| sample.time, resolution) {
+                add(start, vec![Summary::of_samples(start, resolution, &group)]);
+            }
+        } else {
+            let averages = self.averages(start, end)?;
+            for (start, group) in group_by_interval(averages.iter(), |summary| summary.start, resolution) {
+                add(start, group.into_iter().cloned().collect());
+            }
+        }
+        Ok(summaries)
+    }
+
+    /// Answers a query for the samples, or the summaries when it has a resolution
+    pub fn query(&self, query: &Query, now: DateTime<UTC>) -> io::Result<Json> {
+        use rustc_serialize::json::ToJson;
+
+        let (start, end) = query.range(now).ok_or(io::Error::new(io::ErrorKind::InvalidInput, "the range is too long"))?;
+        Ok(match query.resolution {
+            Some(resolution) => self.summaries(start, end, resolution, now)?.to_json(),
+            None => self.samples(start, end)?.to_json(),
+        })
+    }
+}
+
+/// Consecutive items that fall in the same interval of `length`
+fn group_by_interval<'a, T, I, F>(items: I, time: F, length: Duration) -> Vec<(DateTime<UTC>, Vec<&'a T>)>
+    where I: Iterator<Item = &'a T>, F: Fn(&T) -> DateTime<UTC>
+{
+    let mut groups: Vec<(DateTime<UTC>, Vec<&T>)> = vec![];
+    for item in items {
+        let start = interval_start(time(item), length);
+        match groups.last_mut() {
+            Some(&mut (last, ref mut group)) if last == start => group.push(item),
+            _ => groups.push((start, vec![item])),
+        }
+    }
+    groups
+}
+
+fn next_month(month: NaiveDate) -> NaiveDate {
+    if month.month() == 12 {
+        NaiveDate::from_ymd(month.year() + 1, 1, 1)
+    } else {
+        NaiveDate::from_ymd(month.year(), month.month() + 1, 1)
+    }
+}
+
+/// The `.jsonl` files in `dir` named after a date, oldest first. `suffix` completes the name
+/// into a date, for files named after a month.
+fn dated_files(dir: &Path, suffix: &str) -> io::Result<Vec<(NaiveDate, PathBuf)>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+
+    let mut files = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        let date = path.file_name().and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".jsonl"))
+            .and_then(|name| NaiveDate::parse_from_str(&format!("{}{}", name, suffix), "%Y-%m-%d").ok());
+        if let Some(date) = date {
+            files.push((date, path));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn append(path: &Path, json: &Json) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", json)
+}
+
+/// Skips lines that can't be read, like one cut short by a crash
+fn read_lines<T, P>(path: &Path, parse: P) -> io::Result<Vec<T>> where P: Fn(&Json) -> Result<T, String> {
+    let mut items = vec![];
+    for line in BufReader::new(fs::File::open(path)?).lines() {
+        match Json::from_str(&line?).map_err(|e| e.to_string()).and_then(|json| parse(&json)) {
+            Ok(item) => items.push(item),
+            Err(e) => warn!("Skipping a line of {}: {}", path.display(), e),
+        }
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::ac_control::compressor::CompressorMode;
+    use ::status::StatusReport;
+    use ::uom::temp::*;
+    use tempdir::TempDir;
+
+    fn sample(time: DateTime<UTC>, temp: f32) -> Sample {
+        Sample { time, temp: Some(Temperature::in_f(temp)), ..Sample::from_status(time, &StatusReport::new()) }
+    }
+
+    fn start() -> DateTime<UTC> {
+        UTC.ymd(2016, 7, 1).and_hms(12, 0, 0)
+    }
+
+    #[test]
+    fn keeps_samples_and_averages_them() {
+        let dir = TempDir::new("history").unwrap();
+        let mut store = Store::open(dir.path().to_path_buf());
+
+        for minute in 0..12 {
+            store.add(sample(start() + Duration::minutes(minute), 70.0 + minute as f32)).unwrap();
+        }
+
+        let samples = store.samples(start() + Duration::minutes(3), start() + Duration::hours(1)).unwrap();
+        assert_eq!(samples.len(), 9);
+        assert_eq!(samples[0].temp, Some(Temperature::in_f(73.0)));
+
+        // the last 5 minutes aren't over yet
+        let averages = store.averages(start(), start() + Duration::hours(1)).unwrap();
+        assert_eq!(averages.iter().map(|a| a.temp).collect::<Vec<_>>(), vec![Some(72.0), Some(77.0)]);
+    }
+
+    #[test]
+    fn finishes_the_last_5_minutes_after_a_restart() {
+        let dir = TempDir::new("history").unwrap();
+        let mut store = Store::open(dir.path().to_path_buf());
+        store.add(sample(start(), 70.0)).unwrap();
+        store.add(sample(start() + Duration::minutes(1), 72.0)).unwrap();
+
+        let mut store = Store::open(dir.path().to_path_buf());
+        store.add(sample(start() + Duration::minutes(6), 74.0)).unwrap();
+
+        let averages = store.averages(start(), start() + Duration::hours(1)).unwrap();
+        assert_eq!(averages.len(), 1);
+        assert_eq!(averages[0].temp, Some(71.0));
+        assert_eq!(averages[0].samples, 2);
+    }
+
+    #[test]
+    fn keeps_only_a_week_of_samples() {
+        let dir = TempDir::new("history").unwrap();
+        let mut store = Store::open(dir.path().to_path_buf());
+
+        for day in 0..10 {
+            store.add(sample(start() + Duration::days(day), 70.0)).unwrap();
+            store.add(sample(start() + Duration::days(day) + Duration::minutes(5), 70.0)).unwrap();
+        }
+
+        let samples = store.samples(start(), start() + Duration::days(10)).unwrap();
+        assert_eq!(samples[0].time, start() + Duration::days(2));
+        // two per day, but for the last 5 minutes, which are still going
+        assert_eq!(store.averages(start(), start() + Duration::days(10)).unwrap().len(), 19);
+    }
+
+    #[test]
+    fn summarizes_from_the_samples_or_the_averages() {
+        let dir = TempDir::new("history").unwrap();
+        let mut store = Store::open(dir.path().to_path_buf());
+        for minute in 0..61 {
+            let mut sample = sample(start() + Duration::minutes(minute), 70.0);
+            sample.compressor_mode = if minute < 15 { CompressorMode::Cool } else { CompressorMode::Off };
+            store.add(sample).unwrap();
+        }
+        let end = start() + Duration::hours(1);
+
+        let recent = store.summaries(start(), end, Duration::minutes(30), start() + Duration::days(1)).unwrap();
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].cooling, 0.5);
+        assert_eq!(recent[0].samples, 30);
+
+        let old = store.summaries(start(), end, Duration::hours(1), start() + Duration::days(30)).unwrap();
+        assert_eq!(old.len(), 1);
+        assert_eq!(old[0].cooling, 0.25);
+        assert_eq!(old[0].samples, 60);
+    }
+}
@@ -1,11 +1,23 @@
 #[macro_use] extern crate log;
 
 extern crate chrono;
-extern crate mraa_api;
+extern crate num;
+extern crate libc;
+extern crate rustc_serialize;
+#[cfg(feature = "gpio-cdev")]
+extern crate gpio_cdev;
+
+#[cfg(test)] extern crate tempdir;
 
 pub mod sensors;
 pub mod uom;
 pub mod ac_control;
 pub mod controller;
 pub mod platform;
-
+pub mod persist;
+pub mod mqtt;
+pub mod settings;
+pub mod status;
+pub mod history;
+pub mod events;
+pub mod daemon;
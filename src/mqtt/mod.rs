@@ -0,0 +1,327 @@
+//! A small MQTT 3.1.1 client, with just what the thermostat needs: publishing and subscribing at
+//! QoS 0, a last will, and keeping the connection alive. Reconnecting is up to the caller.
+
+use std::io;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+/// How long the broker gets to answer the connect, and to send the rest of a packet
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+const DISCONNECT: u8 = 0xe0;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: String,
+    pub retain: bool,
+}
+
+impl Message {
+    pub fn new(topic: &str, payload: &str, retain: bool) -> Message {
+        Message { topic: topic.to_string(), payload: payload.to_string(), retain }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    /// host:port
+    pub address: String,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub keep_alive: Duration,
+    /// What the broker publishes when the connection goes away without a disconnect
+    pub will: Option<Message>,
+}
+
+pub struct Client {
+    stream: TcpStream,
+    keep_alive: Duration,
+    last_sent: Instant,
+    /// When the last ping went out, until the broker answers it
+    ping_sent: Option<Instant>,
+    next_packet_id: u16,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn push_str(buffer: &mut Vec<u8>, s: &str) {
+    buffer.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(s.as_bytes());
+}
+
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![kind];
+    let mut length = body.len();
+    loop {
+        let byte = (length % 128) as u8;
+        length /= 128;
+        packet.push(if length > 0 { byte | 0x80 } else { byte });
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn connect_packet(options: &Options) -> Vec<u8> {
+    let mut flags = 0x02; // clean session
+    if let Some(ref will) = options.will {
+        flags |= 0x04 | if will.retain { 0x20 } else { 0 };
+    }
+    if options.username.is_some() {
+        flags |= 0x80;
+    }
+    if options.password.is_some() {
+        flags |= 0x40;
+    }
+
+    let mut body = vec![];
+    push_str(&mut body, "MQTT");
+    body.push(4);
+    body.push(flags);
+    body.extend_from_slice(&(options.keep_alive.as_secs().min(u16::MAX as u64) as u16).to_be_bytes());
+    push_str(&mut body, &options.client_id);
+    if let Some(ref will) = options.will {
+        push_str(&mut body, &will.topic);
+        push_str(&mut body, &will.payload);
+    }
+    for field in [&options.username, &options.password].iter().filter_map(|field| field.as_ref()) {
+        push_str(&mut body, field);
+    }
+    packet(CONNECT, &body)
+}
+
+fn publish_packet(message: &Message) -> Vec<u8> {
+    let mut body = vec![];
+    push_str(&mut body, &message.topic);
+    body.extend_from_slice(message.payload.as_bytes());
+    packet(PUBLISH | if message.retain { 0x01 } else { 0 }, &body)
+}
+
+/// Reads a packet's remaining length and body, after its first byte
+fn read_body<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let (mut length, mut shift) = (0usize, 0);
+    loop {
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        length += ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift > 21 {
+            return Err(invalid("invalid packet length"));
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(body)
+}
+
+/// The topic and payload of a PUBLISH, and its packet id when the QoS needs one
+fn parse_publish(flags: u8, body: &[u8]) -> io::Result<(Message, Option<u16>)> {
+    let topic_length = body.get(..2).map(|length| u16::from_be_bytes([length[0], length[1]]) as usize)
+        .ok_or_else(|| invalid("publish without a topic"))?;
+    let topic = body.get(2..2 + topic_length).ok_or_else(|| invalid("publish with a cut off topic"))?;
+    let mut rest = 2 + topic_length;
+    let packet_id = if flags & 0x06 != 0 {
+        let id = body.get(rest..rest + 2).ok_or_else(|| invalid("publish without a packet id"))?;
+        rest += 2;
+        Some(u16::from_be_bytes([id[0], id[1]]))
+    } else {
+        None
+    };
+    Ok((Message {
+        topic: String::from_utf8_lossy(topic).into_owned(),
+        payload: String::from_utf8_lossy(&body[rest..]).into_owned(),
+        retain: flags & 0x01 != 0,
+    }, packet_id))
+}
+
+impl Client {
+    /// Connects and waits for the broker to accept
+    pub fn connect(options: &Options) -> io::Result<Client> {
+        let mut stream = TcpStream::connect(&options.address)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.write_all(&connect_packet(options))?;
+
+        let mut kind = [0];
+        stream.read_exact(&mut kind)?;
+        let body = read_body(&mut stream)?;
+        if kind[0] != CONNACK || body.len() != 2 {
+            return Err(invalid("expected a connack"));
+        }
+        match body[1] {
+            0 => (),
+            4 | 5 => return Err(io::Error::new(io::ErrorKind::PermissionDenied, "the broker refused the username or password")),
+            code => return Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("the broker refused the connection ({})", code))),
+        }
+
+        Ok(Client { stream, keep_alive: options.keep_alive, last_sent: Instant::now(), ping_sent: None, next_packet_id: 1 })
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.stream.write_all(packet)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    pub fn publish(&mut self, message: &Message) -> io::Result<()> {
+        self.send(&publish_packet(message))
+    }
+
+    /// Subscribes at QoS 0. Topics can have the usual + and # wildcards.
+    pub fn subscribe(&mut self, topics: &[String]) -> io::Result<()> {
+        let mut body = self.next_packet_id.to_be_bytes().to_vec();
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        for topic in topics {
+            push_str(&mut body, topic);
+            body.push(0);
+        }
+        self.send(&packet(SUBSCRIBE, &body))
+    }
+
+    pub fn disconnect(mut self) -> io::Result<()> {
+        self.send(&packet(DISCONNECT, &[]))
+    }
+
+    /// Waits up to `timeout` for a packet, and returns it when it is a message. Pings the broker
+    /// as the keep alive needs, and fails when it doesn't answer.
+    pub fn poll(&mut self, timeout: Duration) -> io::Result<Option<Message>> {
+        if let Some(sent) = self.ping_sent {
+            if sent.elapsed() > self.keep_alive {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "the broker stopped answering"));
+            }
+        } else if self.last_sent.elapsed() >= self.keep_alive / 2 {
+            self.send(&packet(PINGREQ, &[]))?;
+            self.ping_sent = Some(Instant::now());
+        }
+
+        self.stream.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        let mut kind = [0];
+        match self.stream.read(&mut kind) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the broker closed the connection")),
+            Ok(_) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        self.stream.set_read_timeout(Some(TIMEOUT))?;
+        let body = read_body(&mut self.stream)?;
+
+        match kind[0] & 0xf0 {
+            PUBLISH => {
+                let (message, packet_id) = parse_publish(kind[0] & 0x0f, &body)?;
+                if let Some(id) = packet_id {
+                    self.send(&packet(PUBACK, &id.to_be_bytes()))?;
+                }
+                Ok(Some(message))
+            },
+            PINGRESP => {
+                self.ping_sent = None;
+                Ok(None)
+            },
+            SUBACK => {
+                if body.iter().skip(2).any(|code| *code == 0x80) {
+                    warn!("The MQTT broker refused a subscription");
+                }
+                Ok(None)
+            },
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn options(address: String) -> Options {
+        Options {
+            address,
+            client_id: "test".to_string(),
+            username: None,
+            password: None,
+            keep_alive: Duration::from_secs(60),
+            will: Some(Message::new("thermostat/availability", "offline", true)),
+        }
+    }
+
+    fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut kind = [0];
+        stream.read_exact(&mut kind).unwrap();
+        (kind[0], read_body(stream).unwrap())
+    }
+
+    #[test]
+    fn encodes_the_remaining_length() {
+        assert_eq!(packet(PINGREQ, &[]), vec![0xc0, 0]);
+        assert_eq!(&packet(PUBLISH, &[0; 321])[..3], &[0x30, 0xc1, 0x02]);
+        assert_eq!(read_body(&mut &packet(PUBLISH, &[7; 321])[1..]).unwrap(), vec![7; 321]);
+    }
+
+    #[test]
+    fn talks_to_a_broker() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (kind, connect) = read_packet(&mut stream);
+            assert_eq!(kind, CONNECT);
+            assert_eq!(connect[7], 0x02 | 0x04 | 0x20);
+            stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+
+            assert_eq!(read_packet(&mut stream), (SUBSCRIBE, vec![0, 1, 0, 5, b'a', b'/', b's', b'e', b't', 0]));
+            stream.write_all(&publish_packet(&Message::new("a/set", "76", false))).unwrap();
+            read_packet(&mut stream)
+        });
+
+        let mut client = Client::connect(&options(address)).unwrap();
+        client.subscribe(&["a/set".to_string()]).unwrap();
+        assert_eq!(client.poll(Duration::from_secs(5)).unwrap(), Some(Message::new("a/set", "76", false)));
+        client.publish(&Message::new("a/temperature", "72.5", true)).unwrap();
+
+        let (kind, body) = broker.join().unwrap();
+        assert_eq!(kind, PUBLISH | 0x01);
+        assert_eq!(parse_publish(0x01, &body).unwrap(), (Message::new("a/temperature", "72.5", true), None));
+    }
+
+    /// Run with `cargo test -- --ignored` while mosquitto listens on localhost:1883
+    #[test]
+    #[ignore]
+    fn talks_to_mosquitto() {
+        let options = |id: &str| Options { client_id: id.to_string(), ..options("localhost:1883".to_string()) };
+        let mut publisher = Client::connect(&options("thermostat-test-publisher")).unwrap();
+        publisher.publish(&Message::new("thermostat-test/temperature", "72.5", true)).unwrap();
+
+        let mut subscriber = Client::connect(&options("thermostat-test-subscriber")).unwrap();
+        subscriber.subscribe(&["thermostat-test/#".to_string()]).unwrap();
+        let mut received = None;
+        for _ in 0..10 {
+            if let Some(message) = subscriber.poll(Duration::from_millis(500)).unwrap() {
+                received = Some(message);
+                break;
+            }
+        }
+        assert_eq!(received, Some(Message::new("thermostat-test/temperature", "72.5", true)));
+
+        // clears the retained message again
+        publisher.publish(&Message::new("thermostat-test/temperature", "", true)).unwrap();
+        publisher.disconnect().unwrap();
+    }
+}
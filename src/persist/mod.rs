@@ -0,0 +1,100 @@
+//! Writing state files so that a crash or power cut mid-write never leaves a broken one behind
+
+use chrono::Duration;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// Readable by the thermostat's group, so monitoring can look without being able to change anything
+const FILE_MODE: u32 = 0o640;
+
+/// No counter or interval in a state file gets anywhere near this
+const MAX_SECONDS: i64 = 1000 * 366 * 24 * 60 * 60;
+
+/// Seconds read back from a file as a duration, or None for a number no duration there could be,
+/// e.g. from a damaged file
+pub fn seconds(seconds: i64) -> Option<Duration> {
+    if (0..=MAX_SECONDS).contains(&seconds) { Some(Duration::seconds(seconds)) } else { None }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map_or(OsString::new(), |name| name.to_os_string());
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Where `write_with_backup` keeps the previous version of a file, e.g. config.json.bak
+pub fn backup_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".bak")
+}
+
+/// Replaces the file in one step: the contents go to a temporary file next to it, which is then
+/// renamed over the old one. Readers see either the old or the new contents, never a mix.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temp = with_suffix(path, ".tmp");
+    {
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(FILE_MODE).open(&temp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+    }
+    fs::rename(&temp, path)
+}
+
+/// Like `write_atomic`, but first keeps the current file as the backup when `is_good` says it is
+/// still readable. A broken file never overwrites the last good backup.
+pub fn write_with_backup<G>(path: &Path, contents: &[u8], is_good: G) -> io::Result<()> where G: Fn(&str) -> bool {
+    let mut current = String::new();
+    let readable = File::open(path).and_then(|mut file| file.read_to_string(&mut current)).is_ok();
+    if readable && is_good(&current) {
+        write_atomic(&backup_path(path), current.as_bytes())?;
+    }
+
+    write_atomic(path, contents)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempdir::TempDir;
+
+    fn contents(path: &Path) -> String {
+        let mut s = String::new();
+        File::open(path).unwrap().read_to_string(&mut s).unwrap();
+        s
+    }
+
+    #[test]
+    fn replaces_longer_contents_completely() {
+        let dir = TempDir::new("persist").unwrap();
+        let path = dir.path().join("config.json");
+
+        write_atomic(&path, b"{\"schedule\":[1,2,3]}").unwrap();
+        write_atomic(&path, b"{}").unwrap();
+
+        assert_eq!(contents(&path), "{}");
+        assert!(!dir.path().join("config.json.tmp").exists());
+        // the umask can only take permissions away
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o007, 0);
+    }
+
+    #[test]
+    fn keeps_the_last_good_file_as_a_backup() {
+        let dir = TempDir::new("persist").unwrap();
+        let path = dir.path().join("config.json");
+        let is_good = |s: &str| s.starts_with('{');
+
+        write_with_backup(&path, b"{1}", is_good).unwrap();
+        assert!(!backup_path(&path).exists());
+
+        write_with_backup(&path, b"garbage", is_good).unwrap();
+        assert_eq!(contents(&backup_path(&path)), "{1}");
+
+        write_with_backup(&path, b"{3}", is_good).unwrap();
+        assert_eq!(contents(&backup_path(&path)), "{1}");
+        assert_eq!(contents(&path), "{3}");
+    }
+}
@@ -0,0 +1,41 @@
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+use ::settings::PinSettings;
+use super::OutputLine;
+use std::io;
+use std::path::Path;
+
+fn to_io_error<E: ToString>(e: E) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+/// A line requested from a GPIO character device. The kernel takes care of the polarity, and
+/// keeps the line ours for as long as the handle is open.
+pub struct CdevLine {
+    handle: LineHandle,
+}
+
+impl CdevLine {
+    pub fn open(chip: &Path, pin: &PinSettings) -> io::Result<CdevLine> {
+        let mut flags = LineRequestFlags::OUTPUT;
+        if pin.active_low {
+            flags |= LineRequestFlags::ACTIVE_LOW;
+        }
+
+        let handle = Chip::new(chip)
+            .and_then(|mut chip| chip.get_line(pin.pin as u32))
+            .and_then(|line| line.request(flags, 0, "thermostat"))
+            .map_err(to_io_error)?;
+
+        Ok(CdevLine { handle })
+    }
+}
+
+impl OutputLine for CdevLine {
+    fn write(&mut self, on: bool) -> io::Result<()> {
+        self.handle.set_value(on as u8).map_err(to_io_error)
+    }
+
+    fn read(&mut self) -> io::Result<bool> {
+        self.handle.get_value().map(|value| value != 0).map_err(to_io_error)
+    }
+}
@@ -0,0 +1,166 @@
+//! Outputs driven through libmraa on the Edison, or through the kernel's own GPIO interfaces
+//! on boards mraa doesn't know about.
+
+mod sysfs;
+#[cfg(feature = "gpio-cdev")]
+mod cdev;
+#[cfg(feature = "mraa")]
+mod mraa;
+
+pub use self::sysfs::SysfsLine;
+#[cfg(feature = "gpio-cdev")]
+pub use self::cdev::CdevLine;
+#[cfg(feature = "mraa")]
+pub use self::mraa::MraaLine;
+
+use ::ac_control::compressor::Switches;
+use ::settings::{GpioSettings, PinSettings};
+use std::io;
+use std::path::Path;
+
+/// A single GPIO line set up as an output, with the polarity already taken care of
+pub trait OutputLine {
+    fn write(&mut self, on: bool) -> io::Result<()>;
+    fn read(&mut self) -> io::Result<bool>;
+}
+
+pub struct LineSwitches {
+    cool: Option<Box<dyn OutputLine>>,
+    heat: Option<Box<dyn OutputLine>>,
+    fan: Option<Box<dyn OutputLine>>,
+}
+
+impl LineSwitches {
+    pub fn open<F>(settings: &GpioSettings, open_line: F) -> io::Result<LineSwitches>
+            where F: Fn(&PinSettings) -> io::Result<Box<dyn OutputLine>> {
+        let open = |pin: &Option<PinSettings>| -> io::Result<Option<Box<dyn OutputLine>>> {
+            match *pin {
+                Some(ref pin) => open_line(pin).map(Some),
+                None => Ok(None),
+            }
+        };
+
+        Ok(LineSwitches {
+            cool: open(&settings.cool)?,
+            heat: open(&settings.heat)?,
+            fan: open(&settings.fan)?,
+        })
+    }
+
+    /// Uses the legacy sysfs interface, usually found at /sys/class/gpio
+    pub fn sysfs(root: &Path, settings: &GpioSettings) -> io::Result<LineSwitches> {
+        LineSwitches::open(settings, |pin| {
+            SysfsLine::open(root, pin).map(|line| Box::new(line) as Box<dyn OutputLine>)
+        })
+    }
+
+    /// Uses libmraa's pin numbering for the board
+    #[cfg(feature = "mraa")]
+    pub fn mraa(settings: &GpioSettings) -> io::Result<LineSwitches> {
+        LineSwitches::open(settings, |pin| MraaLine::open(pin).map(|line| Box::new(line) as Box<dyn OutputLine>))
+    }
+
+    /// Uses the GPIO character device of a chip, e.g. /dev/gpiochip0
+    #[cfg(feature = "gpio-cdev")]
+    pub fn cdev(chip: &Path, settings: &GpioSettings) -> io::Result<LineSwitches> {
+        LineSwitches::open(settings, |pin| {
+            CdevLine::open(chip, pin).map(|line| Box::new(line) as Box<dyn OutputLine>)
+        })
+    }
+}
+
+fn write(line: &mut Option<Box<dyn OutputLine>>, name: &str, on: bool) -> io::Result<()> {
+    match *line {
+        Some(ref mut line) => {
+            info!("Writing to {} gpio: {}", name, on);
+            line.write(on)
+        },
+        // an output that isn't there is always off
+        None if !on => Ok(()),
+        None => Err(io::Error::new(io::ErrorKind::NotFound, format!("no {} output on this board", name))),
+    }
+}
+
+fn read(line: &mut Option<Box<dyn OutputLine>>) -> io::Result<Option<bool>> {
+    match *line {
+        Some(ref mut line) => line.read().map(Some),
+        None => Ok(None),
+    }
+}
+
+impl Switches for LineSwitches {
+    fn set_cool(&mut self, on: bool) -> io::Result<()> {
+        write(&mut self.cool, "cool", on)
+    }
+
+    fn set_heat(&mut self, on: bool) -> io::Result<()> {
+        write(&mut self.heat, "heat", on)
+    }
+
+    fn set_fan(&mut self, on: bool) -> io::Result<()> {
+        write(&mut self.fan, "fan", on)
+    }
+
+    fn read_cool(&mut self) -> io::Result<Option<bool>> {
+        read(&mut self.cool)
+    }
+
+    fn read_heat(&mut self) -> io::Result<Option<bool>> {
+        read(&mut self.heat)
+    }
+
+    fn read_fan(&mut self) -> io::Result<Option<bool>> {
+        read(&mut self.fan)
+    }
+}
+
+/// Whatever ends the thermostat, even a panic, cooling and heating must not keep running with
+/// nothing watching them
+impl Drop for LineSwitches {
+    fn drop(&mut self) {
+        for &mut (ref mut line, name) in &mut [(&mut self.cool, "cool"), (&mut self.heat, "heat"), (&mut self.fan, "fan")] {
+            if let Err(e) = write(line, name, false) {
+                error!("Could not turn the {} output off: {}", name, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::ac_control::compressor::Switches;
+    use ::settings::{GpioBackend, GpioSettings, PinSettings};
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn switches_the_configured_lines_and_reads_them_back() {
+        let root = TempDir::new("gpio").unwrap();
+        for pin in &["gpio5", "gpio6"] {
+            fs::create_dir(root.path().join(pin)).unwrap();
+            fs::write(root.path().join(pin).join("value"), "0").unwrap();
+        }
+        let settings = GpioSettings {
+            backend: GpioBackend::Sysfs(root.path().to_path_buf()),
+            cool: Some(PinSettings { pin: 5, active_low: false }),
+            heat: None,
+            fan: Some(PinSettings { pin: 6, active_low: true }),
+        };
+        let mut switches = LineSwitches::sysfs(root.path(), &settings).unwrap();
+
+        switches.set_fan(true).unwrap();
+        assert_eq!(switches.read_fan().unwrap(), Some(true));
+        assert_eq!(switches.read_cool().unwrap(), Some(false));
+
+        assert!(switches.set_heat(false).is_ok());
+        assert!(switches.set_heat(true).is_err());
+        assert_eq!(switches.read_heat().unwrap(), None);
+
+        switches.set_cool(true).unwrap();
+        drop(switches);
+        assert_eq!(fs::read_to_string(root.path().join("gpio5/value")).unwrap().trim(), "0");
+        // active low, so off is high
+        assert_eq!(fs::read_to_string(root.path().join("gpio6/value")).unwrap().trim(), "1");
+    }
+}
@@ -0,0 +1,62 @@
+use ::settings::PinSettings;
+use super::OutputLine;
+use std::io;
+use std::os::raw::{c_int, c_void};
+
+const MRAA_SUCCESS: c_int = 0;
+const MRAA_GPIO_OUT: c_int = 0;
+
+#[link(name = "mraa")]
+extern "C" {
+    fn mraa_gpio_init(pin: c_int) -> *mut c_void;
+    fn mraa_gpio_dir(dev: *mut c_void, dir: c_int) -> c_int;
+    fn mraa_gpio_write(dev: *mut c_void, value: c_int) -> c_int;
+    fn mraa_gpio_read(dev: *mut c_void) -> c_int;
+    fn mraa_gpio_close(dev: *mut c_void) -> c_int;
+}
+
+fn check(result: c_int, what: &str) -> io::Result<()> {
+    if result == MRAA_SUCCESS {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("mraa could not {} (error {})", what, result)))
+    }
+}
+
+/// A gpio opened through libmraa, using its pin numbering for the board
+pub struct MraaLine {
+    context: *mut c_void,
+    active_low: bool,
+}
+
+impl MraaLine {
+    pub fn open(pin: &PinSettings) -> io::Result<MraaLine> {
+        let context = unsafe { mraa_gpio_init(pin.pin) };
+        if context.is_null() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("mraa could not open pin {}", pin.pin)));
+        }
+
+        let line = MraaLine { context, active_low: pin.active_low };
+        check(unsafe { mraa_gpio_dir(context, MRAA_GPIO_OUT) }, "set the direction")?;
+        Ok(line)
+    }
+}
+
+impl OutputLine for MraaLine {
+    fn write(&mut self, on: bool) -> io::Result<()> {
+        check(unsafe { mraa_gpio_write(self.context, (on != self.active_low) as c_int) }, "write")
+    }
+
+    fn read(&mut self) -> io::Result<bool> {
+        match unsafe { mraa_gpio_read(self.context) } {
+            value if value < 0 => Err(io::Error::other("mraa could not read")),
+            value => Ok((value != 0) != self.active_low),
+        }
+    }
+}
+
+impl Drop for MraaLine {
+    fn drop(&mut self) {
+        unsafe { mraa_gpio_close(self.context); }
+    }
+}
@@ -0,0 +1,134 @@
+use ::settings::PinSettings;
+use super::OutputLine;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait for udev to hand over a freshly exported gpio
+const EXPORT_ATTEMPTS: u32 = 10;
+
+/// A line exported through the legacy sysfs interface
+pub struct SysfsLine {
+    value: PathBuf,
+    active_low: bool,
+}
+
+fn write_file(path: &Path, contents: &str) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(contents.as_bytes())
+}
+
+impl SysfsLine {
+    pub fn open(root: &Path, pin: &PinSettings) -> io::Result<SysfsLine> {
+        let gpio = root.join(format!("gpio{}", pin.pin));
+        let direction = gpio.join("direction");
+
+        if !gpio.exists() {
+            write_file(&root.join("export"), &pin.pin.to_string())?;
+        }
+
+        // "low" and "high" set the direction and the starting level in one go, so the relay never
+        // clicks on while the line is being set up
+        let off = if pin.active_low { "high" } else { "low" };
+        let mut attempt = 1;
+        while let Err(e) = write_file(&direction, off) {
+            if attempt >= EXPORT_ATTEMPTS {
+                return Err(e);
+            }
+            attempt += 1;
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        Ok(SysfsLine { value: gpio.join("value"), active_low: pin.active_low })
+    }
+}
+
+impl OutputLine for SysfsLine {
+    fn write(&mut self, on: bool) -> io::Result<()> {
+        write_file(&self.value, if on != self.active_low { "1" } else { "0" })
+    }
+
+    fn read(&mut self) -> io::Result<bool> {
+        let mut s = String::new();
+        File::open(&self.value)?.read_to_string(&mut s)?;
+
+        match s.trim() {
+            "0" => Ok(self.active_low),
+            "1" => Ok(!self.active_low),
+            other => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected gpio value {}", other))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::platform::linux::gpio::OutputLine;
+    use ::settings::PinSettings;
+    use std::fs::{self, File};
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    fn fake_gpio(root: &Path, pin: i32) {
+        let gpio = root.join(format!("gpio{}", pin));
+        fs::create_dir(&gpio).unwrap();
+        File::create(gpio.join("direction")).unwrap();
+        File::create(gpio.join("value")).unwrap();
+    }
+
+    fn contents(path: &Path) -> String {
+        let mut s = String::new();
+        File::open(path).unwrap().read_to_string(&mut s).unwrap();
+        s
+    }
+
+    #[test]
+    fn sets_up_the_line_as_an_output_that_is_off() {
+        let root = TempDir::new("gpio").unwrap();
+        fake_gpio(root.path(), 14);
+        fake_gpio(root.path(), 15);
+
+        SysfsLine::open(root.path(), &PinSettings { pin: 14, active_low: false }).unwrap();
+        SysfsLine::open(root.path(), &PinSettings { pin: 15, active_low: true }).unwrap();
+
+        assert_eq!(contents(&root.path().join("gpio14/direction")), "low");
+        assert_eq!(contents(&root.path().join("gpio15/direction")), "high");
+    }
+
+    #[test]
+    fn writes_and_reads_back_the_value() {
+        let root = TempDir::new("gpio").unwrap();
+        fake_gpio(root.path(), 14);
+        let mut line = SysfsLine::open(root.path(), &PinSettings { pin: 14, active_low: false }).unwrap();
+
+        line.write(true).unwrap();
+
+        assert_eq!(contents(&root.path().join("gpio14/value")), "1");
+        assert!(line.read().unwrap());
+    }
+
+    #[test]
+    fn inverts_active_low_lines() {
+        let root = TempDir::new("gpio").unwrap();
+        fake_gpio(root.path(), 31);
+        let mut line = SysfsLine::open(root.path(), &PinSettings { pin: 31, active_low: true }).unwrap();
+
+        line.write(true).unwrap();
+
+        assert_eq!(contents(&root.path().join("gpio31/value")), "0");
+        assert!(line.read().unwrap());
+    }
+
+    #[test]
+    fn exports_lines_that_are_not_there_yet() {
+        let root = TempDir::new("gpio").unwrap();
+        File::create(root.path().join("export")).unwrap();
+
+        // nothing creates the gpio directory here, as the kernel would
+        assert!(SysfsLine::open(root.path(), &PinSettings { pin: 20, active_low: false }).is_err());
+        assert_eq!(contents(&root.path().join("export")), "20");
+    }
+}
@@ -0,0 +1,88 @@
+//! Temperature and humidity chips the kernel already has a driver for (SHT3x, BME280, HTU21D, ...),
+//! read through their hwmon (`/sys/class/hwmon/hwmon*/temp1_input`) or IIO
+//! (`/sys/bus/iio/devices/iio:device*/in_temp_input`) attributes
+
+use ::uom::temp::*;
+use ::sensors::{TempReader, HumidityReader};
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+/// A single sysfs attribute holding a number. Multiplied by `scale` it gives degrees C or %RH; for
+/// hwmon and IIO `_input` attributes that is 0.001.
+pub struct SysfsAttribute {
+    path: PathBuf,
+    scale: f32,
+}
+
+impl SysfsAttribute {
+    pub fn new(path: &Path, scale: f32) -> SysfsAttribute {
+        SysfsAttribute { path: path.to_path_buf(), scale }
+    }
+
+    pub fn read(&self) -> io::Result<f32> {
+        let mut s = String::new();
+        File::open(&self.path)?.read_to_string(&mut s)?;
+
+        s.trim().parse::<f32>()
+            .map(|raw| raw * self.scale)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData,
+                                        format!("unexpected value {} in {}", s.trim(), self.path.display())))
+    }
+}
+
+pub struct SysfsTemp(pub SysfsAttribute);
+
+impl TempReader for SysfsTemp {
+    fn get_temp(&mut self) -> io::Result<Temperature<F>> {
+        self.0.read().map(|c| Temperature::in_c(c).to_f())
+    }
+}
+
+pub struct SysfsHumidity(pub SysfsAttribute);
+
+impl HumidityReader for SysfsHumidity {
+    fn get_humidity(&mut self) -> io::Result<f32> {
+        self.0.read()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn fake_attribute(dir: &TempDir, name: &str, contents: &str) -> PathBuf {
+        let path = dir.path().join(name);
+        File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        path
+    }
+
+    #[test]
+    fn reads_scaled_hwmon_values() {
+        let hwmon = TempDir::new("hwmon").unwrap();
+        let temp = fake_attribute(&hwmon, "temp1_input", "23125\n");
+        let humidity = fake_attribute(&hwmon, "humidity1_input", "45500\n");
+
+        assert!(SysfsTemp(SysfsAttribute::new(&temp, 0.001)).get_temp().unwrap() == Temperature::in_c(23.125).to_f());
+        assert!((SysfsHumidity(SysfsAttribute::new(&humidity, 0.001)).get_humidity().unwrap() - 45.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn uses_the_configured_scale() {
+        let iio = TempDir::new("iio").unwrap();
+        let temp = fake_attribute(&iio, "in_temp_input", "2312\n");
+
+        assert!(SysfsTemp(SysfsAttribute::new(&temp, 0.01)).get_temp().unwrap() == Temperature::in_c(23.12).to_f());
+    }
+
+    #[test]
+    fn fails_on_garbage_or_a_missing_attribute() {
+        let hwmon = TempDir::new("hwmon").unwrap();
+        let temp = fake_attribute(&hwmon, "temp1_input", "\n");
+
+        assert!(SysfsTemp(SysfsAttribute::new(&temp, 0.001)).get_temp().is_err());
+        assert!(SysfsTemp(SysfsAttribute::new(&hwmon.path().join("temp2_input"), 0.001)).get_temp().is_err());
+    }
+}
@@ -1,8 +1,12 @@
+pub mod gpio;
+pub mod hwmon;
+pub mod w1;
+
 use ::uom::temp::*;
 use ::sensors::TempReader;
-use ::ac_control::compressor::Switches;
 use std::fs::OpenOptions;
 use std::fs::File;
+use std::io;
 use std::io::BufReader;
 use std::io::prelude::*;
 use std::path::Path;
@@ -25,70 +29,25 @@ fn parse_temp(buffer: String) -> Option<Temperature<F>> {
     None
 }
 
-fn open_sensor() -> File {
-    OpenOptions::new().read(true).write(true).open(Path::new("/dev/ttymcu0")).unwrap()
+fn open_sensor() -> io::Result<File> {
+    OpenOptions::new().read(true).write(true).open(Path::new("/dev/ttymcu0"))
 }
 
-fn read_sensor(mut mcu: File) -> String {
-    mcu.write(b"get_temp\n").unwrap();
+fn read_sensor(mut mcu: File) -> io::Result<String> {
+    mcu.write_all(b"get_temp\n")?;
 
     let mut reader = BufReader::new(mcu);
     let mut buffer = String::new();
-    reader.read_line(&mut buffer).unwrap();
+    reader.read_line(&mut buffer)?;
 
-    buffer
+    Ok(buffer)
 }
 
 impl TempReader for McuTemp {
-    fn get_temp() -> Temperature<F> {
-        // TODO: eliminate these unwraps
-        parse_temp(read_sensor(open_sensor())).unwrap()
-    }
-}
-
-pub mod ac_control {
-    use mraa_api::gpio::Gpio;
-    use mraa_api::gpio::MRAA_INTEL_EDISON;
-    use mraa_api::gpio::GPIO_DIR;
-    use ::ac_control::compressor::Switches;
-
-    pub struct GpioSwitches {
-        cool_gpio: Gpio,
-        heat_gpio: Gpio,
-        fan_gpio: Gpio,
-    }
-
-    impl GpioSwitches {
-        pub fn new() -> GpioSwitches {
-            let mut switches = GpioSwitches {
-                cool_gpio: Gpio::new(14),
-                heat_gpio: Gpio::new(15),
-                fan_gpio: Gpio::new(31),
-            };
-
-            switches.cool_gpio.set_dir(GPIO_DIR::MRAA_GPIO_OUT);
-            switches.heat_gpio.set_dir(GPIO_DIR::MRAA_GPIO_OUT);
-            switches.fan_gpio.set_dir(GPIO_DIR::MRAA_GPIO_OUT);
-
-            switches
-        }
-    }
-
-    impl Switches for GpioSwitches {
-        fn set_cool(&mut self, on: bool) {
-            self.cool_gpio.write(on);
-            info!("Writing to cool gpio: {}", on);
-        }
-
-        fn set_heat(&mut self, on: bool) {
-            self.heat_gpio.write(on);
-            info!("Writing to heat gpio: {}", on);
-        }
-
-        fn set_fan(&mut self, on: bool) {
-            self.fan_gpio.write(on);
-            info!("Writing to fan gpio: {}", on);
-        }
+    fn get_temp(&mut self) -> io::Result<Temperature<F>> {
+        let buffer = read_sensor(open_sensor()?)?;
+        parse_temp(buffer.clone())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("unexpected mcu reply {}", buffer.trim())))
     }
 }
 
@@ -108,6 +67,6 @@ fn parse_valid_temp() {
 fn parse_invalid_temp() {
     let temp_str = "Temp229, Humidity 345";
 
-    assert!(parse_temp(temp_str.to_string()) == None);
+    assert!(parse_temp(temp_str.to_string()).is_none());
 }
 // }
@@ -0,0 +1,172 @@
+//! DS18B20 probes on the kernel's 1-Wire bus
+
+use ::uom::temp::*;
+use ::sensors::TempReader;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+/// Family code the kernel prefixes DS18B20 ids with
+const DS18B20_FAMILY: &str = "28-";
+/// What a probe reports before its first conversion, e.g. after a brown-out
+const POWER_ON_RESET: i32 = 85000;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Parses a w1_slave file, which looks like
+///
+/// ```text
+/// 72 01 4b 46 7f ff 0e 10 57 : crc=57 YES
+/// 72 01 4b 46 7f ff 0e 10 57 t=23125
+/// ```
+fn parse_w1_slave(contents: &str) -> io::Result<Temperature<F>> {
+    let mut lines = contents.lines();
+
+    match lines.next() {
+        Some(crc) if crc.trim_end().ends_with("YES") => (),
+        Some(crc) => return Err(invalid(format!("crc check failed: {}", crc.trim()))),
+        None => return Err(invalid("empty reading".to_string())),
+    }
+
+    let millis = lines.next()
+        .and_then(|line| line.find("t=").map(|index| &line[index + 2..]))
+        .and_then(|t| t.trim().parse::<i32>().ok())
+        .ok_or_else(|| invalid(format!("no temperature in {}", contents.trim())))?;
+
+    if millis == POWER_ON_RESET {
+        return Err(invalid("probe reported its power-on value".to_string()));
+    }
+
+    Ok(Temperature::in_c(millis as f32 / 1000.0).to_f())
+}
+
+/// The DS18B20 probes under a w1 devices directory, normally /sys/bus/w1/devices. Probes are known
+/// by their id (e.g. "28-0316a2795dff") unless given a name.
+pub struct W1Temp {
+    root: PathBuf,
+    /// Probe id to name
+    names: BTreeMap<String, String>,
+    /// Name or id of the probe that `get_temp` reads, otherwise the first one found
+    indoor: Option<String>,
+}
+
+impl W1Temp {
+    pub fn new(root: &Path, names: BTreeMap<String, String>, indoor: Option<String>) -> W1Temp {
+        W1Temp { root: root.to_path_buf(), names, indoor }
+    }
+
+    /// Ids of the probes on the bus, in order
+    pub fn probes(&self) -> io::Result<Vec<String>> {
+        let mut ids = vec![];
+        for entry in fs::read_dir(&self.root)? {
+            let id = entry?.file_name().to_string_lossy().into_owned();
+            if id.starts_with(DS18B20_FAMILY) {
+                ids.push(id);
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    pub fn name<'a>(&'a self, id: &'a str) -> &'a str {
+        self.names.get(id).map_or(id, |name| name)
+    }
+
+    pub fn read_probe(&self, id: &str) -> io::Result<Temperature<F>> {
+        let mut s = String::new();
+        File::open(self.root.join(id).join("w1_slave"))?.read_to_string(&mut s)?;
+        parse_w1_slave(&s).map_err(|e| invalid(format!("{}: {}", self.name(id), e)))
+    }
+
+    /// Every probe's reading, by name
+    pub fn read_all(&self) -> io::Result<BTreeMap<String, io::Result<Temperature<F>>>> {
+        Ok(self.probes()?.iter().map(|id| (self.name(id).to_string(), self.read_probe(id))).collect())
+    }
+
+    fn indoor_probe(&self) -> io::Result<String> {
+        let probes = self.probes()?;
+        let found = match self.indoor {
+            Some(ref wanted) => probes.into_iter().find(|id| id == wanted || self.name(id) == wanted),
+            None => probes.into_iter().next(),
+        };
+
+        found.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound,
+                                           format!("no DS18B20 {} in {}",
+                                                   self.indoor.as_ref().map_or("probes", |s| s),
+                                                   self.root.display())))
+    }
+}
+
+impl TempReader for W1Temp {
+    fn get_temp(&mut self) -> io::Result<Temperature<F>> {
+        let id = self.indoor_probe()?;
+        self.read_probe(&id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    const GOOD: &str = "72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+    const BAD_CRC: &str = "72 01 4b 46 7f ff 0e 10 57 : crc=c4 NO\n72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+    const RESET: &str = "50 05 4b 46 7f ff 0c 10 1c : crc=1c YES\n50 05 4b 46 7f ff 0c 10 1c t=85000\n";
+
+    fn fake_probe(root: &Path, id: &str, contents: &str) {
+        fs::create_dir(root.join(id)).unwrap();
+        File::create(root.join(id).join("w1_slave")).unwrap().write_all(contents.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn parses_a_good_reading() {
+        assert!(parse_w1_slave(GOOD).unwrap() == Temperature::in_c(23.125).to_f());
+    }
+
+    #[test]
+    fn rejects_crc_failures_and_the_power_on_value() {
+        assert!(parse_w1_slave(BAD_CRC).is_err());
+        assert!(parse_w1_slave(RESET).is_err());
+        assert!(parse_w1_slave("").is_err());
+    }
+
+    #[test]
+    fn finds_probes_and_names_them() {
+        let root = TempDir::new("w1").unwrap();
+        fake_probe(root.path(), "28-0000075a1b2c", GOOD);
+        fake_probe(root.path(), "28-0316a2795dff", BAD_CRC);
+        fs::create_dir(root.path().join("w1_bus_master1")).unwrap();
+
+        let mut names = BTreeMap::new();
+        names.insert("28-0316a2795dff".to_string(), "attic".to_string());
+        let w1 = W1Temp::new(root.path(), names, None);
+
+        assert_eq!(w1.probes().unwrap(), vec!["28-0000075a1b2c", "28-0316a2795dff"]);
+        let readings = w1.read_all().unwrap();
+        assert!(readings["28-0000075a1b2c"].is_ok());
+        assert!(readings["attic"].is_err());
+    }
+
+    #[test]
+    fn reads_the_indoor_probe_by_name() {
+        let root = TempDir::new("w1").unwrap();
+        fake_probe(root.path(), "28-0000075a1b2c", RESET);
+        fake_probe(root.path(), "28-0316a2795dff", GOOD);
+
+        let mut names = BTreeMap::new();
+        names.insert("28-0316a2795dff".to_string(), "hallway".to_string());
+
+        let mut first = W1Temp::new(root.path(), names.clone(), None);
+        assert!(first.get_temp().is_err());
+
+        let mut hallway = W1Temp::new(root.path(), names, Some("hallway".to_string()));
+        assert!(hallway.get_temp().unwrap() == Temperature::in_c(23.125).to_f());
+
+        let mut missing = W1Temp::new(root.path(), BTreeMap::new(), Some("hallway".to_string()));
+        assert!(missing.get_temp().is_err());
+    }
+}
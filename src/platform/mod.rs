@@ -1,2 +1,4 @@
 
 pub mod linux;
+#[cfg(feature = "sim")]
+pub mod sim;
@@ -0,0 +1,78 @@
+//! A pretend house to run the thermostat against on a machine without any of the hardware
+
+use ::ac_control::compressor::Switches;
+use ::sensors::TempReader;
+use ::uom::temp::*;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicIsize, Ordering};
+
+static COOL: AtomicBool = AtomicBool::new(false);
+static HEAT: AtomicBool = AtomicBool::new(false);
+static FAN: AtomicBool = AtomicBool::new(false);
+/// In tenths of a degree F, like `Temperature`
+static INDOOR_TEMP: AtomicIsize = AtomicIsize::new(770);
+const OUTDOOR_TEMP: isize = 850;
+
+/// Relays that always do what they are told
+pub struct SimSwitches;
+
+impl Switches for SimSwitches {
+    fn set_cool(&mut self, on: bool) -> io::Result<()> {
+        info!("Simulated cool: {}", on);
+        COOL.store(on, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn set_heat(&mut self, on: bool) -> io::Result<()> {
+        info!("Simulated heat: {}", on);
+        HEAT.store(on, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn set_fan(&mut self, on: bool) -> io::Result<()> {
+        info!("Simulated fan: {}", on);
+        FAN.store(on, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn read_cool(&mut self) -> io::Result<Option<bool>> {
+        Ok(Some(COOL.load(Ordering::SeqCst)))
+    }
+
+    fn read_heat(&mut self) -> io::Result<Option<bool>> {
+        Ok(Some(HEAT.load(Ordering::SeqCst)))
+    }
+
+    fn read_fan(&mut self) -> io::Result<Option<bool>> {
+        Ok(Some(FAN.load(Ordering::SeqCst)))
+    }
+}
+
+/// Every reading moves the house a tenth of a degree towards wherever the equipment, or
+/// otherwise the weather outside, is taking it
+pub struct SimTemp;
+
+impl TempReader for SimTemp {
+    fn get_temp(&mut self) -> io::Result<Temperature<F>> {
+        let current = INDOOR_TEMP.load(Ordering::SeqCst);
+        let change = if COOL.load(Ordering::SeqCst) {
+            -1
+        } else if HEAT.load(Ordering::SeqCst) {
+            1
+        } else {
+            (OUTDOOR_TEMP - current).signum()
+        };
+
+        let temp = INDOOR_TEMP.fetch_add(change, Ordering::SeqCst) + change;
+        Ok(Temperature::in_f(temp as f32 / 10.0))
+    }
+}
+
+/// The weather outside the pretend house
+pub struct SimOutdoorTemp;
+
+impl TempReader for SimOutdoorTemp {
+    fn get_temp(&mut self) -> io::Result<Temperature<F>> {
+        Ok(Temperature::in_f(OUTDOOR_TEMP as f32 / 10.0))
+    }
+}
@@ -1,32 +1,71 @@
 mod temperature_humidity;
+pub mod outdoor;
+pub mod udp;
 
 use ::uom::temp::*;
 
-use std::marker::PhantomData;
+use std::io;
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+
+/// What a sensor could really read, in degrees C. Anything else, e.g. NaN from a garbled packet,
+/// is taken for an error rather than a temperature.
+pub const PLAUSIBLE_C: RangeInclusive<f32> = -60.0..=85.0;
 
 pub trait TempListener {
     fn on_temp_updated(&mut self, temp: Temperature<F>);
 }
 
-pub struct TempSensor< R> {
+pub struct TempSensor<R> {
     last_temp: Option<Temperature<F>>,
-    reader: PhantomData<R>,
+    /// Why the last reading failed, if it did
+    error: Option<String>,
+    reader: R,
 }
 
 pub trait TempReader {
-    fn get_temp() -> Temperature<F>;
+    fn get_temp(&mut self) -> io::Result<Temperature<F>>;
+
+    /// Relative humidity in percent that came with the last temperature, from sensors that
+    /// measure both at once
+    fn last_humidity(&self) -> Option<f32> { None }
+}
+
+/// Relative humidity in percent
+pub trait HumidityReader {
+    fn get_humidity(&mut self) -> io::Result<f32>;
+}
+
+impl<R: TempReader + ?Sized> TempReader for Box<R> {
+    fn get_temp(&mut self) -> io::Result<Temperature<F>> {
+        (**self).get_temp()
+    }
+
+    fn last_humidity(&self) -> Option<f32> {
+        (**self).last_humidity()
+    }
 }
 
 impl<R> TempSensor<R> where R : TempReader {
-    pub fn new() -> TempSensor<R> {
+    pub fn new(reader: R) -> TempSensor<R> {
         TempSensor {
             last_temp: None,
-            reader: PhantomData
+            error: None,
+            reader,
         }
     }
 
+    /// A reading that fails is logged and otherwise treated like one that didn't change
     pub fn get_updated_temp(&mut self) -> Option<Temperature<F>> {
-        let temp = R::get_temp();
+        let reading = self.reader.get_temp();
+        self.error = reading.as_ref().err().map(|e| e.to_string());
+        let temp = match reading {
+            Ok(temp) => temp,
+            Err(e) => {
+                warn!("Could not read the temperature: {}", e);
+                return None;
+            }
+        };
         let changed = match self.last_temp {
             Some(last_temp) => last_temp != temp,
             None => true,
@@ -36,6 +75,34 @@ impl<R> TempSensor<R> where R : TempReader {
         
         if changed { Some(temp) } else { None }
     }
+
+    /// Reads until there is a temperature, for sources that may not have one yet, e.g. remote
+    /// sensors that haven't sent anything. `wait` is called between the attempts, and stops the
+    /// waiting by returning false. None if that happens or `timeout` passes first.
+    pub fn first_reading<W>(&mut self, timeout: Duration, mut wait: W) -> Option<Temperature<F>> where W: FnMut() -> bool {
+        let give_up = Instant::now() + timeout;
+        loop {
+            if let Some(temp) = self.get_updated_temp() {
+                return Some(temp);
+            }
+            if Instant::now() >= give_up || !wait() {
+                return None;
+            }
+        }
+    }
+
+    /// The humidity that came with the last reading, if the reader has one
+    pub fn get_humidity(&self) -> Option<f32> {
+        if self.error.is_some() { None } else { self.reader.last_humidity() }
+    }
+
+    pub fn is_failing(&self) -> bool {
+        self.error.is_some()
+    }
+
+    pub fn get_error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
 }
 
 #[cfg(test)]
@@ -43,38 +110,78 @@ mod test {
     use ::uom::temp::*;
     use super::*;
 
-    static mut current_temp: f32 = 77.0;
-
-    struct Mock;
+    struct Mock(f32);
     impl TempReader for Mock {
-        fn get_temp() -> Temperature<F> {
-            unsafe { Temperature::in_f(current_temp) }
+        fn get_temp(&mut self) -> io::Result<Temperature<F>> {
+            Ok(Temperature::in_f(self.0))
+        }
+    }
+
+    struct Broken;
+    impl TempReader for Broken {
+        fn get_temp(&mut self) -> io::Result<Temperature<F>> {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "crc mismatch"))
         }
     }
 
     #[test]
     fn updates_listeners_when_the_first_reading_happens() {
-        let mut sensor = TempSensor::<Mock>::new();
+        let mut sensor = TempSensor::new(Mock(77.0));
 
         assert!(sensor.get_updated_temp() == Some(Temperature::in_f(77.0)));
     }
 
     #[test]
     fn doesnt_update_listeners_if_the_temperature_hasnt_changed() {
-        let mut sensor = TempSensor::<Mock>::new();
+        let mut sensor = TempSensor::new(Mock(77.0));
 
         assert!(sensor.get_updated_temp() == Some(Temperature::in_f(77.0)));
-        assert!(sensor.get_updated_temp() == None);
+        assert!(sensor.get_updated_temp().is_none());
+        assert!(!sensor.is_failing());
     }
 
     #[test]
     fn updates_listeners_when_the_temperature_changes() {
-        let mut sensor = TempSensor::<Mock>::new();
+        let mut sensor = TempSensor::new(Mock(77.0));
 
         assert!(sensor.get_updated_temp() == Some(Temperature::in_f(77.0)));
 
-        unsafe { current_temp = 74.9; }
+        sensor.reader.0 = 74.9;
 
         assert!(sensor.get_updated_temp() == Some(Temperature::in_f(74.9)));
     }
+
+    /// Fails until it has been asked a number of times
+    struct Starting(u32);
+    impl TempReader for Starting {
+        fn get_temp(&mut self) -> io::Result<Temperature<F>> {
+            if self.0 == 0 {
+                return Ok(Temperature::in_f(71.0));
+            }
+            self.0 -= 1;
+            Err(io::Error::new(io::ErrorKind::NotFound, "no reading yet"))
+        }
+    }
+
+    #[test]
+    fn waits_for_the_first_reading() {
+        let mut sensor = TempSensor::new(Starting(2));
+        let mut waits = 0;
+
+        assert!(sensor.first_reading(Duration::from_secs(60), || { waits += 1; true }) == Some(Temperature::in_f(71.0)));
+        assert_eq!(waits, 2);
+
+        let mut sensor = TempSensor::new(Starting(2));
+        assert!(sensor.first_reading(Duration::from_secs(60), || false).is_none());
+        let mut sensor = TempSensor::new(Broken);
+        assert!(sensor.first_reading(Duration::from_millis(0), || true).is_none());
+    }
+
+    #[test]
+    fn skips_readings_that_fail() {
+        let mut sensor = TempSensor::new(Broken);
+
+        assert!(sensor.get_updated_temp().is_none());
+        assert_eq!(sensor.get_error(), Some("crc mismatch"));
+    }
 }
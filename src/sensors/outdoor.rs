@@ -0,0 +1,171 @@
+//! Temperatures that come from somewhere else: a file another program keeps up to date, or a
+//! small HTTP endpoint (a weather service bridge, or a stub serving a fixed value).
+//!
+//! Either way the reading is a single number, in degrees C unless it ends in `F`:
+//! `21.5`, `21.5C` or `70.7F`.
+
+use ::uom::temp::*;
+use super::{TempReader, PLAUSIBLE_C};
+use chrono::Duration;
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn parse_reading(reading: &str) -> io::Result<Temperature<F>> {
+    let reading = reading.trim();
+    let parse = |degrees: &str| degrees.trim().parse::<f32>().map_err(|_| invalid(format!("invalid temperature {}", reading)));
+
+    let (fahrenheit, celsius) = match reading.strip_suffix('F') {
+        Some(degrees) => parse(degrees).map(|f| (Some(f), (f - 32.0) * 5.0 / 9.0))?,
+        None => (None, parse(reading.strip_suffix('C').unwrap_or(reading))?),
+    };
+    // checked before it becomes a Temperature, which would turn NaN into 0 and cap infinity; NaN
+    // and infinity aren't in the range either
+    if !PLAUSIBLE_C.contains(&celsius) {
+        return Err(invalid(format!("implausible temperature {}", reading)));
+    }
+    Ok(fahrenheit.map_or_else(|| Temperature::in_c(celsius).to_f(), Temperature::in_f))
+}
+
+/// A file holding the current reading. It is stale once it hasn't been written for `max_age`.
+pub struct FileTemp {
+    path: PathBuf,
+    max_age: Duration,
+}
+
+impl FileTemp {
+    pub fn new(path: &Path, max_age: Duration) -> FileTemp {
+        FileTemp { path: path.to_path_buf(), max_age }
+    }
+}
+
+impl TempReader for FileTemp {
+    fn get_temp(&mut self) -> io::Result<Temperature<F>> {
+        let modified = fs::metadata(&self.path)?.modified()?;
+        let age = SystemTime::now().duration_since(modified).unwrap_or_default();
+        if self.max_age.to_std().is_ok_and(|max_age| age > max_age) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, format!("{} is stale", self.path.display())));
+        }
+
+        let mut s = String::new();
+        File::open(&self.path)?.read_to_string(&mut s)?;
+        parse_reading(&s)
+    }
+}
+
+/// GETs the reading from a plain http:// url
+pub struct HttpTemp {
+    url: String,
+}
+
+impl HttpTemp {
+    pub fn new(url: &str) -> HttpTemp {
+        HttpTemp { url: url.to_string() }
+    }
+
+    /// Splits the url into host:port and path
+    fn split_url(&self) -> io::Result<(String, String)> {
+        let rest = self.url.strip_prefix("http://")
+            .ok_or_else(|| invalid(format!("only http:// urls are supported, not {}", self.url)))?;
+        let (host, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        let host = if host.contains(':') { host.to_string() } else { format!("{}:80", host) };
+
+        Ok((host, path.to_string()))
+    }
+}
+
+impl TempReader for HttpTemp {
+    fn get_temp(&mut self) -> io::Result<Temperature<F>> {
+        let timeout = ::std::time::Duration::from_secs(5);
+        let (host, path) = self.split_url()?;
+        let addr = host.to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("cannot resolve {}", host)))?;
+
+        let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, host)?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+
+        let (head, body) = response.split_at(response.find("\r\n\r\n").map_or(response.len(), |index| index + 4));
+        let status = head.lines().next().unwrap_or("");
+        if status.split_whitespace().nth(1) != Some("200") {
+            return Err(io::Error::other(format!("{} answered {}", self.url, status)));
+        }
+
+        parse_reading(body)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+    use tempdir::TempDir;
+
+    /// Serves a single request with the given response
+    fn serve(response: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/outdoor", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // all of the request, or closing with some of it unread resets the connection
+            let mut reader = io::BufReader::new(&stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        url
+    }
+
+    #[test]
+    fn parses_readings_in_either_unit() {
+        assert!(parse_reading("21.5\n").unwrap() == Temperature::in_c(21.5).to_f());
+        assert!(parse_reading("21.5C").unwrap() == Temperature::in_c(21.5).to_f());
+        assert!(parse_reading("70.7F").unwrap() == Temperature::in_f(70.7));
+        assert!(parse_reading("warm").is_err());
+    }
+
+    #[test]
+    fn rejects_readings_no_thermometer_gives() {
+        for reading in &["NaN", "inf", "-infF", "1e30", "1e30F", "200C", "-100"] {
+            assert!(parse_reading(reading).is_err(), "{}", reading);
+        }
+        assert!(parse_reading("-40F").unwrap() == Temperature::in_f(-40.0));
+    }
+
+    #[test]
+    fn reads_a_file() {
+        let dir = TempDir::new("outdoor").unwrap();
+        let path = dir.path().join("outdoor");
+        File::create(&path).unwrap().write_all(b"-3.5\n").unwrap();
+
+        assert!(FileTemp::new(&path, Duration::minutes(30)).get_temp().unwrap() == Temperature::in_c(-3.5).to_f());
+        assert!(FileTemp::new(&dir.path().join("missing"), Duration::minutes(30)).get_temp().is_err());
+    }
+
+    #[test]
+    fn reads_from_a_local_http_stub() {
+        let url = serve("HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\n95F");
+        assert!(HttpTemp::new(&url).get_temp().unwrap() == Temperature::in_f(95.0));
+
+        let url = serve("HTTP/1.0 404 Not Found\r\n\r\n");
+        assert!(HttpTemp::new(&url).get_temp().is_err());
+
+        assert!(HttpTemp::new("https://example.com/").get_temp().is_err());
+    }
+}
@@ -0,0 +1,268 @@
+//! Readings pushed by remote sensors (e.g. ESP8266 boards around the house) over UDP.
+//!
+//! Each datagram is one line of ASCII, fields separated by spaces:
+//!
+//! ```text
+//! <sensor id> <temperature C> <humidity %RH> <battery V> <sequence number>
+//! bedroom 21.50 43.2 3.05 1742
+//! ```
+//!
+//! The sensor id is any string without spaces. Humidity and battery can be `-` when the sensor
+//! doesn't have them. The sequence number is a u32 the sensor increments for every reading, so
+//! repeated broadcasts of the same reading are only counted once; a sensor that restarts sends 0.
+
+use ::uom::temp::*;
+use super::{TempReader, PLAUSIBLE_C};
+use chrono::*;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::ops::RangeInclusive;
+
+#[derive(Clone)]
+pub struct RemoteReading {
+    pub temp: Temperature<C>,
+    pub humidity: Option<f32>,
+    /// Battery voltage
+    pub battery: Option<f32>,
+    pub seq: u32,
+    pub received: DateTime<UTC>,
+}
+
+/// A number in `range`, which also keeps out NaN and infinity
+fn number(field: &str, what: &str, range: RangeInclusive<f32>) -> Result<f32, String> {
+    field.parse::<f32>().ok()
+        .filter(|value| range.contains(value))
+        .ok_or(format!("invalid {} {}", what, field))
+}
+
+fn optional(field: &str, what: &str, range: RangeInclusive<f32>) -> Result<Option<f32>, String> {
+    match field {
+        "-" => Ok(None),
+        value => number(value, what, range).map(Some),
+    }
+}
+
+fn parse_packet(packet: &str, received: DateTime<UTC>) -> Result<(String, RemoteReading), String> {
+    let fields: Vec<&str> = packet.split_whitespace().collect();
+    if fields.len() != 5 {
+        return Err(format!("expected 5 fields, got {}", fields.len()));
+    }
+
+    Ok((fields[0].to_string(), RemoteReading {
+        temp: Temperature::in_c(number(fields[1], "temperature", PLAUSIBLE_C)?),
+        humidity: optional(fields[2], "humidity", 0.0..=100.0)?,
+        battery: optional(fields[3], "battery voltage", 0.0..=10.0)?,
+        seq: fields[4].parse::<u32>().map_err(|_| format!("invalid sequence number {}", fields[4]))?,
+        received,
+    }))
+}
+
+/// Whether `seq` comes after `last`, allowing for the counter wrapping around
+fn is_newer(seq: u32, last: u32) -> bool {
+    seq == 0 || (seq.wrapping_sub(last) as i32) > 0
+}
+
+/// The last reading from every sensor heard from
+pub struct UdpSensors {
+    socket: UdpSocket,
+    readings: BTreeMap<String, RemoteReading>,
+}
+
+impl UdpSensors {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<UdpSensors> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpSensors { socket, readings: BTreeMap::new() })
+    }
+
+    pub fn local_port(&self) -> io::Result<u16> {
+        self.socket.local_addr().map(|addr| addr.port())
+    }
+
+    /// Takes in every packet that has arrived since the last call
+    pub fn receive(&mut self, now: DateTime<UTC>) -> io::Result<()> {
+        let mut buffer = [0; 512];
+        loop {
+            let (length, from) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            };
+
+            let packet = String::from_utf8_lossy(&buffer[..length]);
+            match parse_packet(&packet, now) {
+                Ok((id, reading)) => {
+                    let newer = self.readings.get(&id).is_none_or(|last| is_newer(reading.seq, last.seq));
+                    if newer {
+                        self.readings.insert(id, reading);
+                    }
+                },
+                Err(e) => warn!("Ignoring sensor packet from {}: {}", from, e),
+            }
+        }
+    }
+
+    pub fn get_readings(&self) -> &BTreeMap<String, RemoteReading> {
+        &self.readings
+    }
+
+    /// The sensor's last reading, unless it is older than `max_age`
+    pub fn get_fresh(&self, id: &str, now: DateTime<UTC>, max_age: Duration) -> Option<&RemoteReading> {
+        self.readings.get(id).filter(|reading| now - reading.received <= max_age)
+    }
+}
+
+/// Feeds one of the remote sensors, picked by name or id, to the thermostat
+pub struct UdpTemp {
+    sensors: UdpSensors,
+    /// Sensor id to name
+    names: BTreeMap<String, String>,
+    /// Name or id of the sensor that `get_temp` reads, otherwise whichever reported last
+    indoor: Option<String>,
+    max_age: Duration,
+}
+
+impl UdpTemp {
+    pub fn new(sensors: UdpSensors, names: BTreeMap<String, String>, indoor: Option<String>, max_age: Duration) -> UdpTemp {
+        UdpTemp { sensors, names, indoor, max_age }
+    }
+
+    fn indoor_id(&self) -> Option<String> {
+        let readings = self.sensors.get_readings();
+        match self.indoor {
+            Some(ref wanted) => readings.keys()
+                .find(|id| *id == wanted || self.names.get(*id) == Some(wanted))
+                .cloned(),
+            None => readings.iter().max_by_key(|&(_, reading)| reading.received).map(|(id, _)| id.clone()),
+        }
+    }
+}
+
+impl TempReader for UdpTemp {
+    fn get_temp(&mut self) -> io::Result<Temperature<F>> {
+        let now = UTC::now();
+        self.sensors.receive(now)?;
+
+        let id = self.indoor_id()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no reading from the remote sensor yet"))?;
+        self.sensors.get_fresh(&id, now, self.max_age)
+            .map(|reading| reading.temp.to_f())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, format!("the last reading from {} is stale", id)))
+    }
+
+    fn last_humidity(&self) -> Option<f32> {
+        self.indoor_id()
+            .and_then(|id| self.sensors.get_fresh(&id, UTC::now(), self.max_age))
+            .and_then(|reading| reading.humidity)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::sensors::TempSensor;
+    use std::thread;
+
+    fn send(sensors: &UdpSensors, packets: &[&str]) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for packet in packets {
+            socket.send_to(packet.as_bytes(), ("127.0.0.1", sensors.local_port().unwrap())).unwrap();
+        }
+        // loopback delivery is quick, but not synchronous
+        thread::sleep(::std::time::Duration::from_millis(50));
+    }
+
+    #[test]
+    fn parses_the_documented_packet() {
+        let now = UTC::now();
+        let (id, reading) = parse_packet("bedroom 21.50 43.2 3.05 1742\n", now).unwrap();
+
+        assert_eq!(id, "bedroom");
+        assert!(reading.temp == Temperature::in_c(21.5));
+        assert_eq!(reading.humidity, Some(43.2));
+        assert_eq!(reading.battery, Some(3.05));
+        assert_eq!(reading.seq, 1742);
+        assert_eq!(reading.received, now);
+        assert_eq!(parse_packet("porch 5.0 - - 1", now).unwrap().1.humidity, None);
+        assert!(parse_packet("porch 5.0 -", now).is_err());
+        assert!(parse_packet("porch warm - - 1", now).is_err());
+    }
+
+    #[test]
+    fn rejects_numbers_no_sensor_sends() {
+        let now = UTC::now();
+
+        for packet in &["porch NaN - - 1", "porch inf - - 1", "porch 1e30 - - 1", "porch -100 - - 1",
+                        "porch 5.0 NaN - 1", "porch 5.0 120 - 1", "porch 5.0 - inf 1"] {
+            assert!(parse_packet(packet, now).is_err(), "{}", packet);
+        }
+        assert!(parse_packet("porch -40 0 0 1", now).is_ok());
+    }
+
+    #[test]
+    fn keeps_the_latest_reading_per_sensor_over_loopback() {
+        let mut sensors = UdpSensors::bind("127.0.0.1:0").unwrap();
+
+        send(&sensors, &["bedroom 21.5 - - 7", "bedroom 21.5 - - 7", "kitchen 23.0 - - 1",
+                         "bedroom 22.0 - - 8", "bedroom 19.0 - - 6", "garbage"]);
+        sensors.receive(UTC::now()).unwrap();
+
+        let readings = sensors.get_readings();
+        assert_eq!(readings.len(), 2);
+        assert!(readings["bedroom"].temp == Temperature::in_c(22.0));
+        assert_eq!(readings["bedroom"].seq, 8);
+
+        send(&sensors, &["bedroom 18.0 - - 0"]);
+        sensors.receive(UTC::now()).unwrap();
+        assert!(sensors.get_readings()["bedroom"].temp == Temperature::in_c(18.0));
+    }
+
+    #[test]
+    fn stale_readings_are_not_used() {
+        let mut names = BTreeMap::new();
+        names.insert("esp-3f21".to_string(), "bedroom".to_string());
+        let sensors = UdpSensors::bind("127.0.0.1:0").unwrap();
+        send(&sensors, &["esp-3f21 21.5 - - 1"]);
+        let mut temp = UdpTemp::new(sensors, names, Some("bedroom".to_string()), Duration::minutes(10));
+
+        assert!(temp.get_temp().unwrap() == Temperature::in_c(21.5).to_f());
+
+        temp.max_age = Duration::zero();
+        thread::sleep(::std::time::Duration::from_millis(5));
+        assert!(temp.get_temp().is_err());
+    }
+
+    #[test]
+    fn waits_for_a_sensor_that_hasnt_sent_anything_yet() {
+        let sensors = UdpSensors::bind("127.0.0.1:0").unwrap();
+        let port = sensors.local_port().unwrap();
+        let mut sensor = TempSensor::new(UdpTemp::new(sensors, BTreeMap::new(), None, Duration::minutes(10)));
+
+        assert!(sensor.get_updated_temp().is_none());
+        assert!(sensor.get_humidity().is_none());
+
+        let mut waits = 0;
+        let temp = sensor.first_reading(::std::time::Duration::from_secs(10), || {
+            waits += 1;
+            if waits == 3 {
+                let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+                socket.send_to(b"hall 22.0 41.5 - 1", ("127.0.0.1", port)).unwrap();
+            }
+            thread::sleep(::std::time::Duration::from_millis(20));
+            true
+        });
+
+        assert!(temp == Some(Temperature::in_c(22.0).to_f()));
+        assert_eq!(sensor.get_humidity(), Some(41.5));
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        assert!(is_newer(8, 7));
+        assert!(!is_newer(7, 7));
+        assert!(!is_newer(6, 7));
+        assert!(is_newer(2, u32::MAX - 1));
+        assert!(is_newer(0, 1000));
+    }
+}
@@ -0,0 +1,620 @@
+use ::controller::config_file::MAX_DURATION_DAYS;
+use ::controller::outdoor::OutdoorRules;
+use ::uom::temp::*;
+use chrono::*;
+use rustc_serialize::json::Json;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+/// Settings for this particular installation (wiring, equipment timings, ...), as opposed to
+/// `Config` which holds the user's comfort settings coming from the web UI.
+#[derive(Clone, Debug, Default)]
+pub struct Settings {
+    pub fan: FanSettings,
+    pub maintenance: MaintenanceSettings,
+    pub gpio: GpioSettings,
+    pub sensors: SensorSettings,
+    pub outdoor: OutdoorSettings,
+    pub metrics: MetricsSettings,
+    pub mqtt: MqttSettings,
+}
+
+#[derive(Clone, Debug)]
+pub struct FanSettings {
+    /// How long the fan runs before the compressor starts
+    pub pre_run: Duration,
+    /// How long the fan keeps running after cooling or heating stops
+    pub overrun: Duration,
+    /// Minutes per hour to circulate air for, unless the active schedule leg says otherwise
+    pub circulate_minutes: u32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MaintenanceSettings {
+    /// Air handler runtime after which to remind about changing the filter
+    pub filter_runtime: Option<Duration>,
+}
+
+/// The pin driving each output. An output is None when it isn't wired up on this board.
+#[derive(Clone, Debug)]
+pub struct GpioSettings {
+    pub backend: GpioBackend,
+    pub cool: Option<PinSettings>,
+    pub heat: Option<PinSettings>,
+    pub fan: Option<PinSettings>,
+}
+
+/// How the pins are reached. For the kernel interfaces the pin is the line number on the chip.
+#[derive(Clone, Debug, PartialEq)]
+pub enum GpioBackend {
+    Mraa,
+    /// Root of the sysfs gpio tree
+    Sysfs(PathBuf),
+    /// Path of the gpio chip's character device
+    Cdev(PathBuf),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PinSettings {
+    pub pin: i32,
+    /// The relay driver switches on when the pin is low
+    pub active_low: bool,
+}
+
+/// Where the indoor temperature comes from
+#[derive(Clone, Debug, PartialEq)]
+pub enum SensorSource {
+    /// The Edison's MCU, over /dev/ttymcu0
+    Mcu,
+    /// DS18B20 probes under this w1 devices directory
+    W1(PathBuf),
+    /// A hwmon or IIO temperature attribute, e.g. /sys/class/hwmon/hwmon0/temp1_input
+    Sysfs(PathBuf),
+    /// Remote sensors sending their readings to this UDP port
+    Udp(u16),
+    /// A file some other program keeps the reading in
+    File(PathBuf),
+    /// An http:// url serving the reading
+    Http(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct SensorSettings {
+    pub source: SensorSource,
+    /// Name (or id) of the sensor to control on. When None, the first one found.
+    pub indoor: Option<String>,
+    /// Friendly names for sensors, keyed by their id
+    pub names: BTreeMap<String, String>,
+    /// A hwmon or IIO humidity attribute, e.g. /sys/class/hwmon/hwmon0/humidity1_input
+    pub humidity: Option<PathBuf>,
+    /// What sysfs attribute values are multiplied by to get degrees C or %RH
+    pub scale: f32,
+    /// How old a remote sensor's last reading can get before it is no longer used
+    pub stale_after: Duration,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OutdoorSettings {
+    /// None when there is no outdoor sensor. A udp source needs a different port than the indoor one.
+    pub source: Option<SensorSource>,
+    /// Name (or id) of the outdoor sensor, for sources with more than one
+    pub name: Option<String>,
+    pub rules: OutdoorRules,
+}
+
+/// Where to serve `/metrics` for Prometheus, and `/history`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MetricsSettings {
+    /// None unless a port is given. Without an address it is only reachable from the same machine.
+    pub listen: Option<SocketAddr>,
+}
+
+/// The MQTT broker to publish the status to and take commands from
+#[derive(Clone, Debug, PartialEq)]
+pub struct MqttSettings {
+    /// host:port, or None to not use MQTT
+    pub broker: Option<String>,
+    pub client_id: String,
+    /// What every topic starts with
+    pub topic: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub keep_alive: Duration,
+    /// Where Home Assistant looks for discovery payloads, usually `homeassistant`. None to not
+    /// publish them.
+    pub discovery_prefix: Option<String>,
+}
+
+impl Default for MqttSettings {
+    fn default() -> MqttSettings {
+        MqttSettings {
+            broker: None,
+            client_id: "thermostat".to_string(),
+            topic: "thermostat".to_string(),
+            username: None,
+            password: None,
+            keep_alive: Duration::seconds(60),
+            discovery_prefix: None,
+        }
+    }
+}
+
+impl Default for FanSettings {
+    fn default() -> FanSettings {
+        FanSettings {
+            pre_run: Duration::zero(),
+            overrun: Duration::zero(),
+            circulate_minutes: 0,
+        }
+    }
+}
+
+impl Default for GpioSettings {
+    /// The original Edison board, or the same pins through sysfs when built without mraa
+    fn default() -> GpioSettings {
+        GpioSettings {
+            backend: if cfg!(feature = "mraa") { GpioBackend::Mraa } else { GpioBackend::Sysfs(PathBuf::from("/sys/class/gpio")) },
+            cool: Some(PinSettings { pin: 14, active_low: false }),
+            heat: Some(PinSettings { pin: 15, active_low: false }),
+            fan: Some(PinSettings { pin: 31, active_low: false }),
+        }
+    }
+}
+
+impl Default for SensorSettings {
+    fn default() -> SensorSettings {
+        SensorSettings {
+            source: SensorSource::Mcu,
+            indoor: None,
+            names: BTreeMap::new(),
+            humidity: None,
+            // hwmon and IIO report millidegrees and milli-percent
+            scale: 0.001,
+            stale_after: Duration::minutes(10),
+        }
+    }
+}
+
+/// The durations in settings.json, in seconds per unit, by section
+const DURATIONS: [(&str, &str, i64); 5] = [
+    ("fan", "preRunSeconds", 1),
+    ("fan", "overrunSeconds", 1),
+    ("maintenance", "filterRuntimeHours", 60 * 60),
+    ("sensors", "staleAfterSeconds", 1),
+    ("mqtt", "keepAliveSeconds", 1),
+];
+
+/// `key` as so many `unit_seconds`, if it is there, when it is from 0 to `MAX_DURATION_DAYS`
+fn duration(json: &Json, key: &str, unit_seconds: i64) -> Option<Result<Duration, String>> {
+    json.find(key).map(|value| value.as_i64()
+        .filter(|amount| (0..=MAX_DURATION_DAYS * 24 * 60 * 60 / unit_seconds).contains(amount))
+        .map(|amount| Duration::seconds(amount * unit_seconds))
+        .ok_or(format!("invalid {} {}, expected 0 to {} days", key, value, MAX_DURATION_DAYS)))
+}
+
+/// A duration that falls back to `default` when it is missing or out of range
+fn seconds(json: &Json, key: &str, default: Duration) -> Duration {
+    match duration(json, key, 1) {
+        Some(Ok(duration)) => duration,
+        Some(Err(e)) => {
+            warn!("{}, using the default", e);
+            default
+        },
+        None => default,
+    }
+}
+
+impl FanSettings {
+    fn from_json(json: &Json) -> FanSettings {
+        let default = FanSettings::default();
+        FanSettings {
+            pre_run: seconds(json, "preRunSeconds", default.pre_run),
+            overrun: seconds(json, "overrunSeconds", default.overrun),
+            circulate_minutes: json.find("circulateMinutes").and_then(|m| m.as_u64())
+                .map_or(default.circulate_minutes, |m| m as u32),
+        }
+    }
+}
+
+impl MaintenanceSettings {
+    fn from_json(json: &Json) -> MaintenanceSettings {
+        MaintenanceSettings {
+            filter_runtime: duration(json, "filterRuntimeHours", 60 * 60).and_then(|hours| hours.map_err(|e| warn!("{}, without a filter reminder", e)).ok()),
+        }
+    }
+}
+
+impl PinSettings {
+    fn from_json(json: &Json) -> Option<PinSettings> {
+        json.find("pin").and_then(|p| p.as_i64()).map(|pin| PinSettings {
+            pin: pin as i32,
+            active_low: json.find("activeLow").and_then(|a| a.as_boolean()).unwrap_or(false),
+        })
+    }
+}
+
+impl GpioSettings {
+    /// Unlike the other settings, an output left out here isn't defaulted, it isn't there
+    fn from_json(json: &Json) -> GpioSettings {
+        let pin = |key| json.find(key).and_then(PinSettings::from_json);
+        let path = json.find("path").and_then(|p| p.as_string()).map(PathBuf::from);
+        let backend = match json.find("backend").and_then(|b| b.as_string()) {
+            Some("sysfs") => GpioBackend::Sysfs(path.unwrap_or_else(|| PathBuf::from("/sys/class/gpio"))),
+            Some("cdev") => GpioBackend::Cdev(path.unwrap_or_else(|| PathBuf::from("/dev/gpiochip0"))),
+            Some("mraa") => GpioBackend::Mraa,
+            _ => GpioSettings::default().backend,
+        };
+
+        GpioSettings {
+            backend,
+            cool: pin("cool"),
+            heat: pin("heat"),
+            fan: pin("fan"),
+        }
+    }
+}
+
+impl SensorSource {
+    fn from_json(json: &Json) -> Option<SensorSource> {
+        let path = json.find("path").and_then(|p| p.as_string()).map(PathBuf::from);
+        match json.find("source").and_then(|s| s.as_string()) {
+            Some("w1") => Some(SensorSource::W1(path.unwrap_or_else(|| PathBuf::from("/sys/bus/w1/devices")))),
+            Some("sysfs") => Some(SensorSource::Sysfs(path.unwrap_or_else(|| PathBuf::from("/sys/class/hwmon/hwmon0/temp1_input")))),
+            Some("udp") => port_or(json, "udp", Some(4210)).map(SensorSource::Udp),
+            Some("file") => path.map(SensorSource::File),
+            Some("http") => json.find("url").and_then(|u| u.as_string()).map(|u| SensorSource::Http(u.to_string())),
+            Some("mcu") => Some(SensorSource::Mcu),
+            _ => None,
+        }
+    }
+}
+
+impl SensorSettings {
+    fn from_json(json: &Json) -> SensorSettings {
+        let default = SensorSettings::default();
+        SensorSettings {
+            source: SensorSource::from_json(json).unwrap_or(default.source),
+            indoor: json.find("indoor").and_then(|i| i.as_string()).map(|i| i.to_string()),
+            names: json.find("names").and_then(|n| n.as_object()).map_or(default.names, |names| {
+                names.iter().filter_map(|(id, name)| name.as_string().map(|name| (id.clone(), name.to_string()))).collect()
+            }),
+            humidity: json.find("humidityPath").and_then(|p| p.as_string()).map(PathBuf::from),
+            scale: json.find("scale").and_then(|s| s.as_f64()).map_or(default.scale, |s| s as f32),
+            stale_after: seconds(json, "staleAfterSeconds", default.stale_after),
+        }
+    }
+}
+
+/// The `port`, if there is one, which has to fit in 16 bits
+fn port(json: &Json) -> Option<Result<u16, String>> {
+    json.find("port").map(|port| port.as_u64()
+        .and_then(|p| u16::try_from(p).ok())
+        .ok_or(format!("invalid port {}, expected 0 to 65535", port)))
+}
+
+/// The `port`, or `default` without one. An invalid one is logged, and gives None.
+fn port_or(json: &Json, section: &str, default: Option<u16>) -> Option<u16> {
+    match port(json) {
+        Some(Ok(port)) => Some(port),
+        Some(Err(e)) => {
+            warn!("{}: {}", section, e);
+            None
+        },
+        None => default,
+    }
+}
+
+fn degrees_f(json: &Json, key: &str) -> Option<Temperature<F>> {
+    json.find(key).and_then(|t| t.as_f64()).map(|t| Temperature::in_f(t as f32))
+}
+
+impl OutdoorSettings {
+    fn from_json(json: &Json) -> OutdoorSettings {
+        OutdoorSettings {
+            source: SensorSource::from_json(json),
+            name: json.find("name").and_then(|n| n.as_string()).map(|n| n.to_string()),
+            rules: OutdoorRules {
+                heat_pump_min: degrees_f(json, "heatPumpMinF"),
+                cool_min: degrees_f(json, "coolMinF"),
+                auto_heat_below: degrees_f(json, "autoHeatBelowF"),
+                auto_cool_above: degrees_f(json, "autoCoolAboveF"),
+                adjust_below: degrees_f(json, "adjustBelowF"),
+                adjust_above: degrees_f(json, "adjustAboveF"),
+                setpoint_adjust: degrees_f(json, "setpointAdjustF").unwrap_or(Temperature::in_f(0.0)),
+            },
+        }
+    }
+}
+
+impl MetricsSettings {
+    fn from_json(json: &Json) -> MetricsSettings {
+        let address = json.find("address").and_then(|a| a.as_string()).and_then(|a| a.parse().ok())
+            .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        MetricsSettings {
+            listen: port_or(json, "metrics", None).map(|port| SocketAddr::new(address, port)),
+        }
+    }
+}
+
+impl MqttSettings {
+    fn from_json(json: &Json) -> MqttSettings {
+        let default = MqttSettings::default();
+        let string = |key| json.find(key).and_then(|s| s.as_string()).map(|s| s.to_string());
+        let port = port_or(json, "mqtt", Some(1883));
+        MqttSettings {
+            broker: string("host").and_then(|host| port.map(|port| format!("{}:{}", host, port))),
+            client_id: string("clientId").unwrap_or(default.client_id),
+            topic: string("topic").map(|topic| topic.trim_end_matches('/').to_string()).unwrap_or(default.topic),
+            username: string("username"),
+            password: string("password"),
+            keep_alive: seconds(json, "keepAliveSeconds", default.keep_alive),
+            discovery_prefix: string("discoveryPrefix").map(|prefix| prefix.trim_end_matches('/').to_string()),
+        }
+    }
+}
+
+impl Settings {
+    /// Anything missing from the json keeps its default value
+    pub fn from_json(json: &Json) -> Settings {
+        Settings {
+            fan: json.find("fan").map_or(FanSettings::default(), FanSettings::from_json),
+            maintenance: json.find("maintenance").map_or(MaintenanceSettings::default(), MaintenanceSettings::from_json),
+            gpio: json.find("gpio").map_or(GpioSettings::default(), GpioSettings::from_json),
+            sensors: json.find("sensors").map_or(SensorSettings::default(), SensorSettings::from_json),
+            outdoor: json.find("outdoor").map_or(OutdoorSettings::default(), OutdoorSettings::from_json),
+            metrics: json.find("metrics").map_or(MetricsSettings::default(), MetricsSettings::from_json),
+            mqtt: json.find("mqtt").map_or(MqttSettings::default(), MqttSettings::from_json),
+        }
+    }
+
+    /// What `from_json` would replace with a default: unknown sections, backends and sensor
+    /// sources, and durations out of range
+    pub fn problems(json: &Json) -> Vec<String> {
+        let mut problems = vec![];
+        let object = match json.as_object() {
+            Some(object) => object,
+            None => return vec!["expected an object".to_string()],
+        };
+
+        for key in object.keys().filter(|key| !["fan", "maintenance", "gpio", "sensors", "outdoor", "metrics", "mqtt"].contains(&key.as_str())) {
+            problems.push(format!("unknown section {}", key));
+        }
+        if let Some(backend) = json.find_path(&["gpio", "backend"]) {
+            if !backend.as_string().is_some_and(|b| ["sysfs", "cdev", "mraa"].contains(&b)) {
+                problems.push(format!("gpio: unknown backend {}, expected sysfs, cdev or mraa", backend));
+            }
+        }
+        if let Some(address) = json.find_path(&["metrics", "address"]) {
+            if address.as_string().is_none_or(|a| a.parse::<IpAddr>().is_err()) {
+                problems.push(format!("metrics: invalid address {}, expected an IP address", address));
+            }
+        }
+        for &(section, key, unit_seconds) in &DURATIONS {
+            if let Some(Err(e)) = json.find(section).and_then(|json| duration(json, key, unit_seconds)) {
+                problems.push(format!("{}: {}", section, e));
+            }
+        }
+        for section in &["sensors", "outdoor", "metrics", "mqtt"] {
+            if let Some(Err(e)) = json.find(section).and_then(port) {
+                problems.push(format!("{}: {}", section, e));
+            }
+        }
+        for section in &["sensors", "outdoor"] {
+            if let Some(json) = json.find(section) {
+                let bad_port = port(json).is_some_and(|port| port.is_err());
+                if json.find("source").is_some() && SensorSource::from_json(json).is_none() && !bad_port {
+                    problems.push(format!("{}: unknown source, or its path or url is missing", section));
+                }
+            }
+        }
+        problems
+    }
+
+    pub fn load(path: &Path) -> io::Result<Settings> {
+        let mut file = File::open(path)?;
+        let mut s = String::new();
+        file.read_to_string(&mut s)?;
+
+        Json::from_str(&s)
+            .map(|json| Settings::from_json(&json))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rustc_serialize::json::Json;
+
+    #[test]
+    fn reads_fan_timing() {
+        let json = Json::from_str(r#"{ "fan": { "preRunSeconds": 15, "overrunSeconds": 90 } }"#).unwrap();
+        let settings = Settings::from_json(&json);
+
+        assert_eq!(settings.fan.pre_run, Duration::seconds(15));
+        assert_eq!(settings.fan.overrun, Duration::seconds(90));
+    }
+
+    #[test]
+    fn uses_defaults_for_missing_values() {
+        let json = Json::from_str(r#"{ "fan": { "overrunSeconds": 90 } }"#).unwrap();
+        let settings = Settings::from_json(&json);
+
+        assert_eq!(settings.fan.pre_run, Duration::zero());
+        assert_eq!(settings.fan.overrun, Duration::seconds(90));
+    }
+
+    #[test]
+    fn uses_defaults_for_durations_out_of_range() {
+        let json = Json::from_str(r#"{ "fan": { "preRunSeconds": -5, "overrunSeconds": 9223372036854775807 },
+            "sensors": { "staleAfterSeconds": 99999999999 } }"#).unwrap();
+        let settings = Settings::from_json(&json);
+
+        assert_eq!(settings.fan.pre_run, Duration::zero());
+        assert_eq!(settings.fan.overrun, Duration::zero());
+        assert_eq!(settings.sensors.stale_after, Duration::minutes(10));
+        assert_eq!(Settings::problems(&json), vec![
+            "fan: invalid preRunSeconds -5, expected 0 to 366 days".to_string(),
+            "fan: invalid overrunSeconds 9223372036854775807, expected 0 to 366 days".to_string(),
+            "sensors: invalid staleAfterSeconds 99999999999, expected 0 to 366 days".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn reads_the_filter_runtime() {
+        let json = Json::from_str(r#"{ "maintenance": { "filterRuntimeHours": 300 } }"#).unwrap();
+
+        assert_eq!(Settings::from_json(&json).maintenance.filter_runtime, Some(Duration::hours(300)));
+        assert_eq!(Settings::default().maintenance.filter_runtime, None);
+
+        let json = Json::from_str(r#"{ "maintenance": { "filterRuntimeHours": 9223372036854775807 } }"#).unwrap();
+        assert_eq!(Settings::from_json(&json).maintenance.filter_runtime, None);
+    }
+
+    #[test]
+    fn reads_the_pin_mapping() {
+        let json = Json::from_str(r#"{ "gpio": {
+            "cool": { "pin": 20, "activeLow": true },
+            "fan": { "pin": 21 }
+        } }"#).unwrap();
+        let gpio = Settings::from_json(&json).gpio;
+
+        assert_eq!(gpio.cool, Some(PinSettings { pin: 20, active_low: true }));
+        assert_eq!(gpio.heat, None);
+        assert_eq!(gpio.fan, Some(PinSettings { pin: 21, active_low: false }));
+    }
+
+    #[test]
+    fn defaults_to_the_edison_pins() {
+        let gpio = Settings::from_json(&Json::from_str("{}").unwrap()).gpio;
+
+        assert_eq!(gpio.cool, Some(PinSettings { pin: 14, active_low: false }));
+        assert_eq!(gpio.heat, Some(PinSettings { pin: 15, active_low: false }));
+        assert_eq!(gpio.fan, Some(PinSettings { pin: 31, active_low: false }));
+    }
+
+    #[test]
+    fn reads_the_gpio_backend() {
+        let backend = |json| Settings::from_json(&Json::from_str(json).unwrap()).gpio.backend;
+
+        assert_eq!(backend(r#"{ "gpio": { "backend": "sysfs" } }"#), GpioBackend::Sysfs(PathBuf::from("/sys/class/gpio")));
+        assert_eq!(backend(r#"{ "gpio": { "backend": "cdev", "path": "/dev/gpiochip2" } }"#),
+                   GpioBackend::Cdev(PathBuf::from("/dev/gpiochip2")));
+        assert_eq!(backend(r#"{ "gpio": { "backend": "mraa" } }"#), GpioBackend::Mraa);
+        assert_eq!(backend("{}"), GpioSettings::default().backend);
+    }
+
+    #[test]
+    fn reads_the_sensor_source_and_names() {
+        let json = Json::from_str(r#"{ "sensors": {
+            "source": "w1",
+            "indoor": "hallway",
+            "names": { "28-0316a2795dff": "hallway", "28-0000075a1b2c": "attic" }
+        } }"#).unwrap();
+        let sensors = Settings::from_json(&json).sensors;
+
+        assert_eq!(sensors.source, SensorSource::W1(PathBuf::from("/sys/bus/w1/devices")));
+        assert_eq!(sensors.indoor, Some("hallway".to_string()));
+        assert_eq!(sensors.names["28-0000075a1b2c"], "attic");
+        assert_eq!(Settings::default().sensors.source, SensorSource::Mcu);
+    }
+
+    #[test]
+    fn reads_sysfs_sensor_paths_and_scale() {
+        let json = Json::from_str(r#"{ "sensors": {
+            "source": "sysfs",
+            "path": "/sys/bus/iio/devices/iio:device0/in_temp_input",
+            "humidityPath": "/sys/bus/iio/devices/iio:device0/in_humidityrelative_input",
+            "scale": 0.01
+        } }"#).unwrap();
+        let sensors = Settings::from_json(&json).sensors;
+
+        assert_eq!(sensors.source, SensorSource::Sysfs(PathBuf::from("/sys/bus/iio/devices/iio:device0/in_temp_input")));
+        assert_eq!(sensors.humidity, Some(PathBuf::from("/sys/bus/iio/devices/iio:device0/in_humidityrelative_input")));
+        assert!((sensors.scale - 0.01).abs() < 1e-6);
+        assert!((Settings::default().sensors.scale - 0.001).abs() < 1e-6);
+    }
+
+    #[test]
+    fn reads_the_udp_port_and_staleness() {
+        let json = Json::from_str(r#"{ "sensors": { "source": "udp", "port": 5000, "staleAfterSeconds": 300 } }"#).unwrap();
+        let sensors = Settings::from_json(&json).sensors;
+
+        assert_eq!(sensors.source, SensorSource::Udp(5000));
+        assert_eq!(sensors.stale_after, Duration::minutes(5));
+        assert_eq!(Settings::default().sensors.stale_after, Duration::minutes(10));
+    }
+
+    #[test]
+    fn rejects_ports_that_dont_fit_in_16_bits() {
+        let json = Json::from_str(r#"{ "sensors": { "source": "udp", "port": 70000 }, "metrics": { "port": 70000 },
+            "mqtt": { "host": "broker.lan", "port": 70000 } }"#).unwrap();
+        let settings = Settings::from_json(&json);
+
+        assert_eq!(settings.sensors.source, SensorSource::Mcu);
+        assert_eq!(settings.metrics.listen, None);
+        assert_eq!(settings.mqtt.broker, None);
+        assert_eq!(Settings::problems(&json), vec![
+            "sensors: invalid port 70000, expected 0 to 65535".to_string(),
+            "metrics: invalid port 70000, expected 0 to 65535".to_string(),
+            "mqtt: invalid port 70000, expected 0 to 65535".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn reads_where_to_serve_metrics() {
+        let json = Json::from_str(r#"{ "metrics": { "port": 9105 } }"#).unwrap();
+        assert_eq!(Settings::from_json(&json).metrics.listen, Some("127.0.0.1:9105".parse().unwrap()));
+
+        let json = Json::from_str(r#"{ "metrics": { "port": 9105, "address": "::" } }"#).unwrap();
+        assert_eq!(Settings::from_json(&json).metrics.listen, Some("[::]:9105".parse().unwrap()));
+        assert_eq!(Settings::default().metrics.listen, None);
+    }
+
+    #[test]
+    fn reads_the_mqtt_broker() {
+        let json = Json::from_str(r#"{ "mqtt": { "host": "broker.lan", "topic": "house/thermostat/", "username": "t" } }"#).unwrap();
+        let mqtt = Settings::from_json(&json).mqtt;
+
+        assert_eq!(mqtt.broker, Some("broker.lan:1883".to_string()));
+        assert_eq!(mqtt.topic, "house/thermostat");
+        assert_eq!(mqtt.username, Some("t".to_string()));
+        assert_eq!(mqtt.keep_alive, Duration::minutes(1));
+        assert_eq!(mqtt.discovery_prefix, None);
+        assert_eq!(Settings::default().mqtt.broker, None);
+    }
+
+    #[test]
+    fn reads_the_outdoor_sensor_and_rules() {
+        let json = Json::from_str(r#"{ "outdoor": {
+            "source": "http",
+            "url": "http://localhost:8000/outdoor",
+            "heatPumpMinF": 25,
+            "autoCoolAboveF": 65.5
+        } }"#).unwrap();
+        let outdoor = Settings::from_json(&json).outdoor;
+
+        assert_eq!(outdoor.source, Some(SensorSource::Http("http://localhost:8000/outdoor".to_string())));
+        assert_eq!(outdoor.rules.heat_pump_min, Some(Temperature::in_f(25.0)));
+        assert_eq!(outdoor.rules.auto_cool_above, Some(Temperature::in_f(65.5)));
+        assert_eq!(outdoor.rules.cool_min, None);
+        assert_eq!(Settings::default().outdoor.source, None);
+    }
+
+    #[test]
+    fn finds_what_would_be_defaulted() {
+        let problems = |json| Settings::problems(&Json::from_str(json).unwrap());
+
+        assert_eq!(problems(r#"{ "fan": {}, "sensors": { "source": "w1" } }"#), Vec::<String>::new());
+        assert_eq!(problems(r#"{ "fans": {}, "gpio": { "backend": "spi" }, "outdoor": { "source": "file" } }"#), vec![
+            "unknown section fans".to_string(),
+            "gpio: unknown backend \"spi\", expected sysfs, cdev or mraa".to_string(),
+            "outdoor: unknown source, or its path or url is missing".to_string(),
+        ]);
+    }
+}
@@ -0,0 +1,127 @@
+//! The status in the Prometheus text format. Readings the thermostat doesn't have, e.g. the
+//! humidity without a humidity sensor, are left out rather than reported as 0.
+
+use super::StatusReport;
+use ::ac_control::compressor::CompressorMode;
+use ::controller::Status;
+use chrono::*;
+use std::fmt::Write;
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+const STATUSES: [Status; 4] = [Status::TooHot, Status::TooCold, Status::JustRight, Status::Hold];
+const COMPRESSOR_MODES: [CompressorMode; 3] = [CompressorMode::Off, CompressorMode::Cool, CompressorMode::HeatPump];
+
+struct Metrics(String);
+
+impl Metrics {
+    /// One metric with its samples, each given as its labels (e.g. `mode="Cool"`) and value
+    fn add(&mut self, name: &str, kind: &str, help: &str, samples: &[(String, f64)]) {
+        if samples.is_empty() {
+            return;
+        }
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples {
+            match labels.as_str() {
+                "" => { let _ = writeln!(self.0, "{} {}", name, value); },
+                labels => { let _ = writeln!(self.0, "{}{{{}}} {}", name, labels, value); },
+            }
+        }
+    }
+}
+
+fn value(value: f64) -> Vec<(String, f64)> {
+    vec![(String::new(), value)]
+}
+
+/// Readings to 2 decimals, rather than whatever the f32 comes out as in an f64
+fn reading(value: f32) -> f64 {
+    (value as f64 * 100.0).round() / 100.0
+}
+
+fn flag(on: bool) -> f64 {
+    if on { 1.0 } else { 0.0 }
+}
+
+pub fn render(status: &StatusReport, now: DateTime<UTC>) -> String {
+    let mut metrics = Metrics(String::new());
+
+    let temps = [("indoor", status.temp), ("outdoor", status.outdoor_temp)];
+    metrics.add("thermostat_temperature_fahrenheit", "gauge", "Measured temperature",
+                &temps.iter().filter_map(|(sensor, temp)| {
+                    temp.map(|temp| (format!("sensor=\"{}\"", sensor), reading(temp.value())))
+                }).collect::<Vec<_>>());
+    let setpoints = [("min", status.min_temp), ("max", status.max_temp)];
+    metrics.add("thermostat_target_temperature_fahrenheit", "gauge", "Setpoints in effect",
+                &setpoints.iter().filter_map(|(setpoint, temp)| {
+                    temp.map(|temp| (format!("setpoint=\"{}\"", setpoint), reading(temp.value())))
+                }).collect::<Vec<_>>());
+    metrics.add("thermostat_humidity_percent", "gauge", "Measured relative humidity",
+                &status.humidity.map(|humidity| value(reading(humidity))).unwrap_or_default());
+
+    metrics.add("thermostat_status", "gauge", "What the temperature calls for, 1 for the current status",
+                &STATUSES.iter().map(|s| (format!("status=\"{:?}\"", s), flag(status.controller_status == Some(*s)))).collect::<Vec<_>>());
+    metrics.add("thermostat_compressor_mode", "gauge", "1 for the mode the compressor is in",
+                &COMPRESSOR_MODES.iter().map(|m| (format!("mode=\"{:?}\"", m), flag(status.compressor_mode == *m))).collect::<Vec<_>>());
+    metrics.add("thermostat_fan_on", "gauge", "Whether the fan output is on", &value(flag(status.fan)));
+    metrics.add("thermostat_output_fault", "gauge", "Whether an output could not be switched", &value(flag(status.fault.is_some())));
+
+    let counters = &status.counters;
+    metrics.add("thermostat_compressor_starts_total", "counter", "Times cooling or heating started since the thermostat started",
+                &[("mode=\"Cool\"".to_string(), counters.cool_starts as f64), ("mode=\"HeatPump\"".to_string(), counters.heat_starts as f64)]);
+    let lifetime = status.runtime.lifetime;
+    metrics.add("thermostat_runtime_seconds_total", "counter", "How long each output has been on over the life of the system",
+                &[("output=\"cool\"".to_string(), lifetime.cool.num_seconds() as f64),
+                  ("output=\"heat\"".to_string(), lifetime.heat.num_seconds() as f64),
+                  ("output=\"fan\"".to_string(), lifetime.fan.num_seconds() as f64)]);
+    metrics.add("thermostat_compressor_blocked_total", "counter",
+                "Compressor requests that had to wait for the lockout or fan timers, or were not allowed",
+                &value(counters.blocked as f64));
+    metrics.add("thermostat_sensor_errors_total", "counter", "Failed sensor readings",
+                &counters.sensor_errors.iter().map(|(sensor, errors)| (format!("sensor=\"{}\"", sensor), *errors as f64)).collect::<Vec<_>>());
+
+    metrics.add("thermostat_last_reading_age_seconds", "gauge", "Time since the indoor temperature was last read",
+                &status.last_reading.map(|last| value((now - last).num_milliseconds() as f64 / 1000.0)).unwrap_or_default());
+
+    metrics.0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::events::{Event, EventKind};
+    use ::uom::temp::*;
+
+    #[test]
+    fn renders_what_it_knows() {
+        let now = UTC.ymd(2016, 7, 1).and_hms(12, 0, 0);
+        let mut status = StatusReport::new();
+        status.temp = Some(Temperature::in_f(72.6));
+        status.controller_status = Some(Status::TooHot);
+        status.compressor_mode = CompressorMode::Cool;
+        status.last_reading = Some(now - Duration::seconds(90));
+        status.counters.count(&[
+            Event::new(now, EventKind::CompressorApplied { from: CompressorMode::Off, to: CompressorMode::Cool }),
+            Event::new(now, EventKind::CompressorApplied { from: CompressorMode::Cool, to: CompressorMode::Off }),
+            Event::new(now, EventKind::CompressorBlocked { mode: CompressorMode::Cool, reason: "lockout".to_string() }),
+        ]);
+        status.counters.sensor_error("outdoor");
+
+        let text = render(&status, now);
+        let lines: Vec<&str> = text.lines().filter(|line| !line.starts_with('#')).collect();
+        for expected in &["thermostat_temperature_fahrenheit{sensor=\"indoor\"} 72.6",
+                          "thermostat_status{status=\"TooHot\"} 1",
+                          "thermostat_status{status=\"JustRight\"} 0",
+                          "thermostat_compressor_mode{mode=\"Cool\"} 1",
+                          "thermostat_compressor_starts_total{mode=\"Cool\"} 1",
+                          "thermostat_compressor_starts_total{mode=\"HeatPump\"} 0",
+                          "thermostat_compressor_blocked_total 1",
+                          "thermostat_sensor_errors_total{sensor=\"outdoor\"} 1",
+                          "thermostat_last_reading_age_seconds 90"] {
+            assert!(lines.contains(expected), "{} missing from\n{}", expected, text);
+        }
+        assert!(!text.contains("thermostat_humidity_percent"));
+        assert!(!text.contains("sensor=\"outdoor\"} 0"));
+    }
+}
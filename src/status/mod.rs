@@ -0,0 +1,127 @@
+use ::uom::temp::*;
+use ::ac_control::compressor::{Compressor, CompressorMode, Fault};
+use ::ac_control::runtime::{Runtime, Reminder};
+use ::controller::Status;
+use ::controller::config::SystemMode;
+use ::events::{Event, EventKind};
+use chrono::*;
+use rustc_serialize::json::{Json, ToJson};
+use std::collections::BTreeMap;
+
+pub mod metrics;
+
+/// Everything there is to know about what the thermostat is doing. The control loop keeps it up to
+/// date, and anything reporting on the thermostat reads it.
+#[derive(Clone)]
+pub struct StatusReport {
+    pub temp: Option<Temperature<F>>,
+    /// Relative humidity in percent, when there is a humidity sensor
+    pub humidity: Option<f32>,
+    pub outdoor_temp: Option<Temperature<F>>,
+    /// The setpoints in effect, from the schedule or a hold
+    pub min_temp: Option<Temperature<F>>,
+    pub max_temp: Option<Temperature<F>>,
+    pub mode: SystemMode,
+    /// When the hold on the setpoints ends, while there is one
+    pub hold_until: Option<DateTime<UTC>>,
+    /// What the temperature called for when the controller last looked at it
+    pub controller_status: Option<Status>,
+    pub compressor_mode: CompressorMode,
+    pub fan: bool,
+    /// When the fan timer runs out, while it is running
+    pub fan_until: Option<DateTime<UTC>>,
+    pub runtime: Runtime,
+    pub reminders: Vec<Reminder>,
+    pub fault: Option<Fault>,
+    /// When the indoor temperature was last read successfully
+    pub last_reading: Option<DateTime<UTC>>,
+    pub counters: Counters,
+}
+
+/// Counts since the thermostat started
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Counters {
+    pub cool_starts: u64,
+    pub heat_starts: u64,
+    /// Requests for the compressor that had to wait for a timer, or weren't allowed at all
+    pub blocked: u64,
+    /// Failed readings, by sensor
+    pub sensor_errors: BTreeMap<String, u64>,
+}
+
+impl Counters {
+    /// Counts the compressor starts and blocked requests among the events
+    pub fn count(&mut self, events: &[Event]) {
+        for event in events {
+            match event.kind {
+                EventKind::CompressorApplied { to: CompressorMode::Cool, .. } => self.cool_starts += 1,
+                EventKind::CompressorApplied { to: CompressorMode::HeatPump, .. } => self.heat_starts += 1,
+                EventKind::CompressorBlocked { .. } => self.blocked += 1,
+                _ => (),
+            }
+        }
+    }
+
+    pub fn sensor_error(&mut self, sensor: &str) {
+        *self.sensor_errors.entry(sensor.to_string()).or_insert(0) += 1;
+    }
+}
+
+impl Default for StatusReport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StatusReport {
+    pub fn new() -> StatusReport {
+        StatusReport {
+            temp: None,
+            humidity: None,
+            outdoor_temp: None,
+            min_temp: None,
+            max_temp: None,
+            mode: SystemMode::Auto,
+            hold_until: None,
+            controller_status: None,
+            compressor_mode: CompressorMode::Off,
+            fan: false,
+            fan_until: None,
+            runtime: Runtime::new(),
+            reminders: vec![],
+            fault: None,
+            last_reading: None,
+            counters: Counters::default(),
+        }
+    }
+
+    pub fn update_compressor(&mut self, compressor: &Compressor) {
+        self.compressor_mode = compressor.get_mode();
+        self.fan = compressor.get_fan_mode();
+        self.runtime = compressor.get_runtime().clone();
+        self.reminders = self.runtime.get_reminders();
+        self.fault = compressor.get_fault().cloned();
+    }
+}
+
+impl ToJson for StatusReport {
+    fn to_json(&self) -> Json {
+        let mut json = BTreeMap::new();
+        json.insert("currentTempF".to_string(), self.temp.map_or(Json::Null, |t| t.value().to_json()));
+        json.insert("humidity".to_string(), self.humidity.map_or(Json::Null, |h| h.to_json()));
+        json.insert("outdoorTempF".to_string(), self.outdoor_temp.map_or(Json::Null, |t| t.value().to_json()));
+        json.insert("minTempF".to_string(), self.min_temp.map_or(Json::Null, |t| t.value().to_json()));
+        json.insert("maxTempF".to_string(), self.max_temp.map_or(Json::Null, |t| t.value().to_json()));
+        json.insert("mode".to_string(), self.mode.to_string().to_json());
+        json.insert("holdUntil".to_string(), self.hold_until.map_or(Json::Null, |until| until.to_rfc3339().to_json()));
+        json.insert("controllerStatus".to_string(), self.controller_status.map_or(Json::Null, |s| format!("{:?}", s).to_json()));
+        json.insert("compressorMode".to_string(), format!("{:?}", self.compressor_mode).to_json());
+        json.insert("fanOn".to_string(), self.fan.to_json());
+        json.insert("fanUntil".to_string(), self.fan_until.map_or(Json::Null, |until| until.to_rfc3339().to_json()));
+        json.insert("runtime".to_string(), self.runtime.to_json());
+        json.insert("reminders".to_string(),
+                    self.reminders.iter().map(|r| r.to_string()).collect::<Vec<_>>().to_json());
+        json.insert("fault".to_string(), self.fault.as_ref().map_or(Json::Null, |f| f.to_string().to_json()));
+        Json::Object(json)
+    }
+}
@@ -39,6 +39,18 @@ pub mod temp {
         }
     }
 
+    impl fmt::Debug for Temperature<C> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            fmt::Display::fmt(self, f)
+        }
+    }
+
+    impl fmt::Debug for Temperature<F> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            fmt::Display::fmt(self, f)
+        }
+    }
+
     impl<Unit> Sub for Temperature<Unit> {
         type Output = Self;
         fn sub(self, _rhs: Self) -> Self {
@@ -56,7 +68,7 @@ pub mod temp {
     impl<Unit> Copy for Temperature<Unit> {}
     impl<Unit> Clone for Temperature<Unit> {
         fn clone(&self) -> Self {
-            Temperature(self.0, PhantomData)
+            *self
         }
     }
 
@@ -66,11 +78,11 @@ pub mod temp {
         }
 
         pub fn to_f(&self) -> Temperature<F> {
-            Temperature::in_f(((self.0 as f32)/10.0 * (9.0/5.0) + 32.0))
+            Temperature::in_f((self.0 as f32)/10.0 * (9.0/5.0) + 32.0)
         }
 
         pub fn value(&self) -> f32 {
-            return self.0 as f32 / 10.0;
+            self.0 as f32 / 10.0
         }
     }
 
@@ -84,7 +96,7 @@ pub mod temp {
         }
 
         pub fn value(&self) -> f32 {
-            return self.0 as f32 / 10.0;
+            self.0 as f32 / 10.0
         }
     }
 }
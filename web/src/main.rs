@@ -0,0 +1,165 @@
+extern crate thermostat;
+extern crate thermostat_server;
+extern crate env_logger;
+extern crate chrono;
+#[macro_use]
+extern crate log;
+
+use std::cmp;
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::sync::{RwLock, Arc};
+
+use chrono::{DateTime, UTC};
+use thermostat::ac_control::compressor::CompressorMode;
+use thermostat::controller::config::SystemMode;
+use thermostat::controller::config_file::{ConfigFile, ScheduleEntry};
+use thermostat::daemon::{self, Command};
+use thermostat::status::StatusReport;
+use thermostat_server::server::Status as StatusDto;
+use thermostat_server::server::Config as ConfigDto;
+use thermostat_server::server::Schedule as ScheduleDto;
+
+/// How long to wait before the first restart of the web server
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// A server that ran at least this long before stopping gets restarted quickly again
+const HEALTHY_RUN: Duration = Duration::from_secs(10 * 60);
+
+fn main() {
+    // initialize logging framework
+    env_logger::init().unwrap();
+
+    let (paths, options) = daemon::parse_args();
+
+    // before the web server starts a thread that SIGTERM could end up in
+    let (tx, commands) = channel();
+    daemon::handle_signals(tx.clone());
+
+    let config_file = daemon::startup_config(&paths, &options);
+    let report_lock = Arc::new(RwLock::new(StatusReport::new()));
+
+    daemon::listen(&paths, tx.clone(), report_lock.clone());
+    daemon::connect_mqtt(&paths, tx.clone(), report_lock.clone());
+    let config_dto = to_dto(&config_file);
+    let (config_path, report) = (paths.config(), report_lock.clone());
+    thread::spawn(move || supervise(config_path, config_dto, tx, report));
+
+    daemon::run(config_file, &options, &paths, commands, report_lock);
+}
+
+fn start_server(config: &ConfigDto, status_lock: Arc<RwLock<StatusDto>>) -> (JoinHandle<()>, Receiver<ConfigDto>) {
+    let (tx, rx) = channel();
+    let config_for_server = config.clone();
+
+    let server = thread::spawn(move || { thermostat_server::server::start(config_for_server, tx, status_lock); });
+    println!("started!");
+
+    (server, rx)
+}
+
+/// Keeps the web server running, restarting it whenever its thread ends. Temperature control
+/// doesn't depend on it, so while it's down the thermostat carries on with the last config it had.
+fn supervise(config_path: PathBuf, mut config: ConfigDto, tx: Sender<Command>, report_lock: Arc<RwLock<StatusReport>>) {
+    let status = StatusDto { currentTempF: 0.0, compressorOn: false, fanOn: false };
+    let status_lock = Arc::new(RwLock::new(status));
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        let started = Instant::now();
+        let (server, rx) = start_server(&config, status_lock.clone());
+        bridge(&server, &rx, &tx, &config_path, &status_lock, &report_lock);
+
+        match server.join() {
+            Ok(()) => error!("Web server stopped"),
+            Err(_) => error!("Web server panicked"),
+        }
+        if started.elapsed() >= HEALTHY_RUN {
+            backoff = MIN_BACKOFF;
+        }
+        warn!("Restarting the web server in {}s", backoff.as_secs());
+        thread::sleep(backoff);
+        backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+
+        // pick up whatever changed through the socket or config.json while it was down
+        match ConfigFile::load(&config_path) {
+            Ok(config_file) => config = to_dto(&config_file),
+            Err(e) => warn!("Could not read {}, the web UI keeps its old config: {}", config_path.display(), e),
+        }
+    }
+}
+
+/// Passes config changes from the web UI to the control loop, and the control loop's status back,
+/// until the server goes away
+fn bridge(server: &JoinHandle<()>, rx: &Receiver<ConfigDto>, tx: &Sender<Command>, config_path: &Path,
+          status_lock: &RwLock<StatusDto>, report_lock: &RwLock<StatusReport>) {
+    while !server.is_finished() {
+        match rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(config_dto) => {
+                // the web UI doesn't know about the mode or holds, so they stay whatever they are
+                let (mode, hold_until) = {
+                    let report = report_lock.read().unwrap();
+                    (report.mode, report.hold_until)
+                };
+                // nor about circulation or the held setpoints, which are kept from config.json
+                let current = ConfigFile::load(config_path).unwrap_or_default();
+                tx.send(Command::UpdateConfig(from_dto(&config_dto, mode, hold_until, &current))).unwrap()
+            },
+            Err(RecvTimeoutError::Disconnected) => return,
+            Err(RecvTimeoutError::Timeout) => (),
+        }
+
+        let report = report_lock.read().unwrap();
+        let mut status = status_lock.write().unwrap();
+        if let Some(temp) = report.temp {
+            status.currentTempF = temp.value() as _;
+        }
+        status.compressorOn = report.compressor_mode != CompressorMode::Off;
+        status.fanOn = report.fan;
+    }
+}
+
+/// `current` is the config so far. Its hold keeps its setpoints, and its schedule entries their
+/// circulate minutes if the web UI still has them with the same times and days.
+fn from_dto(config: &ConfigDto, mode: SystemMode, hold_until: Option<DateTime<UTC>>, current: &ConfigFile) -> ConfigFile {
+    ConfigFile {
+        max_temp_f: config.maxTempF as _,
+        min_temp_f: config.minTempF as _,
+        fan_duration_hours: config.fanDurationHours as _,
+        schedule: config.schedule.iter().map(|s| {
+            let days: Vec<i8> = s.days.iter().map(|d| *d as _).collect();
+            ScheduleEntry {
+                min_temp_f: s.minTempF as _,
+                max_temp_f: s.maxTempF as _,
+                circulate_minutes: current.schedule.iter()
+                    .find(|entry| entry.start == s.start && entry.end == s.end && entry.days == days)
+                    .and_then(|entry| entry.circulate_minutes),
+                start: s.start.clone(),
+                end: s.end.clone(),
+                days,
+            }
+        }).collect(),
+        mode,
+        hold_until,
+        hold_max_temp_f: current.hold_max_temp_f.filter(|_| hold_until.is_some()),
+        hold_min_temp_f: current.hold_min_temp_f.filter(|_| hold_until.is_some()),
+    }
+}
+
+fn to_dto(config: &ConfigFile) -> ConfigDto {
+    ConfigDto {
+        maxTempF: config.max_temp_f as _,
+        minTempF: config.min_temp_f as _,
+        fanDurationHours: config.fan_duration_hours as _,
+        schedule: config.schedule.iter().map(|s| ScheduleDto {
+            minTempF: s.min_temp_f as _,
+            maxTempF: s.max_temp_f as _,
+            start: s.start.clone(),
+            end: s.end.clone(),
+            days: s.days.iter().map(|d| *d as _).collect(),
+        }).collect(),
+    }
+}